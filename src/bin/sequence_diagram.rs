@@ -0,0 +1,227 @@
+//! Turns the traces recorded by the test instances into mermaid sequence diagrams, one per run.
+//!
+//! Usage: `sequence-diagram <path>...`, where each path is a trace file or a directory containing
+//! them, such as the outputs of a run collected by Testground. The diagrams are written to the
+//! standard output.
+
+#[path = "../trace/entry.rs"]
+mod entry;
+
+use crate::entry::{TraceEntry, TraceKind};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::ffi::OsStr;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const TRACE_FILE: &str = "trace.jsonl";
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let paths = std::env::args()
+        .skip(1)
+        .map(PathBuf::from)
+        .collect::<Vec<_>>();
+    if paths.is_empty() {
+        return Err("Usage: sequence-diagram <path>...".into());
+    }
+
+    let mut files = vec![];
+    for path in paths {
+        find_trace_files(&path, &mut files)?;
+    }
+
+    // Group the entries by run.
+    let mut runs: BTreeMap<String, Vec<TraceEntry>> = BTreeMap::new();
+    for file in files {
+        for line in fs::read_to_string(&file)?.lines() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let entry: TraceEntry = serde_json::from_str(line)
+                .map_err(|e| format!("Failed to parse {}: {e}", file.display()))?;
+            runs.entry(entry.run.clone()).or_default().push(entry);
+        }
+    }
+
+    for (run, entries) in runs {
+        println!("{}", render(&run, entries));
+    }
+    Ok(())
+}
+
+fn find_trace_files(path: &Path, files: &mut Vec<PathBuf>) -> std::io::Result<()> {
+    if path.is_dir() {
+        let mut children = fs::read_dir(path)?
+            .map(|entry| entry.map(|e| e.path()))
+            .collect::<Result<Vec<_>, _>>()?;
+        children.sort();
+        for child in children {
+            if child.is_dir() || child.file_name() == Some(OsStr::new(TRACE_FILE)) {
+                find_trace_files(&child, files)?;
+            }
+        }
+    } else {
+        files.push(path.to_path_buf());
+    }
+    Ok(())
+}
+
+/// The participants of a run, named after the instances.
+struct Participants {
+    /// The participant names and labels, in order of declaration.
+    declared: Vec<(String, String)>,
+    /// An instance restarted with a new key has several node ids, which all resolve to it.
+    by_node_id: HashMap<String, String>,
+    by_socket: HashMap<String, String>,
+}
+
+impl Participants {
+    fn new(entries: &[TraceEntry]) -> Self {
+        let mut participants = Participants {
+            declared: vec![],
+            by_node_id: HashMap::new(),
+            by_socket: HashMap::new(),
+        };
+
+        let mut instances = entries
+            .iter()
+            .map(|e| (e.seq, e.role.clone()))
+            .collect::<Vec<_>>();
+        instances.sort();
+        instances.dedup();
+        for (seq, role) in instances {
+            let name = format!("Node{seq}");
+            participants
+                .declared
+                .push((name.clone(), format!("{name} ({role})")));
+        }
+        for entry in entries {
+            participants
+                .by_node_id
+                .insert(entry.node_id.clone(), format!("Node{}", entry.seq));
+        }
+        for entry in entries {
+            if let TraceKind::Started {
+                socket: Some(socket),
+            } = &entry.kind
+            {
+                participants.by_socket.insert(
+                    socket.clone(),
+                    participants.by_node_id[&entry.node_id].clone(),
+                );
+            }
+        }
+
+        participants
+    }
+
+    /// Resolves the peer of a packet, declaring a participant for a node outside the run.
+    fn resolve(&mut self, node_id: Option<&String>, socket: &str) -> String {
+        if let Some(name) = node_id.and_then(|id| self.by_node_id.get(id)) {
+            return name.clone();
+        }
+        if let Some(name) = self.by_socket.get(socket) {
+            return name.clone();
+        }
+
+        let name = format!("Unknown{}", self.declared.len() + 1);
+        let label = match node_id {
+            Some(node_id) => format!("{name} ({}..)", &node_id[..8.min(node_id.len())]),
+            None => format!("{name} ({socket})"),
+        };
+        self.declared.push((name.clone(), label));
+        if let Some(node_id) = node_id {
+            self.by_node_id.insert(node_id.clone(), name.clone());
+        }
+        self.by_socket.insert(socket.to_string(), name.clone());
+        name
+    }
+}
+
+fn render(run: &str, mut entries: Vec<TraceEntry>) -> String {
+    // The sort is stable, so the entries of an instance recorded at the same time keep the order.
+    entries.sort_by_key(|e| e.at_ms);
+    let mut participants = Participants::new(&entries);
+
+    // A packet between two instances which both trace packets is drawn once, from the sender.
+    let tracing_packets = entries
+        .iter()
+        .filter(|e| matches!(e.kind, TraceKind::Sent { .. } | TraceKind::Received { .. }))
+        .map(|e| participants.by_node_id[&e.node_id].clone())
+        .collect::<HashSet<_>>();
+
+    let mut lines = vec![];
+    let mut node_ids: HashMap<String, String> = HashMap::new();
+    for entry in entries.iter() {
+        let local = participants.by_node_id[&entry.node_id].clone();
+        // Annotate the restart of an instance with a new key.
+        if let Some(previous) = node_ids.insert(local.clone(), entry.node_id.clone()) {
+            if previous != entry.node_id {
+                lines.push(format!(
+                    "    Note over {local}: Node id changed to {}..",
+                    &entry.node_id[..8.min(entry.node_id.len())]
+                ));
+            }
+        }
+        match &entry.kind {
+            TraceKind::Started { .. } => {}
+            TraceKind::Sent {
+                peer_node_id,
+                peer_socket,
+                summary,
+            } => {
+                let peer = participants.resolve(Some(peer_node_id), peer_socket);
+                lines.push(format!("    {local} ->> {peer}: {}", escape(summary)));
+            }
+            TraceKind::Received {
+                peer_node_id,
+                peer_socket,
+                summary,
+            } => {
+                let peer = participants.resolve(peer_node_id.as_ref(), peer_socket);
+                if !tracing_packets.contains(&peer) {
+                    lines.push(format!("    {peer} ->> {local}: {}", escape(summary)));
+                }
+            }
+            TraceKind::Event { name, peer_node_id } => {
+                let note = match peer_node_id {
+                    Some(node_id) => {
+                        let peer = participants
+                            .by_node_id
+                            .get(node_id)
+                            .cloned()
+                            .unwrap_or_else(|| format!("{}..", &node_id[..8.min(node_id.len())]));
+                        format!("{name} ({peer})")
+                    }
+                    None => name.clone(),
+                };
+                lines.push(format!("    Note over {local}: {}", escape(&note)));
+            }
+        }
+    }
+
+    let mut diagram = vec![
+        "```mermaid".to_string(),
+        "sequenceDiagram".to_string(),
+        format!("    %% run: {run}"),
+    ];
+    for (name, label) in participants.declared.iter() {
+        diagram.push(format!("    participant {name} as {label}"));
+    }
+    diagram.push(String::new());
+    diagram.extend(lines);
+    diagram.push("```".to_string());
+    diagram.join("\n")
+}
+
+/// Escapes the characters which have a meaning in mermaid messages.
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '#' => escaped.push_str("#35;"),
+            ';' => escaped.push_str("#59;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
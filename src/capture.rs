@@ -0,0 +1,327 @@
+//! An optional capture of the packets each instance sends and receives, written as JSON lines
+//! into `capture.jsonl` in the outputs of the instance. It is enabled with the `capture` test
+//! parameter.
+//!
+//! The mock records its packets itself, with the messages decoded since it knows the session keys.
+//! The packets of the real discv5 instances are sniffed on the data network with a packet socket,
+//! and only their headers can be decoded, with the node ids of the participants the test cases
+//! register. A UDP proxy in front of discv5 can't be used instead,
+//! since it would change the source addresses that discv5 observes, which many of the test cases
+//! depend on.
+
+use crate::protocol_id::AltProtocolId;
+use discv5::enr::NodeId;
+use discv5::packet::{Packet, PacketHeader, PacketKind};
+use discv5::rpc::Message;
+use discv5::{DefaultProtocolId, Enr};
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{LineWriter, Write};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::Mutex;
+use testground::client::Client;
+use tracing::{info, warn};
+
+const CAPTURE_FILE: &str = "capture.jsonl";
+
+static CAPTURE: Mutex<Option<Capture>> = Mutex::new(None);
+
+struct Capture {
+    writer: LineWriter<File>,
+    /// The node ids of the participants, which the packet headers sent to them are masked with.
+    node_ids: HashMap<SocketAddr, NodeId>,
+    /// The sockets of the mocks in this instance, whose packets are recorded by the mocks.
+    mock_sockets: HashSet<SocketAddr>,
+    /// The current IP address of this instance on the data network, which changes with
+    /// `network::assign_ip`.
+    local_ip: Option<Ipv4Addr>,
+}
+
+#[derive(Clone, Copy, Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Direction {
+    Inbound,
+    Outbound,
+}
+
+#[derive(Serialize)]
+struct CaptureRecord {
+    /// Milliseconds since the UNIX epoch.
+    at_ms: u64,
+    direction: Direction,
+    local: SocketAddr,
+    peer: SocketAddr,
+    peer_node_id: Option<String>,
+    /// `None` if the packet couldn't be decoded.
+    kind: Option<&'static str>,
+    nonce: Option<String>,
+    /// `None` unless the session keys are known.
+    message: Option<String>,
+    size: usize,
+}
+
+/// Starts capturing if the `capture` test parameter is enabled.
+pub(crate) fn start(client: &Client) -> Result<(), Box<dyn std::error::Error>> {
+    let run_parameters = client.run_parameters();
+    let enabled = run_parameters
+        .test_instance_params
+        .get("capture")
+        .map(String::as_str);
+    if enabled != Some("true") {
+        return Ok(());
+    }
+
+    let local_ip = match run_parameters.data_network_ip()? {
+        Some(IpAddr::V4(ip)) => Some(ip),
+        ip => {
+            warn!("Packets are not sniffed on the data network. ip:{ip:?}");
+            None
+        }
+    };
+    let path = std::path::Path::new(&run_parameters.test_outputs_path).join(CAPTURE_FILE);
+    *lock() = Some(Capture {
+        writer: LineWriter::new(File::create(path)?),
+        node_ids: HashMap::new(),
+        mock_sockets: HashSet::new(),
+        local_ip,
+    });
+
+    if local_ip.is_some() {
+        sniffer::spawn();
+    }
+    info!("Capturing packets into {CAPTURE_FILE}.");
+    Ok(())
+}
+
+/// Learns the node ids of the participants, so that the headers of the packets sent to them can
+/// be decoded.
+pub(crate) fn register_enrs<'a>(enrs: impl IntoIterator<Item = &'a Enr>) {
+    if let Some(capture) = lock().as_mut() {
+        for enr in enrs {
+            if let Some(socket) = enr.udp4_socket() {
+                capture.node_ids.insert(socket.into(), enr.node_id());
+            }
+        }
+    }
+}
+
+/// Lets the sniffer follow the IP address of this instance on the data network when it changes.
+pub(crate) fn set_local_ip(ip: IpAddr) {
+    if let (Some(capture), IpAddr::V4(ip)) = (lock().as_mut(), ip) {
+        capture.local_ip = Some(ip);
+    }
+}
+
+/// Lets the sniffer leave the packets of a mock to the mock.
+pub(crate) fn register_mock(socket: SocketAddr) {
+    if let Some(capture) = lock().as_mut() {
+        capture.mock_sockets.insert(socket);
+    }
+}
+
+pub(crate) fn is_enabled() -> bool {
+    lock().is_some()
+}
+
+/// Records a packet the mock has sent or received.
+pub(crate) fn record_mock_packet(
+    direction: Direction,
+    local: SocketAddr,
+    peer: SocketAddr,
+    peer_node_id: Option<NodeId>,
+    header: &PacketHeader,
+    message: Option<&Message>,
+    size: usize,
+) {
+    write(CaptureRecord {
+        at_ms: crate::trace::now_ms(),
+        direction,
+        local,
+        peer,
+        peer_node_id: peer_node_id.as_ref().map(crate::trace::node_id_hex),
+        kind: Some(kind(&header.kind)),
+        nonce: Some(hex(&header.message_nonce)),
+        message: message.map(|m| format!("{m:?}")),
+        size,
+    });
+}
+
+/// Records a packet the mock has received but failed to decode.
+pub(crate) fn record_undecodable(local: SocketAddr, peer: SocketAddr, size: usize) {
+    write(CaptureRecord {
+        at_ms: crate::trace::now_ms(),
+        direction: Direction::Inbound,
+        local,
+        peer,
+        peer_node_id: None,
+        kind: None,
+        nonce: None,
+        message: None,
+        size,
+    });
+}
+
+fn write(record: CaptureRecord) {
+    if let Some(capture) = lock().as_mut() {
+        let result = serde_json::to_writer(&mut capture.writer, &record)
+            .map_err(std::io::Error::from)
+            .and_then(|_| capture.writer.write_all(b"\n"));
+        if let Err(e) = result {
+            warn!("Failed to write a capture record: {e}");
+        }
+    }
+}
+
+fn lock() -> std::sync::MutexGuard<'static, Option<Capture>> {
+    CAPTURE.lock().expect("Lock the capture")
+}
+
+fn kind(kind: &PacketKind) -> &'static str {
+    match kind {
+        PacketKind::Message { .. } => "message",
+        PacketKind::WhoAreYou { .. } => "whoareyou",
+        PacketKind::Handshake { .. } => "handshake",
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+/// Records a datagram sniffed on the data network if it has been sent or received on the current
+/// IP address of this instance, decoding its header with the node id of the receiver if it is
+/// known. Both of the protocol ids the test cases run with are tried.
+fn record_sniffed(src: SocketAddr, dst: SocketAddr, payload: &[u8]) {
+    let (direction, local, peer, header) = {
+        let guard = lock();
+        let capture = match guard.as_ref() {
+            Some(capture) => capture,
+            None => return,
+        };
+        let local_ip = match capture.local_ip {
+            Some(local_ip) => IpAddr::V4(local_ip),
+            None => return,
+        };
+        let (direction, local, peer) = if src.ip() == local_ip {
+            (Direction::Outbound, src, dst)
+        } else if dst.ip() == local_ip {
+            (Direction::Inbound, dst, src)
+        } else {
+            return;
+        };
+        if capture.mock_sockets.contains(&local) {
+            return;
+        }
+        let header = capture
+            .node_ids
+            .get(&dst)
+            .and_then(|dst_id| {
+                Packet::decode::<DefaultProtocolId>(dst_id, payload)
+                    .or_else(|_| Packet::decode::<AltProtocolId>(dst_id, payload))
+                    .ok()
+            })
+            .map(|(packet, _)| (packet.header, capture.node_ids.get(&peer).copied()));
+        (direction, local, peer, header)
+    };
+
+    let (kind, nonce, peer_node_id) = match header {
+        Some((header, known_peer_id)) => {
+            let peer_node_id = match (direction, &header.kind) {
+                (Direction::Inbound, PacketKind::Message { src_id })
+                | (Direction::Inbound, PacketKind::Handshake { src_id, .. }) => Some(*src_id),
+                _ => known_peer_id,
+            };
+            (
+                Some(self::kind(&header.kind)),
+                Some(hex(&header.message_nonce)),
+                peer_node_id,
+            )
+        }
+        None => (None, None, None),
+    };
+
+    write(CaptureRecord {
+        at_ms: crate::trace::now_ms(),
+        direction,
+        local,
+        peer,
+        peer_node_id: peer_node_id.as_ref().map(crate::trace::node_id_hex),
+        kind,
+        nonce,
+        message: None,
+        size: payload.len(),
+    });
+}
+
+mod sniffer {
+    use std::net::{Ipv4Addr, SocketAddrV4};
+    use tracing::warn;
+
+    const IPPROTO_UDP: u8 = 17;
+
+    /// Sniffs the UDP datagrams on a thread, since the packet socket is blocking. The ones on the
+    /// local IP address are picked by `record_sniffed`.
+    pub(super) fn spawn() {
+        std::thread::spawn(move || {
+            if let Err(e) = sniff() {
+                warn!("Failed to sniff packets: {e}");
+            }
+        });
+    }
+
+    fn sniff() -> std::io::Result<()> {
+        // A packet socket receives the packets from the IP header on, both inbound and outbound.
+        // This requires CAP_NET_RAW, which Docker grants by default.
+        let protocol = (libc::ETH_P_IP as u16).to_be() as libc::c_int;
+        let fd = unsafe { libc::socket(libc::AF_PACKET, libc::SOCK_DGRAM, protocol) };
+        if fd < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+
+        let mut buffer = [0u8; 65536];
+        loop {
+            let length = unsafe {
+                libc::recv(
+                    fd,
+                    buffer.as_mut_ptr() as *mut libc::c_void,
+                    buffer.len(),
+                    0,
+                )
+            };
+            if length < 0 {
+                let e = std::io::Error::last_os_error();
+                unsafe { libc::close(fd) };
+                return Err(e);
+            }
+
+            if let Some((src, dst, payload)) = parse_udp(&buffer[..length as usize]) {
+                super::record_sniffed(src.into(), dst.into(), payload);
+            }
+        }
+    }
+
+    /// Parses an IPv4 packet carrying a UDP datagram.
+    fn parse_udp(packet: &[u8]) -> Option<(SocketAddrV4, SocketAddrV4, &[u8])> {
+        if packet.len() < 20 || packet[0] >> 4 != 4 || packet[9] != IPPROTO_UDP {
+            return None;
+        }
+        let header_length = ((packet[0] & 0x0f) as usize) * 4;
+        let total_length = u16::from_be_bytes([packet[2], packet[3]]) as usize;
+        let src_ip = Ipv4Addr::new(packet[12], packet[13], packet[14], packet[15]);
+        let dst_ip = Ipv4Addr::new(packet[16], packet[17], packet[18], packet[19]);
+
+        let udp = packet.get(header_length..total_length.min(packet.len()))?;
+        if udp.len() < 8 {
+            return None;
+        }
+        let src_port = u16::from_be_bytes([udp[0], udp[1]]);
+        let dst_port = u16::from_be_bytes([udp[2], udp[3]]);
+
+        Some((
+            SocketAddrV4::new(src_ip, src_port),
+            SocketAddrV4::new(dst_ip, dst_port),
+            &udp[8..],
+        ))
+    }
+}
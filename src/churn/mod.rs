@@ -0,0 +1,322 @@
+mod params;
+mod schedule;
+
+use crate::capture;
+use crate::churn::params::Params;
+use crate::churn::schedule::Schedule;
+use crate::utils::{publish_and_collect, publish_and_collect_on, run_seed, start_discv5};
+use chrono::Local;
+use discv5::enr::{CombinedKey, NodeId};
+use discv5::{ConnectionState, Discv5, Enr, ListenConfig};
+use rand::seq::SliceRandom;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::Ipv4Addr;
+use std::time::{Duration, Instant};
+use testground::client::Client;
+use testground::WriteQuery;
+use tracing::{info, warn};
+
+const STATE_DONE: &str = "state_done";
+
+// The interval at which the routing table is sampled within a tick.
+const SAMPLING_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct InstanceInfo {
+    // The sequence number of this test instance within the test.
+    seq: u64,
+    enr: Enr,
+}
+
+/// What an instance has measured over the run.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+struct ChurnResult {
+    // Lookups for live nodes.
+    lookups: u64,
+    lookup_failures: u64,
+    // The time it took to evict each departed peer, i.e. to mark it as disconnected.
+    eviction_delays_ms: Vec<u64>,
+    // The departed peers which were still connected when the instance stopped watching them.
+    not_evicted: u64,
+    // The sums over all the samples of the routing table.
+    stale_entries: u64,
+    table_entries: u64,
+}
+
+pub(crate) async fn run(client: Client) -> Result<(), Box<dyn std::error::Error>> {
+    let run_parameters = client.run_parameters();
+    let ip = run_parameters
+        .data_network_ip()?
+        .expect("IP address for the data network");
+    let params = Params::new(&run_parameters.test_instance_params)?;
+    if params.tick == 0 || params.ticks() < 2 {
+        return Err("`duration` must be at least twice as long as `tick`.".into());
+    }
+
+    // ////////////////////////
+    // Construct local Enr
+    // ////////////////////////
+    let enr_key = CombinedKey::generate_secp256k1();
+    let enr = Enr::builder()
+        .ip(ip)
+        .udp4(9000)
+        .build(&enr_key)
+        .expect("Construct an Enr");
+
+    // ////////////////////////
+    // Discv5 config
+    // ////////////////////////
+    // The lookup in each tick has to finish within the tick.
+    let tick_duration = Duration::from_secs(params.tick);
+    let listen_config = ListenConfig::Ipv4 {
+        ip: Ipv4Addr::UNSPECIFIED,
+        port: 9000,
+    };
+    let config = discv5::ConfigBuilder::new(listen_config)
+        .ping_interval(Duration::from_secs(params.ping_interval))
+        .query_timeout((tick_duration / 2).max(Duration::from_secs(1)))
+        .build();
+    let mut discv5: Discv5 = Discv5::new(enr.clone(), enr_key, config)?;
+
+    // //////////////////////////////////////////////////////////////
+    // Collect information of all participants in the test case
+    // //////////////////////////////////////////////////////////////
+    let instance_info = InstanceInfo {
+        seq: client.global_seq(),
+        enr,
+    };
+    client.record_message(format!(
+        "seq: {}, node_id: {}, ip: {}",
+        instance_info.seq,
+        instance_info.enr.node_id(),
+        ip
+    ));
+    let participants = publish_and_collect(&client, instance_info.clone()).await?;
+    capture::register_enrs(participants.iter().map(|p| &p.enr));
+    let seqs = participants
+        .iter()
+        .map(|p| (p.enr.node_id(), p.seq))
+        .collect::<HashMap<_, _>>();
+    let bootstrap = participants
+        .iter()
+        .find(|p| p.seq == 1)
+        .expect("Bootstrap node")
+        .clone();
+
+    let schedule = Schedule::new(
+        run_parameters.test_instance_count,
+        &params,
+        run_seed(&run_parameters.test_run),
+    );
+
+    // //////////////////////////////////////////////////////////////
+    // Run the schedule
+    // //////////////////////////////////////////////////////////////
+    let seq = instance_info.seq;
+    let mut result = ChurnResult::default();
+    let mut has_joined = false;
+    // The departed peers which were connected when they left, with the time they left at.
+    let mut departed = HashMap::<NodeId, Instant>::new();
+
+    for tick in 0..params.ticks() {
+        client
+            .signal_and_wait(
+                format!("state_tick_{tick}"),
+                run_parameters.test_instance_count,
+            )
+            .await?;
+        let tick_started_at = Instant::now();
+        let online = schedule.is_online(tick, seq);
+        let was_online = tick > 0 && schedule.is_online(tick - 1, seq);
+
+        // Join, leave or restart.
+        if online && !was_online {
+            start_discv5(&mut discv5).await?;
+            if has_joined {
+                info!("tick {tick}: Restarted.");
+            } else {
+                info!("tick {tick}: Joined.");
+                if seq != bootstrap.seq {
+                    discv5.add_enr(bootstrap.enr.clone())?;
+                }
+                has_joined = true;
+            }
+        } else if !online && was_online {
+            info!("tick {tick}: Shut down.");
+            discv5.shutdown();
+            result.not_evicted += departed.len() as u64;
+            departed.clear();
+        }
+
+        if !online {
+            tokio::time::sleep(tick_duration).await;
+            continue;
+        }
+
+        // Watch the peers which have just left, if they are connected.
+        departed.retain(|node_id, _| !schedule.is_online(tick, seqs[node_id]));
+        for (node_id, _, status) in discv5.table_entries() {
+            if let Some(&peer_seq) = seqs.get(&node_id) {
+                if schedule.has_left(tick, peer_seq) && status.state == ConnectionState::Connected {
+                    departed.insert(node_id, tick_started_at);
+                }
+            }
+        }
+
+        // Look up a live node, while sampling the routing table.
+        let live = participants
+            .iter()
+            .filter(|p| p.seq != seq && schedule.is_live(tick, p.seq))
+            .collect::<Vec<_>>();
+        let lookup = live.choose(&mut rand::thread_rng()).map(|target| {
+            let target_id = target.enr.node_id();
+            (target_id, tokio::spawn(discv5.find_node(target_id)))
+        });
+
+        let mut stale_entries = 0;
+        let mut table_entries = 0;
+        while tick_started_at.elapsed() + SAMPLING_INTERVAL < tick_duration {
+            tokio::time::sleep(SAMPLING_INTERVAL).await;
+
+            let entries = discv5.table_entries();
+            stale_entries = entries
+                .iter()
+                .filter(|(node_id, _, _)| match seqs.get(node_id) {
+                    Some(&peer_seq) => !schedule.is_online(tick, peer_seq),
+                    None => false,
+                })
+                .count() as u64;
+            table_entries = entries.len() as u64;
+            result.stale_entries += stale_entries;
+            result.table_entries += table_entries;
+
+            // A departed peer is evicted once it is no longer connected, or has been removed.
+            let connected = entries
+                .iter()
+                .filter(|(_, _, status)| status.state == ConnectionState::Connected)
+                .map(|(node_id, _, _)| *node_id)
+                .collect::<Vec<_>>();
+            departed.retain(|node_id, left_at| {
+                if connected.contains(node_id) {
+                    return true;
+                }
+                result
+                    .eviction_delays_ms
+                    .push(left_at.elapsed().as_millis() as u64);
+                false
+            });
+        }
+
+        let lookup_succeeded = match lookup {
+            Some((target_id, handle)) => {
+                result.lookups += 1;
+                let succeeded = match handle.await? {
+                    Ok(enrs) => enrs.iter().any(|enr| enr.node_id() == target_id),
+                    Err(e) => {
+                        warn!("tick {tick}: The lookup has failed: {e}");
+                        false
+                    }
+                };
+                if !succeeded {
+                    result.lookup_failures += 1;
+                }
+                Some(succeeded)
+            }
+            None => None,
+        };
+
+        // //////////////////////////////////////////////////////////////
+        // Record metrics
+        // //////////////////////////////////////////////////////////////
+        let mut write_query = WriteQuery::new(
+            Local::now().into(),
+            format!(
+                "discv5-testground_{}_{}",
+                run_parameters.test_case, run_parameters.test_run
+            ),
+        )
+        .add_field("tick", tick)
+        .add_field("table_entries", table_entries)
+        .add_field("stale_entries", stale_entries)
+        .add_field("departed_not_evicted", departed.len() as u64)
+        .add_tag("instance_seq", seq)
+        .add_tag("scope", "tick");
+        if let Some(succeeded) = lookup_succeeded {
+            write_query = write_query.add_field("lookup_succeeded", succeeded);
+        }
+        client.record_metric(write_query).await?;
+    }
+    result.not_evicted += departed.len() as u64;
+
+    client
+        .signal_and_wait(STATE_DONE, run_parameters.test_instance_count)
+        .await?;
+    discv5.shutdown();
+
+    // //////////////////////////////////////////////////////////////
+    // Aggregate the results of the run
+    // //////////////////////////////////////////////////////////////
+    let results = publish_and_collect_on(&client, "churn_results", result).await?;
+    if seq == 1 {
+        record_summary(&client, &results).await?;
+    }
+
+    client.record_success().await?;
+    Ok(())
+}
+
+async fn record_summary(
+    client: &Client,
+    results: &[ChurnResult],
+) -> Result<(), Box<dyn std::error::Error>> {
+    let run_parameters = client.run_parameters();
+    let lookups = results.iter().map(|r| r.lookups).sum::<u64>();
+    let lookup_failures = results.iter().map(|r| r.lookup_failures).sum::<u64>();
+    let not_evicted = results.iter().map(|r| r.not_evicted).sum::<u64>();
+    let stale_entries = results.iter().map(|r| r.stale_entries).sum::<u64>();
+    let table_entries = results.iter().map(|r| r.table_entries).sum::<u64>();
+    let mut eviction_delays = results
+        .iter()
+        .flat_map(|r| r.eviction_delays_ms.iter().copied())
+        .collect::<Vec<_>>();
+    eviction_delays.sort_unstable();
+
+    let lookup_failure_rate = if lookups == 0 {
+        0.
+    } else {
+        lookup_failures as f64 / lookups as f64
+    };
+    let stale_ratio = if table_entries == 0 {
+        0.
+    } else {
+        stale_entries as f64 / table_entries as f64
+    };
+    client.record_message(format!(
+        "lookups: {lookups}, lookup_failure_rate: {lookup_failure_rate}, stale_ratio: {stale_ratio}, evictions: {}, not_evicted: {not_evicted}",
+        eviction_delays.len()
+    ));
+
+    let mut write_query = WriteQuery::new(
+        Local::now().into(),
+        format!(
+            "discv5-testground_{}_{}",
+            run_parameters.test_case, run_parameters.test_run
+        ),
+    )
+    .add_field("lookups", lookups)
+    .add_field("lookup_failure_rate", lookup_failure_rate)
+    .add_field("stale_ratio", stale_ratio)
+    .add_field("evictions", eviction_delays.len() as u64)
+    .add_field("not_evicted", not_evicted)
+    .add_tag("scope", "run");
+    if !eviction_delays.is_empty() {
+        let percentile = |p: usize| eviction_delays[((eviction_delays.len() - 1) * p) / 100];
+        write_query = write_query
+            .add_field("eviction_delay_p50_ms", percentile(50))
+            .add_field("eviction_delay_p95_ms", percentile(95))
+            .add_field("eviction_delay_max_ms", percentile(100));
+    }
+    client.record_metric(write_query).await?;
+    Ok(())
+}
@@ -0,0 +1,30 @@
+use crate::utils::get_param;
+use std::collections::HashMap;
+
+pub(crate) struct Params {
+    pub ping_interval: u64,
+    pub duration: u64,
+    pub tick: u64,
+    pub leave_probability: f64,
+    pub rejoin_probability: f64,
+    pub late_joiners: u64,
+}
+
+impl Params {
+    pub(crate) fn new(
+        instance_params: &HashMap<String, String>,
+    ) -> Result<Params, Box<dyn std::error::Error>> {
+        Ok(Params {
+            ping_interval: get_param::<u64>("ping_interval", instance_params)?,
+            duration: get_param::<u64>("duration", instance_params)?,
+            tick: get_param::<u64>("tick", instance_params)?,
+            leave_probability: get_param::<f64>("leave_probability", instance_params)?,
+            rejoin_probability: get_param::<f64>("rejoin_probability", instance_params)?,
+            late_joiners: get_param::<u64>("late_joiners", instance_params)?,
+        })
+    }
+
+    pub(crate) fn ticks(&self) -> u64 {
+        self.duration / self.tick
+    }
+}
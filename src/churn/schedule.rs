@@ -0,0 +1,59 @@
+use crate::churn::params::Params;
+use rand::{Rng, SeedableRng};
+use rand_xorshift::XorShiftRng;
+
+/// Which instances are online at each tick. It is generated from a seed shared by all the
+/// instances, so that each of them knows which of its peers are online without communicating.
+///
+/// The instance #1 is the bootstrap node, which stays online. The last `late_joiners` instances
+/// are offline at first. After that, an online instance leaves with `leave_probability` and an
+/// offline one joins with `rejoin_probability` at each tick.
+pub(super) struct Schedule {
+    /// Indexed by the tick and then by the sequence number minus one.
+    online: Vec<Vec<bool>>,
+}
+
+impl Schedule {
+    pub(super) fn new(instance_count: u64, params: &Params, seed: u64) -> Self {
+        let mut rng = XorShiftRng::seed_from_u64(seed);
+        let first_late_joiner = instance_count.saturating_sub(params.late_joiners).max(1) + 1;
+        let mut online = vec![(1..=instance_count)
+            .map(|seq| seq < first_late_joiner)
+            .collect::<Vec<_>>()];
+
+        for _ in 1..params.ticks() {
+            let previous = online.last().expect("The first tick");
+            let next = previous
+                .iter()
+                .enumerate()
+                .map(|(i, &was_online)| {
+                    if i == 0 {
+                        true
+                    } else if was_online {
+                        !rng.gen_bool(params.leave_probability)
+                    } else {
+                        rng.gen_bool(params.rejoin_probability)
+                    }
+                })
+                .collect();
+            online.push(next);
+        }
+
+        Schedule { online }
+    }
+
+    pub(super) fn is_online(&self, tick: u64, seq: u64) -> bool {
+        self.online[tick as usize][seq as usize - 1]
+    }
+
+    /// Whether the instance has been online since the previous tick, so that the others have had
+    /// a chance to learn about it. A lookup for such an instance is expected to succeed.
+    pub(super) fn is_live(&self, tick: u64, seq: u64) -> bool {
+        tick > 0 && self.is_online(tick - 1, seq) && self.is_online(tick, seq)
+    }
+
+    /// Whether the instance has shut down at the beginning of the tick.
+    pub(super) fn has_left(&self, tick: u64, seq: u64) -> bool {
+        tick > 0 && self.is_online(tick - 1, seq) && !self.is_online(tick, seq)
+    }
+}
@@ -1,4 +1,6 @@
+use crate::capture;
 use crate::concurrent_requests::InstanceInfo;
+use crate::trace::{spawn_event_recorder, Tracer};
 use crate::utils::publish_and_collect;
 use discv5::enr::CombinedKey;
 use discv5::{Discv5, Enr, ListenConfig};
@@ -42,6 +44,7 @@ pub(crate) async fn run(client: Client) -> Result<(), Box<dyn std::error::Error>
 
     let another_instance_info = {
         let participants = publish_and_collect(&client, instance_info).await?;
+        capture::register_enrs(participants.iter().map(|p| &p.enr));
         assert_eq!(2, participants.len());
 
         let info = participants
@@ -69,6 +72,12 @@ pub(crate) async fn run(client: Client) -> Result<(), Box<dyn std::error::Error>
     // ////////////////////////
     let mut discv5: Discv5 = Discv5::new(enr.clone(), enr_key, config)?;
     discv5.start().await.expect("Start Discovery v5 server");
+    let tracer = Tracer::new(
+        &run_parameters.test_run,
+        client.global_seq(),
+        "discv5",
+        &enr,
+    );
 
     client
         .signal_and_wait(
@@ -79,6 +88,11 @@ pub(crate) async fn run(client: Client) -> Result<(), Box<dyn std::error::Error>
 
     match client.global_seq() {
         1 => {
+            spawn_event_recorder(
+                discv5.event_stream().await.expect("event stream"),
+                tracer.clone(),
+            );
+
             // Sent requests in parallel.
             let mut handles = vec![];
             for i in 0..2 {
@@ -97,6 +111,7 @@ pub(crate) async fn run(client: Client) -> Result<(), Box<dyn std::error::Error>
             let mut req_count = 0;
             let mut event = discv5.event_stream().await.expect("event stream");
             while let Some(ev) = event.recv().await {
+                tracer.record_event(&ev);
                 match ev {
                     discv5::Event::Discovered(_) => {}
                     discv5::Event::EnrAdded { .. } => {}
@@ -125,6 +140,7 @@ pub(crate) async fn run(client: Client) -> Result<(), Box<dyn std::error::Error>
         .signal_and_wait(STATE_FINISHED, client.run_parameters().test_instance_count)
         .await?;
 
+    tracer.write(&run_parameters.test_outputs_path)?;
     client.record_success().await?;
     Ok(())
 }
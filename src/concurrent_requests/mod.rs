@@ -1,6 +1,8 @@
 pub(crate) mod before_establishing_session;
 pub(crate) mod whoareyou_timeout;
 
+use crate::capture;
+use crate::trace::{spawn_event_recorder, Tracer};
 use crate::utils::publish_and_collect;
 use discv5::enr::CombinedKey;
 use discv5::{Discv5, Enr, ListenConfig};
@@ -57,6 +59,16 @@ pub(crate) async fn run(client: Client) -> Result<(), Box<dyn std::error::Error>
 
     let mut discv5: Discv5 = Discv5::new(enr.clone(), enr_key, config)?;
     discv5.start().await.expect("Start Discovery v5 server");
+    let tracer = Tracer::new(
+        &run_parameters.test_run,
+        client.global_seq(),
+        "discv5",
+        &enr,
+    );
+    spawn_event_recorder(
+        discv5.event_stream().await.expect("Discv5Event"),
+        tracer.clone(),
+    );
 
     // //////////////////////////////////////////////////////////////
     // Collect information of all participants in the test case
@@ -73,6 +85,7 @@ pub(crate) async fn run(client: Client) -> Result<(), Box<dyn std::error::Error>
     ));
 
     let participants = publish_and_collect(&client, instance_info.clone()).await?;
+    capture::register_enrs(participants.iter().map(|p| &p.enr));
 
     // //////////////////////////////////////////////////////////////
     // Construct topology
@@ -140,6 +153,7 @@ pub(crate) async fn run(client: Client) -> Result<(), Box<dyn std::error::Error>
         .signal_and_wait(STATE_COMPLETED, run_parameters.test_instance_count)
         .await?;
 
+    tracer.write(&run_parameters.test_outputs_path)?;
     if succeeded {
         client.record_success().await?;
     } else {
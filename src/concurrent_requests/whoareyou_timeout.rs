@@ -1,5 +1,7 @@
+use crate::capture;
 use crate::concurrent_requests::InstanceInfo;
-use crate::mock::{Action, Behaviour, Behaviours, Expect, Mock, Request};
+use crate::mock::{Action, Behaviour, Behaviours, Expect, Mock, Request, Step, UnorderedBehaviour};
+use crate::trace::{spawn_event_recorder, Tracer};
 use crate::utils::publish_and_collect;
 use discv5::enr::CombinedKey;
 use discv5::{Discv5, Enr, ListenConfig};
@@ -45,6 +47,7 @@ pub(crate) async fn run(client: Client) -> Result<(), Box<dyn std::error::Error>
 
     let another_instance_info = {
         let participants = publish_and_collect(&client, instance_info).await?;
+        capture::register_enrs(participants.iter().map(|p| &p.enr));
         assert_eq!(2, participants.len());
 
         let info = participants
@@ -86,8 +89,18 @@ async fn run_discv5(
     // ////////////////////////
     // Start discv5
     // ////////////////////////
-    let mut discv5: Discv5 = Discv5::new(enr.clone(), enr_key, config)?;
+    let tracer = Tracer::new(
+        &client.run_parameters().test_run,
+        client.global_seq(),
+        "discv5",
+        &enr,
+    );
+    let mut discv5: Discv5 = Discv5::new(enr, enr_key, config)?;
     discv5.start().await.expect("Start Discovery v5 server");
+    spawn_event_recorder(
+        discv5.event_stream().await.expect("Discv5Event"),
+        tracer.clone(),
+    );
 
     client
         .signal_and_wait(
@@ -121,6 +134,7 @@ async fn run_discv5(
         .signal_and_wait(STATE_FINISHED, client.run_parameters().test_instance_count)
         .await?;
 
+    tracer.write(&client.run_parameters().test_outputs_path)?;
     client.record_success().await?;
     Ok(())
 }
@@ -135,26 +149,45 @@ async fn run_mock(
     // ////////////////////////
     // Start mock
     // ////////////////////////
+    // The WHOAREYOU in reply to the random packet may arrive before or after the requests sent
+    // in parallel, so they are matched in any order.
     let mut behaviours = VecDeque::new();
-    behaviours.push_back(Behaviour {
-        expect: Expect::WhoAreYou,
-        actions: vec![Action::Ignore(
-            "Ingore WHOAREYOU packet to make happen a challenge timeout on Node1 side.".to_string(),
-        )],
-    });
-    behaviours.push_back(Behaviour {
-        expect: Expect::MessageWithoutSession,
-        actions: vec![Action::SendWhoAreYou],
-    });
-    behaviours.push_back(Behaviour {
-        expect: Expect::MessageWithoutSession,
-        actions: vec![Action::Ignore("WHOAREYOU packet already sent.".to_string())],
-    });
-    behaviours.push_back(Behaviour {
+    behaviours.push_back(Step::Unordered(vec![
+        UnorderedBehaviour::once(Behaviour {
+            expect: Expect::WhoAreYou,
+            actions: vec![Action::Ignore(
+                "Ingore WHOAREYOU packet to make happen a challenge timeout on Node1 side."
+                    .to_string(),
+            )],
+        }),
+        UnorderedBehaviour::once(Behaviour {
+            expect: Expect::MessageWithoutSession,
+            actions: vec![Action::SendWhoAreYou],
+        }),
+        UnorderedBehaviour::once(Behaviour {
+            expect: Expect::MessageWithoutSession,
+            actions: vec![Action::Ignore("WHOAREYOU packet already sent.".to_string())],
+        }),
+    ]));
+    behaviours.push_back(Step::Ordered(Behaviour {
         expect: Expect::Handshake(Request::FindNode),
         actions: vec![Action::EstablishSession, Action::Ignore("todo".to_string())],
-    });
-    // TODO: handle PING request
+    }));
+    // The packets sent over the session, such as PINGs, are out of the scope of this test case.
+    behaviours.push_back(Step::Unordered(vec![UnorderedBehaviour::any_number(
+        Behaviour {
+            expect: Expect::Any,
+            actions: vec![Action::Ignore(
+                "A packet after the session has been established.".to_string(),
+            )],
+        },
+    )]));
+    let tracer = Tracer::new(
+        &client.run_parameters().test_run,
+        client.global_seq(),
+        "mock",
+        &enr,
+    );
     let mut mock = Mock::start(enr, enr_key, config, Behaviours::Sequential(behaviours)).await;
 
     client
@@ -169,7 +202,6 @@ async fn run_mock(
     if let Err(e) = mock.send_random_packet(another_instance_info.enr) {
         error!("Failed to send random packet: {e}");
     }
-    tokio::time::sleep(Duration::from_secs(1)).await;
 
     client
         .signal_and_wait(
@@ -182,6 +214,8 @@ async fn run_mock(
         .signal_and_wait(STATE_FINISHED, client.run_parameters().test_instance_count)
         .await?;
 
+    tracer.record_packets(mock.transcript().await?.packets());
+    tracer.write(&client.run_parameters().test_outputs_path)?;
     client.record_success().await?;
     Ok(())
 }
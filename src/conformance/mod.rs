@@ -0,0 +1,436 @@
+use crate::capture;
+use crate::mock::{
+    compare_transcript, Action, Behaviour, Behaviours, Expect, Mock, Request, Response, Step,
+    VerificationKind,
+};
+use crate::utils::{get_param, publish_and_collect, start_discv5};
+use discv5::enr::{CombinedKey, NodeId};
+use discv5::rpc::{RequestBody, ResponseBody};
+use discv5::{Discv5, Enr, Event, Key, ListenConfig};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::net::{IpAddr, Ipv4Addr};
+use std::time::Duration;
+use testground::client::Client;
+use tracing::{error, info};
+
+const STATE_DISCV5_STARTED: &str = "state_discv5_started";
+const STATE_SESSION_ESTABLISHED: &str = "state_session_established";
+const STATE_RESPONSES_CHECKED: &str = "state_responses_checked";
+const STATE_FINISHED: &str = "state_finished";
+
+// The number of ENRs added to the routing table of the discv5 node.
+const NUM_TABLE_ENTRIES: usize = 10;
+// The number of handshakes the discv5 node initiates, before and after changing its key.
+const NUM_HANDSHAKES: usize = 2;
+// The distances the mock asks the discv5 node for.
+const DISTANCES: [u64; 3] = [0, 255, 256];
+
+const TALK_PROTOCOL: &[u8] = b"PROTOCOL";
+const TALK_REQUEST: &[u8] = b"A REQUEST";
+const TALK_RESPONSE: &[u8] = b"A RESPONSE";
+
+// The transcript the mock is expected to record, checked if `compare_transcript` is enabled.
+const GOLDEN_TRANSCRIPT: &str = include_str!("transcript.golden");
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct InstanceInfo {
+    // The sequence number of this test instance within the test.
+    seq: u64,
+    enr: Enr,
+}
+
+pub(crate) async fn run(client: Client) -> Result<(), Box<dyn std::error::Error>> {
+    let run_parameters = client.run_parameters();
+    let ip = run_parameters
+        .data_network_ip()?
+        .expect("IP address for the data network");
+
+    // ////////////////////////
+    // Construct local Enr
+    // ////////////////////////
+    let enr_key = CombinedKey::generate_secp256k1();
+    let enr = Enr::builder()
+        .ip(ip)
+        .udp4(9000)
+        .build(&enr_key)
+        .expect("enr");
+
+    // //////////////////////////////////////////////////////////////
+    // Collect information of all participants in the test case
+    // //////////////////////////////////////////////////////////////
+    let instance_info = InstanceInfo {
+        seq: client.global_seq(),
+        enr: enr.clone(),
+    };
+    client.record_message(format!(
+        "seq: {}, node_id: {}, ip: {}",
+        instance_info.seq,
+        instance_info.enr.node_id(),
+        ip
+    ));
+
+    let participants = publish_and_collect(&client, instance_info).await?;
+    capture::register_enrs(participants.iter().map(|p| &p.enr));
+    let another_instance_info = participants
+        .into_iter()
+        .find(|p| p.seq != client.global_seq())
+        .expect("Another instance");
+
+    // ////////////////////////
+    // Discv5 config
+    // ////////////////////////
+    let listen_config = ListenConfig::Ipv4 {
+        ip: Ipv4Addr::UNSPECIFIED,
+        port: 9000,
+    };
+    let config = discv5::ConfigBuilder::new(listen_config)
+        .request_timeout(Duration::from_secs(5))
+        .build();
+
+    match client.global_seq() {
+        1 => run_discv5(client, enr, enr_key, config, another_instance_info).await?,
+        2 => run_mock(client, enr, enr_key, config, another_instance_info).await?,
+        _ => unreachable!(),
+    }
+
+    Ok(())
+}
+
+async fn run_discv5(
+    client: Client,
+    enr: Enr,
+    enr_key: CombinedKey,
+    config: discv5::Config,
+    mock: InstanceInfo,
+) -> Result<(), Box<dyn std::error::Error>> {
+    // ////////////////////////
+    // Start discv5
+    // ////////////////////////
+    let ip = enr.ip4().expect("ip4");
+    let mut discv5: Discv5 = Discv5::new(enr, enr_key, config.clone())?;
+
+    // Fill the routing table so that the NODES responses are not empty.
+    for i in 0..NUM_TABLE_ENTRIES {
+        let key = CombinedKey::generate_secp256k1();
+        let enr = Enr::builder()
+            .ip4(Ipv4Addr::new(10, 0, 0, i as u8 + 1))
+            .udp4(9000)
+            .build(&key)
+            .expect("enr");
+        if let Err(e) = discv5.add_enr(enr) {
+            error!("Failed to add an ENR: {e}");
+        }
+    }
+    start_discv5(&mut discv5).await?;
+
+    // Respond to the TALKREQ sent by the mock.
+    let mut event_stream = discv5.event_stream().await.expect("Discv5Event");
+    tokio::spawn(async move {
+        while let Some(event) = event_stream.recv().await {
+            if let Event::TalkRequest(talk_request) = event {
+                info!("TalkRequest: {:?}", talk_request);
+                if let Err(e) = talk_request.respond(TALK_RESPONSE.to_vec()) {
+                    error!("Failed to send response: {e:?}");
+                }
+            }
+        }
+    });
+
+    client
+        .signal_and_wait(
+            STATE_DISCV5_STARTED,
+            client.run_parameters().test_instance_count,
+        )
+        .await?;
+
+    // Send a PING to establish a session with the mock.
+    let result = discv5.send_ping(mock.enr.clone()).await;
+    if let Err(e) = &result {
+        error!("Failed to send PING: {e}");
+    }
+
+    client
+        .signal_and_wait(
+            STATE_SESSION_ESTABLISHED,
+            client.run_parameters().test_instance_count,
+        )
+        .await?;
+
+    client
+        .signal_and_wait(
+            STATE_RESPONSES_CHECKED,
+            client.run_parameters().test_instance_count,
+        )
+        .await?;
+
+    // //////////////////////////////////////////////////////////////
+    // Restart with a new key, to let the mock check the handshake signed with it
+    // //////////////////////////////////////////////////////////////
+    // The socket may not have been released yet, which `start_discv5` retries on.
+    discv5.shutdown();
+    let enr_key = CombinedKey::generate_secp256k1();
+    let enr = Enr::builder()
+        .ip4(ip)
+        .udp4(9000)
+        .build(&enr_key)
+        .expect("enr");
+    let mut discv5: Discv5 = Discv5::new(enr, enr_key, config)?;
+    start_discv5(&mut discv5).await?;
+    let result_after_key_change = discv5.send_ping(mock.enr.clone()).await;
+    // Send a TALKREQ over the new session.
+    let talk_result = discv5
+        .talk_req(mock.enr, TALK_PROTOCOL.to_vec(), TALK_REQUEST.to_vec())
+        .await;
+
+    client
+        .signal_and_wait(STATE_FINISHED, client.run_parameters().test_instance_count)
+        .await?;
+
+    if let Err(e) = result {
+        client
+            .record_failure(format!("Failed to establish a session: {e}"))
+            .await?;
+    } else if let Err(e) = result_after_key_change {
+        client
+            .record_failure(format!(
+                "Failed to establish a session after changing the key: {e}"
+            ))
+            .await?;
+    } else if let Err(e) = talk_result {
+        client
+            .record_failure(format!("Failed to send TALKREQ: {e}"))
+            .await?;
+    } else {
+        client.record_success().await?;
+    }
+    Ok(())
+}
+
+async fn run_mock(
+    client: Client,
+    enr: Enr,
+    enr_key: CombinedKey,
+    config: discv5::Config,
+    discv5_node: InstanceInfo,
+) -> Result<(), Box<dyn std::error::Error>> {
+    // ////////////////////////
+    // Start mock
+    // ////////////////////////
+    // Once the session has been established, the mock sends a PING back to the discv5 node.
+    let mut behaviours = VecDeque::new();
+    behaviours.push_back(Step::Ordered(Behaviour {
+        expect: Expect::MessageWithoutSession,
+        actions: vec![Action::SendWhoAreYou],
+    }));
+    behaviours.push_back(Step::Ordered(Behaviour {
+        // The PING carries the sequence number of the discv5 node's ENR, and a valid request id.
+        expect: Expect::Handshake(Request::AllOf(vec![
+            Request::PingEnrSeq(discv5_node.enr.seq()),
+            Request::MaxIdLength(8),
+        ])),
+        // The PONGs report the socket of the discv5 node, as discv5 does.
+        actions: vec![
+            Action::EstablishSession,
+            Action::SendResponse(Response::DefaultWithObservedSocket),
+            Action::SendRequest(RequestBody::Ping { enr_seq: enr.seq() }),
+        ],
+    }));
+    // The discv5 node restarts with a new key, and then sends a PING and a TALKREQ.
+    behaviours.push_back(Step::Ordered(Behaviour {
+        expect: Expect::MessageWithoutSession,
+        actions: vec![Action::SendWhoAreYou],
+    }));
+    behaviours.push_back(Step::Ordered(Behaviour {
+        expect: Expect::Handshake(Request::Ping),
+        actions: vec![
+            Action::EstablishSession,
+            Action::SendResponse(Response::DefaultWithObservedSocket),
+        ],
+    }));
+    behaviours.push_back(Step::Ordered(Behaviour {
+        expect: Expect::Message(Request::AllOf(vec![
+            Request::TalkWith {
+                protocol: TALK_PROTOCOL.to_vec(),
+                request: Some(TALK_REQUEST.to_vec()),
+            },
+            Request::MaxIdLength(8),
+        ])),
+        actions: vec![Action::SendResponse(Response::Default)],
+    }));
+    let mut mock = Mock::start(
+        enr.clone(),
+        enr_key,
+        config,
+        Behaviours::Sequential(behaviours),
+    )
+    .await;
+
+    client
+        .signal_and_wait(
+            STATE_DISCV5_STARTED,
+            client.run_parameters().test_instance_count,
+        )
+        .await?;
+
+    client
+        .signal_and_wait(
+            STATE_SESSION_ESTABLISHED,
+            client.run_parameters().test_instance_count,
+        )
+        .await?;
+
+    // Send the rest of the requests over the established session.
+    let mut requests = vec![RequestBody::Talk {
+        protocol: TALK_PROTOCOL.to_vec(),
+        request: TALK_REQUEST.to_vec(),
+    }];
+    for distance in DISTANCES {
+        requests.push(RequestBody::FindNode {
+            distances: vec![distance],
+        });
+    }
+    for body in requests {
+        if let Err(e) = mock.send_request(discv5_node.enr.clone(), body) {
+            error!("Failed to send request: {e}");
+        }
+    }
+
+    // //////////////////////////////////////////////////////////////
+    // Check the responses
+    // //////////////////////////////////////////////////////////////
+    let mut errors = vec![];
+    // PING, TALKREQ and FINDNODE for each distance.
+    let mut pending_requests = 2 + DISTANCES.len();
+    let discv5_key: Key<NodeId> = discv5_node.enr.node_id().into();
+    let mut nodes_responses = HashMap::new();
+
+    while pending_requests > 0 {
+        let (request, response) =
+            match tokio::time::timeout(Duration::from_secs(10), mock.recv_response()).await {
+                Ok(Some(r)) => r,
+                Ok(None) => break,
+                Err(_) => {
+                    errors.push(format!(
+                        "Timed out waiting for responses. pending_requests: {pending_requests}"
+                    ));
+                    break;
+                }
+            };
+
+        let request_id = request.id;
+        match (request.body, response.body) {
+            (RequestBody::Ping { .. }, ResponseBody::Pong { enr_seq, ip, port }) => {
+                pending_requests -= 1;
+                // The PONG should report the address the mock has sent the PING from.
+                if ip != IpAddr::V4(enr.ip4().expect("ip4")) || port.get() != 9000 {
+                    errors.push(format!(
+                        "PONG reported an unexpected address. ip: {ip}, port: {port}"
+                    ));
+                }
+                if enr_seq != discv5_node.enr.seq() {
+                    errors.push(format!(
+                        "PONG reported an unexpected enr_seq. expected: {}, actual: {}",
+                        discv5_node.enr.seq(),
+                        enr_seq
+                    ));
+                }
+            }
+            (RequestBody::Talk { .. }, ResponseBody::Talk { response }) => {
+                pending_requests -= 1;
+                if response != TALK_RESPONSE {
+                    errors.push(format!(
+                        "TALKRESP has an unexpected body. expected: {TALK_RESPONSE:?}, actual: {response:?}"
+                    ));
+                }
+            }
+            (RequestBody::FindNode { distances }, ResponseBody::Nodes { total, nodes }) => {
+                // A NODES response can be split into multiple packets.
+                let received = nodes_responses.entry(request_id).or_insert(0);
+                *received += 1;
+                if *received >= total {
+                    pending_requests -= 1;
+                }
+                for node in nodes {
+                    // The distance 0 means the discv5 node itself.
+                    let distance = discv5_key
+                        .log2_distance(&node.node_id().into())
+                        .unwrap_or(0);
+                    if !distances.contains(&distance) {
+                        errors.push(format!(
+                            "NODES contains an ENR at an unexpected distance. requested: {distances:?}, actual: {distance}"
+                        ));
+                    }
+                }
+            }
+            (request, response) => {
+                pending_requests -= 1;
+                errors.push(format!(
+                    "Received an unexpected response. request: {request:?}, response: {response:?}"
+                ));
+            }
+        }
+    }
+
+    client
+        .signal_and_wait(
+            STATE_RESPONSES_CHECKED,
+            client.run_parameters().test_instance_count,
+        )
+        .await?;
+
+    // //////////////////////////////////////////////////////////////
+    // Check the id-nonce signatures in the handshakes
+    // //////////////////////////////////////////////////////////////
+    let mut signatures = 0;
+    while signatures < NUM_HANDSHAKES {
+        let verification =
+            match tokio::time::timeout(Duration::from_secs(10), mock.recv_verification()).await {
+                Ok(Some(v)) => v,
+                _ => {
+                    errors.push(format!(
+                        "Timed out waiting for handshakes. signatures: {signatures}"
+                    ));
+                    break;
+                }
+            };
+        if verification.kind == VerificationKind::IdNonceSignature {
+            signatures += 1;
+        }
+        if let Err(e) = verification.result {
+            errors.push(format!(
+                "{:?} from {}: {e}",
+                verification.kind, verification.node_address
+            ));
+        }
+    }
+
+    // //////////////////////////////////////////////////////////////
+    // Compare the transcript with the golden one
+    // //////////////////////////////////////////////////////////////
+    // The transcript is always logged so that the golden one can be updated from the logs.
+    let transcript = mock.transcript().await?.entries();
+    info!("Transcript:\n{}", transcript.join("\n"));
+    if get_param::<bool>(
+        "compare_transcript",
+        &client.run_parameters().test_instance_params,
+    )? {
+        if let Err(e) = compare_transcript(&transcript, GOLDEN_TRANSCRIPT) {
+            errors.push(e);
+        }
+    }
+
+    client
+        .signal_and_wait(STATE_FINISHED, client.run_parameters().test_instance_count)
+        .await?;
+
+    if errors.is_empty() {
+        client.record_success().await?;
+    } else {
+        for e in errors.iter() {
+            error!("{e}");
+        }
+        client.record_failure(errors.join(", ")).await?;
+    }
+    Ok(())
+}
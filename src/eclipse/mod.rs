@@ -1,11 +1,15 @@
-use crate::utils::publish_and_collect;
-use discv5::enr::k256::elliptic_curve::rand_core::RngCore;
-use discv5::enr::k256::elliptic_curve::rand_core::SeedableRng;
-use discv5::enr::{CombinedKey, NodeId};
-use discv5::{Discv5, Enr, ListenConfig};
+mod poisoned_nodes;
+
+use crate::capture;
+pub(super) use crate::eclipse::poisoned_nodes::PoisonedNodes;
+use crate::utils::{generate_deterministic_keypair, get_param, publish_and_collect, run_seed};
+use chrono::Local;
+use discv5::enr::{CombinedKey, EnrKey, NodeId};
+use discv5::{Discv5, Enr, Key, ListenConfig};
 use serde::{Deserialize, Serialize};
 use std::u64;
 use testground::client::Client;
+use testground::WriteQuery;
 use tokio::task;
 use tracing::debug;
 
@@ -14,6 +18,11 @@ const STATE_COMPLETED_TO_COLLECT_INSTANCE_INFORMATION: &str =
 const STATE_ATTACKERS_SENT_QUERY: &str = "STATE_ATTACKERS_SENT_QUERY";
 const STATE_DONE: &str = "STATE_DONE";
 
+// The range of the target distances. The node ids at a distance are searched for by generating
+// keys, and the ones at a distance below this are too rare to find in reasonable time.
+const MIN_TARGET_DISTANCE: u64 = 248;
+const MAX_TARGET_DISTANCE: u64 = 256;
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 enum Role {
     Victim,
@@ -38,6 +47,41 @@ struct InstanceInfo {
     role: Role,
 }
 
+/// The sizes of the attack, given by the test parameters. These must be the same in all the
+/// instances, since each instance derives its key from them.
+struct Params {
+    num_honest: u64,
+    num_attackers: u64,
+    // The distance of the victim's bucket the honest nodes and the attackers are put in.
+    target_distance: u64,
+}
+
+impl Params {
+    fn new(client: &Client) -> Result<Self, Box<dyn std::error::Error>> {
+        let instance_params = &client.run_parameters().test_instance_params;
+        let params = Params {
+            num_honest: get_param("num_honest", instance_params)?,
+            num_attackers: get_param("num_attackers", instance_params)?,
+            target_distance: get_param("target_distance", instance_params)?,
+        };
+
+        if !(MIN_TARGET_DISTANCE..=MAX_TARGET_DISTANCE).contains(&params.target_distance) {
+            return Err(format!(
+                "target_distance must be between {MIN_TARGET_DISTANCE} and {MAX_TARGET_DISTANCE}."
+            )
+            .into());
+        }
+        if 1 + params.num_honest + params.num_attackers
+            != client.run_parameters().test_instance_count
+        {
+            return Err(
+                "The number of instances must be 1 (victim) + num_honest + num_attackers.".into(),
+            );
+        }
+        Ok(params)
+    }
+}
+
 pub(super) struct MonopolizingByIncomingNodes {}
 
 impl MonopolizingByIncomingNodes {
@@ -49,6 +93,7 @@ impl MonopolizingByIncomingNodes {
         let run_parameters = client.run_parameters();
         // Note: The seq starts from 1.
         let role: Role = run_parameters.test_group_id.as_str().into();
+        let params = Params::new(&client)?;
         client.record_message(format!(
             "role: {:?}, group_seq: {}",
             role,
@@ -58,7 +103,12 @@ impl MonopolizingByIncomingNodes {
         // ////////////////////////
         // Construct a local Enr
         // ////////////////////////
-        let enr_key = Self::generate_deterministic_keypair(client.group_seq(), &role);
+        let enr_key = Self::generate_deterministic_keypair(
+            client.group_seq(),
+            &role,
+            &params,
+            run_seed(&run_parameters.test_run),
+        );
         let enr = Enr::builder()
             .ip(run_parameters
                 .data_network_ip()?
@@ -99,8 +149,9 @@ impl MonopolizingByIncomingNodes {
             role,
         };
 
-        let (victim, honest, attackers) =
-            self.collect_instance_info(&client, &instance_info).await?;
+        let (victim, honest, attackers) = self
+            .collect_instance_info(&client, &instance_info, &params)
+            .await?;
 
         client
             .signal_and_wait(
@@ -114,7 +165,7 @@ impl MonopolizingByIncomingNodes {
         // //////////////////////////////////////////////////////////////
         match instance_info.role {
             Role::Victim => {
-                self.play_victim(discv5, client, &honest, &attackers)
+                self.play_victim(discv5, client, &honest, &attackers, &params)
                     .await?
             }
             Role::Honest => self.play_honest(client).await?,
@@ -124,28 +175,40 @@ impl MonopolizingByIncomingNodes {
         Ok(())
     }
 
-    fn generate_deterministic_keypair(group_seq: u64, role: &Role) -> CombinedKey {
-        // Generate 20 key pairs. Distances between the first key pair and all other ones are the
-        // same. So in the node with the first key pair, node ids given from the other ones will be
-        // inserted into the same bucket.
+    fn generate_deterministic_keypair(
+        group_seq: u64,
+        role: &Role,
+        params: &Params,
+        seed: u64,
+    ) -> CombinedKey {
+        // Generate a key pair for each participant. Distances between the first key pair and all
+        // other ones are the same, `target_distance`. So in the node with the first key pair, node
+        // ids given from the other ones will be inserted into the same bucket.
         //
-        // The 20 key pairs generated are assigned to participants according to its role as follows:
+        // The key pairs generated are assigned to participants according to its role as follows:
         // - 0: victim
-        // - 1: honest
-        // - 2: attacker
-        // - 3: attacker
-        // ...
-        // - 19: attacker
+        // - 1..=num_honest: honest
+        // - the rest: attackers
         //
-        // The `122488` seed is a pre-computed one for this function. See `find_seed_same_bucket()`
-        // in https://github.com/sigp/discv5/blob/master/src/discv5/test.rs for more details of the
-        // pre-computing.
-        let mut keypairs = generate_deterministic_keypair(20, 122488);
+        // The key pairs are derived from the same seed in all the instances, which search for the
+        // ones at the target distance in the same order.
+        let victim_keypair = generate_deterministic_keypair(1, seed).remove(0);
+        let victim_key: Key<NodeId> = NodeId::from(victim_keypair.public()).into();
+        let mut keypairs = vec![victim_keypair];
+        keypairs.extend(
+            (1..)
+                .map(|i| generate_deterministic_keypair(1, seed.wrapping_add(i)).remove(0))
+                .filter(|keypair| {
+                    victim_key.log2_distance(&NodeId::from(keypair.public()).into())
+                        == Some(params.target_distance)
+                })
+                .take((params.num_honest + params.num_attackers) as usize),
+        );
 
         let index = match role {
             Role::Victim => group_seq,
             Role::Honest => group_seq + 1, // Take the number of victim into account
-            Role::Attacker => group_seq + 2, // Take the number of victim + honest into account
+            Role::Attacker => group_seq + 1 + params.num_honest, // Take the number of victim + honest into account
         } - 1; // The group_seq starts from 1, not from 0, so we should minus one here.
         keypairs.remove(usize::try_from(index).expect("Valid as usize"))
     }
@@ -154,12 +217,16 @@ impl MonopolizingByIncomingNodes {
         &self,
         client: &Client,
         instance_info: &InstanceInfo,
-    ) -> Result<(InstanceInfo, InstanceInfo, Vec<InstanceInfo>), Box<dyn std::error::Error>> {
+        params: &Params,
+    ) -> Result<(InstanceInfo, Vec<InstanceInfo>, Vec<InstanceInfo>), Box<dyn std::error::Error>>
+    {
         let mut victim = vec![];
         let mut honest = vec![];
         let mut attackers = vec![];
 
-        for i in publish_and_collect(client, instance_info.clone()).await? {
+        let participants = publish_and_collect(client, instance_info.clone()).await?;
+        capture::register_enrs(participants.iter().map(|p| &p.enr));
+        for i in participants {
             match i.role {
                 Role::Victim => victim.push(i),
                 Role::Honest => honest.push(i),
@@ -167,18 +234,25 @@ impl MonopolizingByIncomingNodes {
             }
         }
 
-        assert!(victim.len() == 1 && honest.len() == 1 && attackers.len() == 18);
+        assert!(
+            victim.len() == 1
+                && honest.len() as u64 == params.num_honest
+                && attackers.len() as u64 == params.num_attackers
+        );
 
-        Ok((victim.remove(0), honest.remove(0), attackers))
+        Ok((victim.remove(0), honest, attackers))
     }
 
     async fn play_victim(
         &self,
         discv5: Discv5,
         client: Client,
-        honest: &InstanceInfo,
+        honest: &[InstanceInfo],
         attackers: &[InstanceInfo],
+        params: &Params,
     ) -> Result<(), Box<dyn std::error::Error>> {
+        let run_parameters = client.run_parameters();
+
         // Wait until the attacker has done its attack.
         client
             .barrier(
@@ -198,20 +272,65 @@ impl MonopolizingByIncomingNodes {
             ));
         }
 
+        // The attackers which have been inserted into the target bucket.
+        let local_key: Key<NodeId> = discv5.local_enr().node_id().into();
+        let attacker_ids = attackers
+            .iter()
+            .map(|a| a.enr.node_id())
+            .collect::<Vec<_>>();
+        let attackers_in_bucket = discv5
+            .table_entries_id()
+            .into_iter()
+            .filter(|node_id| {
+                attacker_ids.contains(node_id)
+                    && local_key.log2_distance(&(*node_id).into()) == Some(params.target_distance)
+            })
+            .count();
+
         // If the victim is vulnerable to the eclipse attack, this will result in `Table full`
         // error because the bucket is full of the attacker's node id.
-        let result = discv5.add_enr(honest.enr.clone());
+        let mut errors = vec![];
+        for h in honest.iter() {
+            if let Err(msg) = discv5.add_enr(h.enr.clone()) {
+                errors.push(format!("Failed to add the honest node's ENR: {}", msg));
+            }
+        }
+        let honest_admitted = honest.len() - errors.len();
+
+        // //////////////////////////////////////////////////////////////
+        // Record metrics
+        // //////////////////////////////////////////////////////////////
+        let write_query = WriteQuery::new(
+            Local::now().into(),
+            format!(
+                "discv5-testground_{}_{}",
+                run_parameters.test_case, run_parameters.test_run
+            ),
+        )
+        .add_field("attackers_in_bucket", attackers_in_bucket as u64)
+        .add_field("honest_admitted", honest_admitted as u64)
+        .add_field("eclipsed", honest_admitted == 0)
+        .add_tag("num_honest", params.num_honest)
+        .add_tag("num_attackers", params.num_attackers)
+        .add_tag("target_distance", params.target_distance)
+        .add_tag(
+            "incoming_bucket_limit",
+            run_parameters
+                .test_instance_params
+                .get("incoming_bucket_limit")
+                .cloned()
+                .unwrap_or_default(),
+        );
+        client.record_metric(write_query).await?;
 
         client
             .signal_and_wait(STATE_DONE, client.run_parameters().test_instance_count)
             .await?;
 
-        if let Err(msg) = result {
-            client
-                .record_failure(format!("Failed to add the honest node's ENR: {}", msg))
-                .await?;
-        } else {
+        if errors.is_empty() {
             client.record_success().await?;
+        } else {
+            client.record_failure(errors.join(", ")).await?;
         }
         Ok(())
     }
@@ -253,25 +372,3 @@ impl MonopolizingByIncomingNodes {
         Ok(())
     }
 }
-
-// This function is copied from https://github.com/sigp/discv5/blob/master/src/discv5/test.rs
-// Generate `n` deterministic keypairs from a given seed.
-fn generate_deterministic_keypair(n: usize, seed: u64) -> Vec<CombinedKey> {
-    let mut keypairs = Vec::new();
-    for i in 0..n {
-        let sk = {
-            let rng = &mut rand_xorshift::XorShiftRng::seed_from_u64(seed + i as u64);
-            let mut b = [0; 32];
-            loop {
-                // until a value is given within the curve order
-                rng.fill_bytes(&mut b);
-                if let Ok(k) = enr::k256::ecdsa::SigningKey::from_slice(&b) {
-                    break k;
-                }
-            }
-        };
-        let kp = CombinedKey::from(sk);
-        keypairs.push(kp);
-    }
-    keypairs
-}
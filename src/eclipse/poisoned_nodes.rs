@@ -0,0 +1,387 @@
+use super::Role;
+use crate::capture;
+use crate::fleet::Fleet;
+use crate::mock::{Action, Behaviours, DeclarativeBehaviour, Response};
+use crate::utils::{get_param, publish_and_collect};
+use chrono::Local;
+use discv5::enr::{CombinedKey, NodeId};
+use discv5::{Discv5, Enr, ListenConfig};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::net::{IpAddr, Ipv4Addr};
+use std::str::FromStr;
+use std::time::Duration;
+use testground::client::Client;
+use testground::WriteQuery;
+use tracing::{error, info};
+
+const STATE_COMPLETED_TO_COLLECT_INSTANCE_INFORMATION: &str =
+    "STATE_COMPLETED_TO_COLLECT_INSTANCE_INFORMATION";
+const STATE_COMPLETED_TO_SEED_ROUTING_TABLES: &str = "STATE_COMPLETED_TO_SEED_ROUTING_TABLES";
+const STATE_DONE: &str = "STATE_DONE";
+
+const DISCV5_PORT: u16 = 9000;
+
+/// How the victim filters the nodes inserted into its routing table.
+#[derive(Clone, Copy, Debug)]
+enum TableFilter {
+    /// `none`. Accept all the nodes, which is the default of discv5.
+    None,
+    /// `ip-limit`. discv5's limit on the number of nodes from the same /24 subnet.
+    IpLimit,
+    /// `default-port`. A `table_filter` which only accepts the nodes listening on the default
+    /// port, which rejects the colluders hosted on the other ports of the attackers' instance.
+    DefaultPort,
+}
+
+impl FromStr for TableFilter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(TableFilter::None),
+            "ip-limit" => Ok(TableFilter::IpLimit),
+            "default-port" => Ok(TableFilter::DefaultPort),
+            _ => Err(format!("Unknown table filter: {s}")),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct InstanceInfo {
+    role: Role,
+    // The ENRs of the identities hosted in the instance.
+    enrs: Vec<Enr>,
+}
+
+/// The attackers answer the FINDNODE requests of the victim with NODES responses containing only
+/// colluding ENRs, so that the victim contacts and inserts the colluders as outgoing entries.
+/// The attackers need a single entry in the victim's routing table to start with.
+pub(crate) struct PoisonedNodes {}
+
+impl PoisonedNodes {
+    pub(crate) fn new() -> Self {
+        PoisonedNodes {}
+    }
+
+    pub(crate) async fn run(&self, client: Client) -> Result<(), Box<dyn std::error::Error>> {
+        let run_parameters = client.run_parameters();
+        let role: Role = run_parameters.test_group_id.as_str().into();
+        let ip = run_parameters
+            .data_network_ip()?
+            .expect("IP address for the data network");
+        client.record_message(format!(
+            "role: {:?}, group_seq: {}",
+            role,
+            client.group_seq()
+        ));
+
+        match role {
+            Role::Victim => self.play_victim(client, ip).await?,
+            Role::Honest => self.play_honest(client, ip).await?,
+            Role::Attacker => self.play_attackers(client, ip).await?,
+        }
+
+        Ok(())
+    }
+
+    async fn collect_instance_info(
+        &self,
+        client: &Client,
+        instance_info: InstanceInfo,
+    ) -> Result<(Enr, Vec<Enr>, Vec<Enr>), Box<dyn std::error::Error>> {
+        let mut victim = vec![];
+        let mut honest = vec![];
+        let mut attackers = vec![];
+
+        let participants = publish_and_collect(client, instance_info).await?;
+        capture::register_enrs(participants.iter().flat_map(|p| p.enrs.iter()));
+        for i in participants {
+            match i.role {
+                Role::Victim => victim.extend(i.enrs),
+                Role::Honest => honest.extend(i.enrs),
+                Role::Attacker => attackers.extend(i.enrs),
+            }
+        }
+
+        assert_eq!(1, victim.len());
+        assert!(!attackers.is_empty());
+
+        client
+            .signal_and_wait(
+                STATE_COMPLETED_TO_COLLECT_INSTANCE_INFORMATION,
+                client.run_parameters().test_instance_count,
+            )
+            .await?;
+
+        Ok((victim.remove(0), honest, attackers))
+    }
+
+    async fn play_victim(
+        &self,
+        client: Client,
+        ip: IpAddr,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let run_parameters = client.run_parameters();
+        let table_filter: TableFilter =
+            get_param("table_filter", &run_parameters.test_instance_params)?;
+        let rounds: u64 = get_param("rounds", &run_parameters.test_instance_params)?;
+        let round_interval: u64 =
+            get_param("round_interval", &run_parameters.test_instance_params)?;
+
+        // ////////////////////////
+        // Start discv5
+        // ////////////////////////
+        let enr_key = CombinedKey::generate_secp256k1();
+        let enr = Enr::builder()
+            .ip(ip)
+            .udp4(DISCV5_PORT)
+            .build(&enr_key)
+            .expect("Construct an Enr");
+        let mut config = discv5::ConfigBuilder::new(ListenConfig::Ipv4 {
+            ip: Ipv4Addr::UNSPECIFIED,
+            port: DISCV5_PORT,
+        });
+        match table_filter {
+            TableFilter::None => {}
+            TableFilter::IpLimit => {
+                config.ip_limit();
+            }
+            TableFilter::DefaultPort => {
+                config.table_filter(|enr| enr.udp4() == Some(DISCV5_PORT));
+            }
+        }
+        let mut discv5: Discv5 = Discv5::new(enr.clone(), enr_key, config.build())?;
+        discv5.start().await.expect("Start Discovery v5 server");
+
+        // //////////////////////////////////////////////////////////////
+        // Collect information of all participants in the test case
+        // //////////////////////////////////////////////////////////////
+        let (_, honest, attackers) = self
+            .collect_instance_info(
+                &client,
+                InstanceInfo {
+                    role: Role::Victim,
+                    enrs: vec![enr],
+                },
+            )
+            .await?;
+        let attacker_ids = attackers
+            .iter()
+            .map(|enr| enr.node_id())
+            .collect::<HashSet<_>>();
+
+        // The victim knows the honest nodes, and a single attacker.
+        for enr in honest.iter().chain(attackers.first()) {
+            if let Err(e) = discv5.add_enr(enr.clone()) {
+                error!("Failed to add an ENR: {e}");
+            }
+        }
+
+        client
+            .signal_and_wait(
+                STATE_COMPLETED_TO_SEED_ROUTING_TABLES,
+                run_parameters.test_instance_count,
+            )
+            .await?;
+
+        // //////////////////////////////////////////////////////////////
+        // Run lookups, watching how much the attackers control
+        // //////////////////////////////////////////////////////////////
+        for round in 0..rounds {
+            let found = match discv5.find_node(NodeId::random()).await {
+                Ok(found) => found,
+                Err(e) => {
+                    error!("Failed to run a lookup: {e}");
+                    vec![]
+                }
+            };
+            let found_attackers = found
+                .iter()
+                .filter(|enr| attacker_ids.contains(&enr.node_id()))
+                .count();
+
+            let table = discv5.table_entries_id();
+            let table_attackers = table
+                .iter()
+                .filter(|node_id| attacker_ids.contains(node_id))
+                .count();
+
+            info!(
+                "round {round}: table: {table_attackers}/{}, lookup: {found_attackers}/{}",
+                table.len(),
+                found.len()
+            );
+
+            let write_query = WriteQuery::new(
+                Local::now().into(),
+                format!(
+                    "discv5-testground_{}_{}",
+                    run_parameters.test_case, run_parameters.test_run
+                ),
+            )
+            .add_field("round", round)
+            .add_field("table_entries", table.len() as u64)
+            .add_field("table_attackers", table_attackers as u64)
+            .add_field("table_attacker_ratio", ratio(table_attackers, table.len()))
+            .add_field("lookup_results", found.len() as u64)
+            .add_field("lookup_attackers", found_attackers as u64)
+            .add_field("lookup_attacker_ratio", ratio(found_attackers, found.len()))
+            .add_tag("num_attackers", attackers.len() as u64)
+            .add_tag("table_filter", format!("{table_filter:?}"));
+            client.record_metric(write_query).await?;
+
+            tokio::time::sleep(Duration::from_secs(round_interval)).await;
+        }
+
+        client
+            .signal_and_wait(STATE_DONE, run_parameters.test_instance_count)
+            .await?;
+
+        client.record_success().await?;
+        Ok(())
+    }
+
+    async fn play_honest(
+        &self,
+        client: Client,
+        ip: IpAddr,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let run_parameters = client.run_parameters();
+
+        // ////////////////////////
+        // Start discv5
+        // ////////////////////////
+        let enr_key = CombinedKey::generate_secp256k1();
+        let enr = Enr::builder()
+            .ip(ip)
+            .udp4(DISCV5_PORT)
+            .build(&enr_key)
+            .expect("Construct an Enr");
+        let config = discv5::ConfigBuilder::new(ListenConfig::Ipv4 {
+            ip: Ipv4Addr::UNSPECIFIED,
+            port: DISCV5_PORT,
+        })
+        .build();
+        let mut discv5: Discv5 = Discv5::new(enr.clone(), enr_key, config)?;
+        discv5.start().await.expect("Start Discovery v5 server");
+
+        // //////////////////////////////////////////////////////////////
+        // Collect information of all participants in the test case
+        // //////////////////////////////////////////////////////////////
+        let (victim, honest, _) = self
+            .collect_instance_info(
+                &client,
+                InstanceInfo {
+                    role: Role::Honest,
+                    enrs: vec![enr.clone()],
+                },
+            )
+            .await?;
+
+        // The honest nodes know each other and the victim, but none of the attackers.
+        for other in honest
+            .iter()
+            .filter(|&other| other != &enr)
+            .chain([&victim])
+        {
+            if let Err(e) = discv5.add_enr(other.clone()) {
+                error!("Failed to add an ENR: {e}");
+            }
+        }
+
+        client
+            .signal_and_wait(
+                STATE_COMPLETED_TO_SEED_ROUTING_TABLES,
+                run_parameters.test_instance_count,
+            )
+            .await?;
+
+        // Nothing to do, just answer the victim until the simulation has been done.
+        client
+            .signal_and_wait(STATE_DONE, run_parameters.test_instance_count)
+            .await?;
+
+        client.record_success().await?;
+        Ok(())
+    }
+
+    async fn play_attackers(
+        &self,
+        client: Client,
+        ip: IpAddr,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let run_parameters = client.run_parameters();
+        let num_attackers: usize =
+            get_param("num_attackers", &run_parameters.test_instance_params)?;
+
+        // ////////////////////////
+        // Start the colluders
+        // ////////////////////////
+        // The mocks answer FINDNODE with the ENRs they know at the requested distances, as discv5
+        // does. Since they know only the colluders, the responses are poisoned, yet never at
+        // unsolicited distances which would get the attacker banned.
+        let keys = (0..num_attackers)
+            .map(|_| CombinedKey::generate_secp256k1())
+            .collect();
+        let mut fleet = Fleet::start_mock(
+            ip,
+            DISCV5_PORT,
+            keys,
+            |listen_config| discv5::ConfigBuilder::new(listen_config).build(),
+            || {
+                Behaviours::Declarative(DeclarativeBehaviour {
+                    whoareyou: vec![Action::Ignore("Unexpected WHOAREYOU".to_string())],
+                    handshake: vec![
+                        Action::EstablishSession,
+                        Action::SendResponse(Response::Default),
+                    ],
+                    message: vec![Action::SendResponse(Response::Default)],
+                    message_without_session: vec![Action::SendWhoAreYou],
+                })
+            },
+        )
+        .await;
+
+        let colluders = fleet.enrs();
+        for member in fleet.members.iter_mut() {
+            for colluder in colluders.iter().filter(|&c| c != &member.enr) {
+                member.node.add_enr(colluder.clone())?;
+            }
+        }
+
+        // //////////////////////////////////////////////////////////////
+        // Collect information of all participants in the test case
+        // //////////////////////////////////////////////////////////////
+        self.collect_instance_info(
+            &client,
+            InstanceInfo {
+                role: Role::Attacker,
+                enrs: colluders,
+            },
+        )
+        .await?;
+
+        client
+            .signal_and_wait(
+                STATE_COMPLETED_TO_SEED_ROUTING_TABLES,
+                run_parameters.test_instance_count,
+            )
+            .await?;
+
+        client
+            .signal_and_wait(STATE_DONE, run_parameters.test_instance_count)
+            .await?;
+
+        client.record_success().await?;
+        Ok(())
+    }
+}
+
+fn ratio(part: usize, total: usize) -> f64 {
+    if total == 0 {
+        0.
+    } else {
+        part as f64 / total as f64
+    }
+}
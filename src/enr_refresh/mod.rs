@@ -0,0 +1,346 @@
+use crate::capture;
+use crate::mock::{Action, Behaviours, DeclarativeBehaviour, EnrUpdate, Mock, Response};
+use crate::utils::{get_param, publish_and_collect};
+use discv5::enr::CombinedKey;
+use discv5::rpc::{RequestBody, ResponseBody};
+use discv5::{Discv5, Enr, ListenConfig};
+use serde::{Deserialize, Serialize};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::time::Duration;
+use testground::client::Client;
+use tracing::{error, info};
+
+const STATE_CONNECTED: &str = "state_connected";
+const STATE_FINISHED: &str = "state_finished";
+
+const CUSTOM_FIELD: (&str, &[u8]) = ("foo", b"bar");
+// The port the mock advertises after updating its socket. Nothing listens on it.
+const UPDATED_PORT: u16 = 9001;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct InstanceInfo {
+    // The sequence number of this test instance within the test.
+    seq: u64,
+    enr: Enr,
+}
+
+/// The updates the mock makes to its ENR, in order. Each one bumps the sequence number by one.
+#[derive(Clone, Copy, Debug)]
+enum UpdateCase {
+    // The updated sequence number is advertised in the PONG to the periodic PING of the discv5
+    // node.
+    Field,
+    // The updated sequence number is advertised in a PING sent by the mock.
+    Seq,
+    // Same as `Seq`. This must be the last one since the discv5 node can no longer reach the mock
+    // once it has adopted the updated ENR.
+    Socket,
+}
+
+const UPDATE_CASES: [UpdateCase; 3] = [UpdateCase::Field, UpdateCase::Seq, UpdateCase::Socket];
+
+pub(crate) async fn run(client: Client) -> Result<(), Box<dyn std::error::Error>> {
+    let run_parameters = client.run_parameters();
+    let ip = run_parameters
+        .data_network_ip()?
+        .expect("IP address for the data network");
+    let ping_interval = get_param::<u64>("ping_interval", &run_parameters.test_instance_params)?;
+
+    // ////////////////////////
+    // Construct local Enr
+    // ////////////////////////
+    let enr_key = CombinedKey::generate_secp256k1();
+    let enr = Enr::builder()
+        .ip(ip)
+        .udp4(9000)
+        .build(&enr_key)
+        .expect("enr");
+
+    // //////////////////////////////////////////////////////////////
+    // Collect information of all participants in the test case
+    // //////////////////////////////////////////////////////////////
+    let instance_info = InstanceInfo {
+        seq: client.global_seq(),
+        enr: enr.clone(),
+    };
+    client.record_message(format!(
+        "seq: {}, node_id: {}, ip: {}",
+        instance_info.seq,
+        instance_info.enr.node_id(),
+        ip
+    ));
+
+    let participants = publish_and_collect(&client, instance_info).await?;
+    capture::register_enrs(participants.iter().map(|p| &p.enr));
+    let another_instance_info = participants
+        .into_iter()
+        .find(|p| p.seq != client.global_seq())
+        .expect("Another instance");
+
+    // ////////////////////////
+    // Discv5 config
+    // ////////////////////////
+    let listen_config = ListenConfig::Ipv4 {
+        ip: Ipv4Addr::UNSPECIFIED,
+        port: 9000,
+    };
+    let config = discv5::ConfigBuilder::new(listen_config)
+        .ping_interval(Duration::from_secs(ping_interval))
+        .request_timeout(Duration::from_secs(3))
+        .build();
+
+    match client.global_seq() {
+        1 => {
+            run_discv5(
+                client,
+                enr,
+                enr_key,
+                config,
+                another_instance_info,
+                ping_interval,
+            )
+            .await?
+        }
+        2 => run_mock(client, enr, enr_key, config, another_instance_info).await?,
+        _ => unreachable!(),
+    }
+
+    Ok(())
+}
+
+async fn run_discv5(
+    client: Client,
+    enr: Enr,
+    enr_key: CombinedKey,
+    config: discv5::Config,
+    mock: InstanceInfo,
+    ping_interval: u64,
+) -> Result<(), Box<dyn std::error::Error>> {
+    // ////////////////////////
+    // Start discv5
+    // ////////////////////////
+    let mut discv5: Discv5 = Discv5::new(enr, enr_key, config)?;
+    discv5.start().await.expect("Start Discovery v5 server");
+
+    // Insert the mock into the routing table as a connected node, so that the discv5 node pings
+    // it periodically and requests its ENR when it has been updated.
+    let mut errors = vec![];
+    discv5.add_enr(mock.enr.clone())?;
+    if let Err(e) = discv5.send_ping(mock.enr.clone()).await {
+        errors.push(format!("Failed to send PING: {e}"));
+    }
+
+    client
+        .signal_and_wait(STATE_CONNECTED, client.run_parameters().test_instance_count)
+        .await?;
+
+    // //////////////////////////////////////////////////////////////
+    // Check the routing table entry of the mock after each update
+    // //////////////////////////////////////////////////////////////
+    let mock_id = mock.enr.node_id();
+    for (case, expected_seq) in UPDATE_CASES.iter().zip(mock.enr.seq() + 1..) {
+        client
+            .signal_and_wait(
+                case.updated_state(),
+                client.run_parameters().test_instance_count,
+            )
+            .await?;
+
+        // The periodic PING can take up to `ping_interval` to be sent.
+        let deadline = tokio::time::Instant::now() + Duration::from_secs(ping_interval + 5);
+        let mut entry = discv5.find_enr(&mock_id);
+        while entry.as_ref().map(|enr| enr.seq()) != Some(expected_seq)
+            && tokio::time::Instant::now() < deadline
+        {
+            tokio::time::sleep(Duration::from_millis(500)).await;
+            entry = discv5.find_enr(&mock_id);
+        }
+
+        match entry {
+            Some(enr) if enr.seq() == expected_seq => {
+                info!("The routing table entry has been updated. {case:?}: {enr}");
+                if let Err(e) = case.check(&enr) {
+                    errors.push(format!("{case:?}: {e}"));
+                }
+            }
+            Some(enr) => errors.push(format!(
+                "The routing table entry hasn't been updated. {case:?}: expected_seq:{expected_seq}, actual_seq:{}",
+                enr.seq()
+            )),
+            None => errors.push(format!(
+                "The mock has been removed from the routing table. {case:?}"
+            )),
+        }
+
+        client
+            .signal_and_wait(
+                case.checked_state(),
+                client.run_parameters().test_instance_count,
+            )
+            .await?;
+    }
+
+    client
+        .signal_and_wait(STATE_FINISHED, client.run_parameters().test_instance_count)
+        .await?;
+
+    if errors.is_empty() {
+        client.record_success().await?;
+    } else {
+        for e in errors.iter() {
+            error!("{e}");
+        }
+        client.record_failure(errors.join(", ")).await?;
+    }
+    Ok(())
+}
+
+async fn run_mock(
+    client: Client,
+    enr: Enr,
+    enr_key: CombinedKey,
+    config: discv5::Config,
+    discv5_node: InstanceInfo,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let ip = enr.ip4().expect("ip4");
+
+    // ////////////////////////
+    // Start mock
+    // ////////////////////////
+    // The default responses advertise the current ENR of the mock: PONG carries its sequence
+    // number, and NODES in reply to FINDNODE with distance 0 carries the ENR itself. PONG reports
+    // the observed socket, since the socket in the ENR is updated to one the mock isn't bound to.
+    let behaviours = Behaviours::Declarative(DeclarativeBehaviour {
+        whoareyou: vec![Action::Ignore("Unexpected WHOAREYOU".to_string())],
+        handshake: vec![
+            Action::EstablishSession,
+            Action::SendResponse(Response::DefaultWithObservedSocket),
+        ],
+        message: vec![Action::SendResponse(Response::DefaultWithObservedSocket)],
+        message_without_session: vec![Action::SendWhoAreYou],
+    });
+    let mut mock = Mock::start(enr, enr_key, config, behaviours).await;
+
+    client
+        .signal_and_wait(STATE_CONNECTED, client.run_parameters().test_instance_count)
+        .await?;
+
+    // //////////////////////////////////////////////////////////////
+    // Update the ENR
+    // //////////////////////////////////////////////////////////////
+    let mut errors = vec![];
+    for (case, expected_requests) in UPDATE_CASES.iter().zip(1u64..) {
+        match mock.update_enr(case.update(ip.into())).await {
+            Ok(updated_enr) => {
+                if case.pinged_by_mock() {
+                    let body = RequestBody::Ping {
+                        enr_seq: updated_enr.seq(),
+                    };
+                    mock.send_request(discv5_node.enr.clone(), body)?;
+                    match tokio::time::timeout(Duration::from_secs(5), mock.recv_response()).await {
+                        Ok(Some((_, response))) => match response.body {
+                            ResponseBody::Pong { .. } => {}
+                            body => errors.push(format!(
+                                "Received an unexpected response. {case:?}: {body:?}"
+                            )),
+                        },
+                        _ => errors.push(format!("No response to the PING. {case:?}")),
+                    }
+                }
+            }
+            Err(e) => errors.push(format!("Failed to update the ENR. {case:?}: {e}")),
+        }
+
+        client
+            .signal_and_wait(
+                case.updated_state(),
+                client.run_parameters().test_instance_count,
+            )
+            .await?;
+
+        client
+            .signal_and_wait(
+                case.checked_state(),
+                client.run_parameters().test_instance_count,
+            )
+            .await?;
+
+        // The discv5 node should have requested the updated ENR with FINDNODE distance 0.
+        let stats = mock.stats().await?;
+        if stats.enr_requests_answered < expected_requests {
+            errors.push(format!(
+                "The discv5 node hasn't requested the updated ENR. {case:?}: enr_requests_answered:{}",
+                stats.enr_requests_answered
+            ));
+        }
+    }
+
+    client
+        .signal_and_wait(STATE_FINISHED, client.run_parameters().test_instance_count)
+        .await?;
+
+    if errors.is_empty() {
+        client.record_success().await?;
+    } else {
+        for e in errors.iter() {
+            error!("{e}");
+        }
+        client.record_failure(errors.join(", ")).await?;
+    }
+    Ok(())
+}
+
+impl UpdateCase {
+    fn update(&self, ip: IpAddr) -> EnrUpdate {
+        match self {
+            UpdateCase::Field => {
+                EnrUpdate::Field(CUSTOM_FIELD.0.to_string(), CUSTOM_FIELD.1.to_vec())
+            }
+            UpdateCase::Seq => EnrUpdate::Seq,
+            UpdateCase::Socket => EnrUpdate::Socket(SocketAddr::new(ip, UPDATED_PORT)),
+        }
+    }
+
+    fn pinged_by_mock(&self) -> bool {
+        match self {
+            UpdateCase::Field => false,
+            UpdateCase::Seq | UpdateCase::Socket => true,
+        }
+    }
+
+    fn updated_state(&self) -> &'static str {
+        match self {
+            UpdateCase::Field => "state_field_updated",
+            UpdateCase::Seq => "state_seq_updated",
+            UpdateCase::Socket => "state_socket_updated",
+        }
+    }
+
+    fn checked_state(&self) -> &'static str {
+        match self {
+            UpdateCase::Field => "state_field_checked",
+            UpdateCase::Seq => "state_seq_checked",
+            UpdateCase::Socket => "state_socket_checked",
+        }
+    }
+
+    /// Checks the ENR the discv5 node has stored in its routing table.
+    fn check(&self, enr: &Enr) -> Result<(), String> {
+        match self {
+            UpdateCase::Field => match enr.get(CUSTOM_FIELD.0) {
+                Some(value) if value == CUSTOM_FIELD.1 => Ok(()),
+                value => Err(format!(
+                    "The field {} is missing or wrong. value:{value:?}",
+                    CUSTOM_FIELD.0
+                )),
+            },
+            UpdateCase::Seq => Ok(()),
+            UpdateCase::Socket => match enr.udp4() {
+                Some(UPDATED_PORT) => Ok(()),
+                port => Err(format!(
+                    "The socket hasn't been updated. expected:{UPDATED_PORT}, actual:{port:?}"
+                )),
+            },
+        }
+    }
+}
@@ -1,13 +1,17 @@
 mod params;
 
+use crate::capture;
 use crate::enr_update::params::Params;
-use crate::utils::publish_and_collect;
+use crate::trace::{spawn_event_recorder, Tracer};
+use crate::utils::{publish_and_collect, start_discv5};
 use chrono::Local;
 use discv5::enr::CombinedKey;
 use discv5::{Discv5, Enr, ListenConfig};
 use serde::{Deserialize, Serialize};
-use std::time::Duration;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
 use testground::client::Client;
+use testground::WriteQuery;
 use tokio::{sync, task};
 use tracing::{debug, error, info};
 
@@ -24,11 +28,27 @@ struct InstanceInfo {
 pub(super) async fn run(client: Client) -> Result<(), Box<dyn std::error::Error>> {
     let run_parameters = client.run_parameters();
     let params = Params::new(&run_parameters.test_instance_params)?;
+    if params.trials == 0 {
+        return Err("`trials` must be at least 1.".into());
+    }
+    if params.enr_peer_update_min == 0
+        || params.enr_peer_update_min as u64 > run_parameters.test_instance_count - 1
+    {
+        return Err(
+            "`enr_peer_update_min` must be between 1 and the number of the other instances.".into(),
+        );
+    }
+    // The votes come from the PONGs to the PINGs sent every `ping_interval`.
+    if params.update_bound <= params.ping_interval {
+        return Err("`update_bound` must be longer than `ping_interval`.".into());
+    }
 
     // ////////////////////////
     // Construct a local Enr
     // ////////////////////////
     let enr_key = CombinedKey::generate_secp256k1();
+    // Node 1 restarts with the same key in each trial, so that it keeps its identity.
+    let secret_key = enr_key.encode();
 
     let enr = if client.global_seq() == 1 {
         Enr::builder().build(&enr_key).expect("Construct an Enr")
@@ -48,15 +68,14 @@ pub(super) async fn run(client: Client) -> Result<(), Box<dyn std::error::Error>
     // //////////////////////////////////////////////////////////////
     // Start Discovery v5 server
     // //////////////////////////////////////////////////////////////
-    let mut discv5: Discv5 = Discv5::new(
-        enr,
-        enr_key,
-        discv5::ConfigBuilder::new(ListenConfig::default())
-            .ping_interval(Duration::from_secs(params.ping_interval))
-            .build(),
-    )?;
+    let mut discv5 = new_discv5(enr, enr_key, &params)?;
     discv5.start().await.expect("Start Discovery v5 server");
-    let started_up_at = Local::now();
+    let tracer = Tracer::new(
+        &run_parameters.test_run,
+        client.global_seq(),
+        "discv5",
+        &discv5.local_enr(),
+    );
 
     // //////////////////////////////////////////////////////////////
     // Collect information of all participants in the test case
@@ -68,29 +87,72 @@ pub(super) async fn run(client: Client) -> Result<(), Box<dyn std::error::Error>
     debug!("instance_info: {:?}", instance_info);
 
     let participants = publish_and_collect(&client, instance_info.clone()).await?;
+    capture::register_enrs(participants.iter().map(|p| &p.enr));
+
+    if instance_info.seq != 1 {
+        spawn_event_recorder(
+            discv5.event_stream().await.expect("Discv5Event"),
+            tracer.clone(),
+        );
+
+        client
+            .signal_and_wait(
+                STATE_COMPLETED_ESTABLISH_CONNECTIONS,
+                run_parameters.test_instance_count,
+            )
+            .await?;
+        record_peers(&client, &discv5);
+
+        client
+            .signal_and_wait(STATE_COMPLETED, run_parameters.test_instance_count)
+            .await?;
+
+        tracer.write(&run_parameters.test_outputs_path)?;
+        client.record_success().await?;
+        return Ok(());
+    }
+
+    // //////////////////////////////////////////////////////////////
+    // Run the trials
+    // //////////////////////////////////////////////////////////////
+    // In each trial, Node 1 starts with an ENR without the socket address, and discovers it
+    // through the PONG responses from its peers.
+    let bound = Duration::from_secs(params.update_bound);
+    let mut latencies = vec![];
+    let mut errors = vec![];
+    for trial in 0..params.trials {
+        if trial > 0 {
+            // Restart with the same identity, and an ENR which has lost the socket address
+            // again. The sequence number keeps increasing, so that the peers accept the ENR.
+            discv5.shutdown();
+            let enr_key = CombinedKey::secp256k1_from_bytes(&mut secret_key.clone())?;
+            let enr = Enr::builder()
+                .seq(discv5.local_enr().seq() + 1)
+                .build(&enr_key)
+                .expect("Construct an Enr");
+            discv5 = new_discv5(enr, enr_key, &params)?;
+            start_discv5(&mut discv5).await?;
+        }
 
-    let maybe_receiver = if instance_info.seq == 1 {
         let (sender, receiver) = sync::oneshot::channel();
         let mut event_stream = discv5.event_stream().await.expect("Discv5Event");
-
+        let event_tracer = tracer.clone();
         task::spawn(async move {
+            let mut sender = Some(sender);
             while let Some(event) = event_stream.recv().await {
+                event_tracer.record_event(&event);
                 if let discv5::Event::SocketUpdated(socket_addr) = event {
-                    sender.send(socket_addr).unwrap();
-                    break;
+                    if let Some(sender) = sender.take() {
+                        let _ = sender.send((socket_addr, Instant::now()));
+                    }
                 }
             }
         });
 
-        Some(receiver)
-    } else {
-        None
-    };
-
-    // //////////////////////////////////////////////////////////////
-    // Establish connections
-    // //////////////////////////////////////////////////////////////
-    if instance_info.seq == 1 {
+        // //////////////////////////////////////////////////////////////
+        // Establish connections
+        // //////////////////////////////////////////////////////////////
+        let started_at = Instant::now();
         for p in participants
             .iter()
             .filter(|&p| p.seq != client.global_seq())
@@ -102,15 +164,119 @@ pub(super) async fn run(client: Client) -> Result<(), Box<dyn std::error::Error>
                 error!("Failed to run FIND_NODE query: {e}");
             }
         }
+
+        if trial == 0 {
+            client
+                .signal_and_wait(
+                    STATE_COMPLETED_ESTABLISH_CONNECTIONS,
+                    run_parameters.test_instance_count,
+                )
+                .await?;
+            record_peers(&client, &discv5);
+        }
+
+        // //////////////////////////////////////////////////////////////
+        // Wait for the socket to be updated
+        // //////////////////////////////////////////////////////////////
+        let updated: Option<(SocketAddr, Instant)> =
+            tokio::time::timeout(bound.saturating_sub(started_at.elapsed()), receiver)
+                .await
+                .ok()
+                .and_then(Result::ok);
+        let latency = match updated {
+            Some((socket_addr, updated_at)) => {
+                let latency = updated_at - started_at;
+                info!("trial {trial}: Discv5Event::SocketUpdated {socket_addr} in {latency:?}");
+                latencies.push(latency.as_millis() as u64);
+                Some(latency)
+            }
+            None => {
+                errors.push(format!(
+                    "trial {trial}: The socket has not been updated within {bound:?}."
+                ));
+                None
+            }
+        };
+
+        let mut write_query = WriteQuery::new(
+            Local::now().into(),
+            format!(
+                "discv5-testground_{}_{}",
+                run_parameters.test_case, run_parameters.test_run
+            ),
+        )
+        .add_field("trial", trial)
+        .add_field("socket_updated", latency.is_some())
+        .add_tag("ping_interval", params.ping_interval)
+        .add_tag("enr_peer_update_min", params.enr_peer_update_min as u64)
+        .add_tag("scope", "trial");
+        if let Some(latency) = latency {
+            write_query =
+                write_query.add_field("socket_update_latency_ms", latency.as_millis() as u64);
+        }
+        client.record_metric(write_query).await?;
     }
 
+    // //////////////////////////////////////////////////////////////
+    // Record the distribution of the latencies
+    // //////////////////////////////////////////////////////////////
+    latencies.sort_unstable();
+    client.record_message(format!(
+        "trials: {}, socket updates: {}, latencies (ms): {latencies:?}",
+        params.trials,
+        latencies.len()
+    ));
+    let mut write_query = WriteQuery::new(
+        Local::now().into(),
+        format!(
+            "discv5-testground_{}_{}",
+            run_parameters.test_case, run_parameters.test_run
+        ),
+    )
+    .add_field("trials", params.trials)
+    .add_field("socket_updates", latencies.len() as u64)
+    .add_field("socket_update_bound_ms", bound.as_millis() as u64)
+    .add_tag("ping_interval", params.ping_interval)
+    .add_tag("enr_peer_update_min", params.enr_peer_update_min as u64)
+    .add_tag("scope", "run");
+    if !latencies.is_empty() {
+        let percentile = |p: usize| latencies[((latencies.len() - 1) * p) / 100];
+        write_query = write_query
+            .add_field("socket_update_latency_p50_ms", percentile(50))
+            .add_field("socket_update_latency_p95_ms", percentile(95))
+            .add_field("socket_update_latency_max_ms", percentile(100));
+    }
+    client.record_metric(write_query).await?;
+
     client
-        .signal_and_wait(
-            STATE_COMPLETED_ESTABLISH_CONNECTIONS,
-            run_parameters.test_instance_count,
-        )
+        .signal_and_wait(STATE_COMPLETED, run_parameters.test_instance_count)
         .await?;
 
+    tracer.write(&run_parameters.test_outputs_path)?;
+    if errors.is_empty() {
+        client.record_success().await?;
+    } else {
+        for e in errors.iter() {
+            error!("{e}");
+        }
+        client.record_failure(errors.join(", ")).await?;
+    }
+    Ok(())
+}
+
+fn new_discv5(
+    enr: Enr,
+    enr_key: CombinedKey,
+    params: &Params,
+) -> Result<Discv5, Box<dyn std::error::Error>> {
+    let config = discv5::ConfigBuilder::new(ListenConfig::default())
+        .ping_interval(Duration::from_secs(params.ping_interval))
+        .enr_peer_update_min(params.enr_peer_update_min)
+        .build();
+    Ok(Discv5::new(enr, enr_key, config)?)
+}
+
+fn record_peers(client: &Client, discv5: &Discv5) {
     client.record_message(format!(
         "peers: {:?}",
         discv5
@@ -123,26 +289,4 @@ pub(super) async fn run(client: Client) -> Result<(), Box<dyn std::error::Error>
             ))
             .collect::<Vec<_>>()
     ));
-
-    if let Some(receiver) = maybe_receiver {
-        match receiver.await {
-            Ok(socket_addr) => {
-                info!("Discv5Event::SocketUpdated {socket_addr}");
-                client.record_message(format!(
-                    "The socket has been updated {} seconds after startup.",
-                    (Local::now() - started_up_at).num_seconds()
-                ));
-            }
-            Err(e) => {
-                error!("RecvError: {e}");
-            }
-        }
-    }
-
-    client
-        .signal_and_wait(STATE_COMPLETED, run_parameters.test_instance_count)
-        .await?;
-
-    client.record_success().await?;
-    Ok(())
 }
@@ -3,14 +3,27 @@ use std::collections::HashMap;
 
 pub(crate) struct Params {
     pub ping_interval: u64,
+    pub enr_peer_update_min: usize,
+    pub update_bound: u64,
+    pub trials: u64,
 }
 
 impl Params {
     pub(crate) fn new(
         instance_params: &HashMap<String, String>,
     ) -> Result<Params, Box<dyn std::error::Error>> {
+        let ping_interval = get_param::<u64>("ping_interval", instance_params)?;
+        // 0 leaves two `ping_interval`s for the votes, plus some slack.
+        let update_bound = match get_param::<u64>("update_bound", instance_params)? {
+            0 => ping_interval * 2 + 5,
+            update_bound => update_bound,
+        };
+
         Ok(Params {
-            ping_interval: get_param::<u64>("ping_interval", instance_params)?,
+            ping_interval,
+            enr_peer_update_min: get_param::<usize>("enr_peer_update_min", instance_params)?,
+            update_bound,
+            trials: get_param::<u64>("trials", instance_params)?,
         })
     }
 }
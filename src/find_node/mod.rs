@@ -1,8 +1,17 @@
-use crate::utils::publish_and_collect;
+mod quality;
+mod topology;
+
+use crate::capture;
+use crate::find_node::quality::{LookupResult, RunSummary};
+use crate::find_node::topology::Topology;
+use crate::utils::{get_param, publish_and_collect, publish_and_collect_on, run_seed};
 use chrono::Local;
 use discv5::enr::{CombinedKey, NodeId};
-use discv5::{Discv5, Enr, Key, ListenConfig};
+use discv5::{Discv5, Enr, Event, Key, ListenConfig};
 use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
 use testground::client::Client;
 use testground::WriteQuery;
 use tokio::task;
@@ -22,11 +31,13 @@ struct InstanceInfo {
 }
 
 impl InstanceInfo {
-    async fn new(client: &Client, enr: Enr) -> Result<Self, Box<dyn std::error::Error>> {
+    async fn new(
+        client: &Client,
+        enr: Enr,
+        topology: &Topology,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
         let seq = client.global_seq();
-
-        // NOTE: For now, #1 is bootstrap node.
-        let is_bootstrap_node = seq == 1;
+        let is_bootstrap_node = topology.is_bootstrap_node(seq);
 
         Ok(InstanceInfo {
             seq,
@@ -38,6 +49,14 @@ impl InstanceInfo {
 
 pub(super) async fn run(client: Client) -> Result<(), Box<dyn std::error::Error>> {
     let run_parameters = client.run_parameters();
+    let topology: Topology = get_param("topology", &run_parameters.test_instance_params)?;
+    topology.validate(run_parameters.test_instance_count)?;
+    let query_parallelism: usize =
+        get_param("query_parallelism", &run_parameters.test_instance_params)?;
+    client.record_message(format!(
+        "topology: {topology}, query_parallelism: {query_parallelism}"
+    ));
+
     // ////////////////////////
     // Construct a local Enr
     // ////////////////////////
@@ -59,22 +78,29 @@ pub(super) async fn run(client: Client) -> Result<(), Box<dyn std::error::Error>
     let mut discv5: Discv5 = Discv5::new(
         enr,
         enr_key,
-        discv5::ConfigBuilder::new(ListenConfig::default()).build(),
+        discv5::ConfigBuilder::new(ListenConfig::default())
+            .query_parallelism(query_parallelism)
+            .build(),
     )?;
     discv5.start().await.expect("Start Discovery v5 server");
 
-    // Observe Discv5 events.
+    // Observe Discv5 events, counting the sessions established.
+    let sessions_established = Arc::new(AtomicU64::new(0));
     let mut event_stream = discv5.event_stream().await.expect("Discv5Event");
+    let counter = sessions_established.clone();
     task::spawn(async move {
         while let Some(event) = event_stream.recv().await {
             info!("Discv5Event: {:?}", event);
+            if let Event::SessionEstablished(..) = event {
+                counter.fetch_add(1, Ordering::Relaxed);
+            }
         }
     });
 
     // //////////////////////////////////////////////////////////////
     // Collect information of all participants in the test case
     // //////////////////////////////////////////////////////////////
-    let instance_info = InstanceInfo::new(&client, discv5.local_enr()).await?;
+    let instance_info = InstanceInfo::new(&client, discv5.local_enr(), &topology).await?;
     debug!("instance_info: {:?}", instance_info);
 
     let other_instances = collect_other_instance_info(&client, &instance_info).await?;
@@ -88,28 +114,28 @@ pub(super) async fn run(client: Client) -> Result<(), Box<dyn std::error::Error>
         .await?;
 
     // //////////////////////////////////////////////////////////////
-    // Star topology
+    // Construct topology
     // //////////////////////////////////////////////////////////////
-    // NOTE: Assumes only 1 bootstrap node.
     let key: Key<NodeId> = discv5.local_enr().node_id().into();
-    if instance_info.is_bootstrap_node {
-        for i in other_instances.iter() {
-            discv5.add_enr(i.enr.clone())?;
-        }
-    } else {
-        let bootstrap_node = other_instances
-            .iter()
-            .find(|&i| i.is_bootstrap_node)
-            .expect("Bootstrap node");
-
-        // Emit distance to the bootstrap node.
-        let bootstrap_key: Key<NodeId> = bootstrap_node.enr.node_id().into();
+    let peers = topology.peers(
+        instance_info.seq,
+        run_parameters.test_instance_count,
+        run_seed(&run_parameters.test_run),
+    );
+    client.record_message(format!("peers: {peers:?}"));
+    for peer in other_instances.iter().filter(|i| peers.contains(&i.seq)) {
+        // Emit distance to the peer.
+        let peer_key: Key<NodeId> = peer.enr.node_id().into();
         info!(
-            "Distance between `self` and `bootstrap`: {}",
-            key.log2_distance(&bootstrap_key).expect("Distance")
+            "Distance between `self` and `peer` #{}: {}",
+            peer.seq,
+            key.log2_distance(&peer_key).expect("Distance")
         );
 
-        discv5.add_enr(bootstrap_node.enr.clone())?;
+        // The routing table may reject a peer if its bucket is full.
+        if let Err(e) = discv5.add_enr(peer.enr.clone()) {
+            error!("Failed to add the peer #{}: {e}", peer.seq);
+        }
     }
 
     client
@@ -132,11 +158,16 @@ pub(super) async fn run(client: Client) -> Result<(), Box<dyn std::error::Error>
     // Run FINDNODE query
     // //////////////////////////////////////////////////////////////
     let mut failed = false;
+    let mut lookup_results = vec![];
+    let participants = other_instances
+        .iter()
+        .map(|i| i.enr.node_id())
+        .collect::<Vec<_>>();
 
     if instance_info.is_bootstrap_node {
         println!("Skipped to run FINDNODE query because this is the bootstrap node.");
     } else {
-        for target in other_instances {
+        for target in other_instances.iter() {
             if target.is_bootstrap_node {
                 continue;
             }
@@ -149,36 +180,51 @@ pub(super) async fn run(client: Client) -> Result<(), Box<dyn std::error::Error>
                 key.log2_distance(&target_key).expect("Distance")
             );
 
+            // The lookup is run even if the target is already in the routing table, so that
+            // the quality of every lookup is measured.
             if let Some(enr) = discv5.find_enr(&target.enr.node_id()) {
                 info!(
                     "The target is already exists in the routing table. ENR: {:?}",
                     enr
                 );
+            }
+
+            let sessions_before = sessions_established.load(Ordering::Relaxed);
+            let started_at = Instant::now();
+            let enrs = discv5
+                .find_node(target.enr.node_id())
+                .await
+                .expect("FINDNODE query");
+            let latency = started_at.elapsed();
+
+            let mut found_target = true;
+            if enrs.is_empty() {
+                error!("Found no ENRs");
+                found_target = false;
             } else {
-                let enrs = discv5
-                    .find_node(target.enr.node_id())
-                    .await
-                    .expect("FINDNODE query");
-
-                if enrs.is_empty() {
-                    error!("Found no ENRs");
-                    failed = true;
+                info!("Found ENRs: {:?}", enrs);
+
+                // The target node should be found if all the nodes are reachable from each
+                // other through the topology.
+                if enrs.iter().any(|enr| enr.node_id() == target.enr.node_id()) {
+                    info!("Found the target");
                 } else {
-                    info!("Found ENRs: {:?}", enrs);
-
-                    // The target node should be found because the bootstrap node knows all the nodes in our star topology.
-                    if enrs.iter().any(|enr| enr.node_id() == target.enr.node_id()) {
-                        info!("Found the target");
-                    } else {
-                        error!(
-                            "Couldn't find the target. node_id: {}",
-                            target.enr.node_id()
-                        );
-                        failed = true;
-                    }
+                    error!(
+                        "Couldn't find the target. node_id: {}",
+                        target.enr.node_id()
+                    );
+                    found_target = false;
                 }
             }
 
+            let lookup_result = LookupResult {
+                found_target,
+                recall_at_k: quality::recall_at_k(target.enr.node_id(), &enrs, &participants),
+                latency_ms: latency.as_millis() as u64,
+                new_sessions: sessions_established.load(Ordering::Relaxed) - sessions_before,
+            };
+            info!("Lookup result: {lookup_result:?}");
+
             // //////////////////////////////////////////////////////////////
             // Record metrics
             // //////////////////////////////////////////////////////////////
@@ -197,8 +243,45 @@ pub(super) async fn run(client: Client) -> Result<(), Box<dyn std::error::Error>
             )
             .add_field("bytes_sent", metrics.bytes_sent as u64)
             .add_field("bytes_recv", metrics.bytes_recv as u64)
-            .add_tag("instance_seq", instance_info.seq);
+            .add_field("found_target", found_target)
+            .add_field("recall_at_k", lookup_result.recall_at_k)
+            .add_field("latency_ms", lookup_result.latency_ms)
+            .add_field("new_sessions", lookup_result.new_sessions)
+            .add_tag("instance_seq", instance_info.seq)
+            .add_tag("topology", topology.to_string())
+            .add_tag("query_parallelism", query_parallelism as u64)
+            .add_tag("scope", "lookup");
             client.record_metric(write_query).await?;
+
+            // In a topology where some nodes may not be reachable, a lookup which hasn't found
+            // its target is recorded in the metrics only.
+            failed |= !found_target && topology.is_connected();
+            lookup_results.push(lookup_result);
+        }
+    }
+
+    // //////////////////////////////////////////////////////////////
+    // Aggregate the lookup results of the run
+    // //////////////////////////////////////////////////////////////
+    let all_results = publish_and_collect_on(&client, "lookup_results", lookup_results)
+        .await?
+        .concat();
+    if instance_info.seq == 1 {
+        if let Some(summary) = RunSummary::new(&all_results) {
+            client.record_message(format!("Lookups in the run: {summary:?}"));
+            let write_query = WriteQuery::new(
+                Local::now().into(),
+                format!(
+                    "discv5-testground_{}_{}",
+                    run_parameters.test_case, run_parameters.test_run
+                ),
+            )
+            .add_tag("topology", topology.to_string())
+            .add_tag("query_parallelism", query_parallelism as u64)
+            .add_tag("scope", "run");
+            client
+                .record_metric(summary.add_fields(write_query))
+                .await?;
         }
     }
 
@@ -228,6 +311,7 @@ async fn collect_other_instance_info(
     instance_info: &InstanceInfo,
 ) -> Result<Vec<InstanceInfo>, Box<dyn std::error::Error>> {
     let mut info = publish_and_collect(client, instance_info.clone()).await?;
+    capture::register_enrs(info.iter().map(|i| &i.enr));
 
     if let Some(pos) = info.iter().position(|i| i.seq == instance_info.seq) {
         info.remove(pos);
@@ -0,0 +1,100 @@
+use discv5::enr::NodeId;
+use discv5::{Enr, Key};
+use serde::{Deserialize, Serialize};
+use testground::WriteQuery;
+
+/// The number of the closest nodes a lookup is compared with the oracle on, which is the number
+/// of results a lookup returns by default.
+pub(super) const K: usize = 16;
+
+/// The quality of a lookup, judged against the true closest nodes to the target.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(super) struct LookupResult {
+    pub(super) found_target: bool,
+    /// The ratio of the true k-closest nodes found by the lookup.
+    pub(super) recall_at_k: f64,
+    pub(super) latency_ms: u64,
+    /// The number of sessions established during the lookup. discv5 doesn't expose the peers
+    /// contacted by a query, so this is a lower bound of them: the peers which already had a
+    /// session with the node are not counted.
+    pub(super) new_sessions: u64,
+}
+
+/// Computes the recall@k of the lookup result. The oracle is the k closest to the target among
+/// `participants`, which are all the nodes but the one running the lookup, since a lookup never
+/// returns the local node.
+pub(super) fn recall_at_k(target: NodeId, found: &[Enr], participants: &[NodeId]) -> f64 {
+    let oracle = closest(target, participants.iter().copied());
+    if oracle.is_empty() {
+        return 1.;
+    }
+    let found = closest(target, found.iter().map(Enr::node_id));
+    let hits = found
+        .iter()
+        .filter(|node_id| oracle.contains(node_id))
+        .count();
+    hits as f64 / oracle.len() as f64
+}
+
+fn closest(target: NodeId, node_ids: impl Iterator<Item = NodeId>) -> Vec<NodeId> {
+    let target: Key<NodeId> = target.into();
+    let mut node_ids = node_ids.collect::<Vec<_>>();
+    node_ids.sort_by_key(|node_id| target.distance(&Key::from(*node_id)));
+    node_ids.dedup();
+    node_ids.truncate(K);
+    node_ids
+}
+
+/// The lookup results of all the instances in a run, aggregated.
+#[derive(Debug)]
+pub(super) struct RunSummary {
+    lookups: usize,
+    targets_not_found: usize,
+    recall_mean: f64,
+    recall_min: f64,
+    latency_p50_ms: u64,
+    latency_p95_ms: u64,
+    latency_max_ms: u64,
+    new_sessions_mean: f64,
+}
+
+impl RunSummary {
+    /// Returns `None` if no lookup has been run.
+    pub(super) fn new(results: &[LookupResult]) -> Option<Self> {
+        if results.is_empty() {
+            return None;
+        }
+        let lookups = results.len();
+
+        let mut latencies = results.iter().map(|r| r.latency_ms).collect::<Vec<_>>();
+        latencies.sort_unstable();
+        let percentile = |p: usize| latencies[((lookups - 1) * p) / 100];
+
+        Some(RunSummary {
+            lookups,
+            targets_not_found: results.iter().filter(|r| !r.found_target).count(),
+            recall_mean: results.iter().map(|r| r.recall_at_k).sum::<f64>() / lookups as f64,
+            recall_min: results
+                .iter()
+                .map(|r| r.recall_at_k)
+                .fold(f64::INFINITY, f64::min),
+            latency_p50_ms: percentile(50),
+            latency_p95_ms: percentile(95),
+            latency_max_ms: latencies[lookups - 1],
+            new_sessions_mean: results.iter().map(|r| r.new_sessions as f64).sum::<f64>()
+                / lookups as f64,
+        })
+    }
+
+    pub(super) fn add_fields(&self, write_query: WriteQuery) -> WriteQuery {
+        write_query
+            .add_field("lookups", self.lookups as u64)
+            .add_field("targets_not_found", self.targets_not_found as u64)
+            .add_field("recall_mean", self.recall_mean)
+            .add_field("recall_min", self.recall_min)
+            .add_field("latency_p50_ms", self.latency_p50_ms)
+            .add_field("latency_p95_ms", self.latency_p95_ms)
+            .add_field("latency_max_ms", self.latency_max_ms)
+            .add_field("new_sessions_mean", self.new_sessions_mean)
+    }
+}
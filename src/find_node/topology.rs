@@ -0,0 +1,239 @@
+use rand::seq::SliceRandom;
+use rand::{Rng, SeedableRng};
+use rand_xorshift::XorShiftRng;
+use std::collections::HashSet;
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+
+// The number of edge switches per edge to randomize a regular graph.
+const SWITCHES_PER_EDGE: usize = 10;
+
+/// The topology the participants are connected in before running FINDNODE queries, given by the
+/// `topology` test parameter. Each node only adds the ENRs of its peers in the topology into its
+/// routing table.
+///
+/// The instances are identified by their sequence numbers, from 1 to the number of instances.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(super) enum Topology {
+    /// `star:K`. The first `K` nodes are bootstrap nodes, which know all the nodes. The others
+    /// know the bootstrap nodes only.
+    Star { bootstraps: u64 },
+    /// `ring`. Each node knows its two neighbours, and the last node is next to the first one.
+    Ring,
+    /// `line`. Same as the ring, but the first and the last nodes are not connected.
+    Line,
+    /// `random-regular:K`. The nodes are connected in a random connected graph where each node
+    /// has `K` peers, which know each other.
+    RandomRegular { degree: u64 },
+    /// `random-subset:K`. Each node knows `K` peers picked at random, which may not know the node.
+    /// Some nodes may not be reachable from others.
+    RandomSubset { peers: u64 },
+}
+
+impl FromStr for Topology {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (mode, k) = match s.split_once(':') {
+            Some((mode, k)) => (
+                mode,
+                Some(
+                    k.parse::<u64>()
+                        .map_err(|e| format!("Invalid topology: {s}, {e}"))?,
+                ),
+            ),
+            None => (s, None),
+        };
+
+        match (mode, k) {
+            ("star", Some(bootstraps)) if bootstraps > 0 => Ok(Topology::Star { bootstraps }),
+            ("ring", None) => Ok(Topology::Ring),
+            ("line", None) => Ok(Topology::Line),
+            ("random-regular", Some(degree)) if degree > 0 => {
+                Ok(Topology::RandomRegular { degree })
+            }
+            ("random-subset", Some(peers)) if peers > 0 => Ok(Topology::RandomSubset { peers }),
+            _ => Err(format!(
+                "Invalid topology: {s}. Expected one of `star:K`, `ring`, `line`, `random-regular:K` or `random-subset:K`."
+            )),
+        }
+    }
+}
+
+impl Display for Topology {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Topology::Star { bootstraps } => write!(f, "star:{bootstraps}"),
+            Topology::Ring => write!(f, "ring"),
+            Topology::Line => write!(f, "line"),
+            Topology::RandomRegular { degree } => write!(f, "random-regular:{degree}"),
+            Topology::RandomSubset { peers } => write!(f, "random-subset:{peers}"),
+        }
+    }
+}
+
+impl Topology {
+    /// Returns an error if the topology can't be built with the number of instances.
+    pub(super) fn validate(&self, instance_count: u64) -> Result<(), String> {
+        match *self {
+            Topology::Star { bootstraps } if bootstraps >= instance_count => Err(format!(
+                "The number of bootstrap nodes ({bootstraps}) must be less than the number of instances ({instance_count})."
+            )),
+            Topology::RandomRegular { degree }
+                if degree >= instance_count || (degree * instance_count) % 2 == 1 =>
+            {
+                Err(format!(
+                    "A random {degree}-regular graph of {instance_count} nodes doesn't exist."
+                ))
+            }
+            Topology::RandomRegular { degree: 1 } if instance_count > 2 => Err(format!(
+                "A 1-regular graph of {instance_count} nodes isn't connected."
+            )),
+            Topology::RandomSubset { peers } if peers >= instance_count => Err(format!(
+                "The number of peers ({peers}) must be less than the number of instances ({instance_count})."
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// Whether every node is reachable from the others through the topology, so that every
+    /// lookup should find its target.
+    pub(super) fn is_connected(&self) -> bool {
+        !matches!(self, Topology::RandomSubset { .. })
+    }
+
+    pub(super) fn is_bootstrap_node(&self, seq: u64) -> bool {
+        match *self {
+            Topology::Star { bootstraps } => seq <= bootstraps,
+            _ => false,
+        }
+    }
+
+    /// The sequence numbers of the peers the node knows initially.
+    ///
+    /// The random graphs are generated from `seed`, which has to be the same on all the instances
+    /// so that they agree on the graph.
+    pub(super) fn peers(&self, seq: u64, instance_count: u64, seed: u64) -> Vec<u64> {
+        let others = (1..=instance_count).filter(|&s| s != seq);
+        let peers = match *self {
+            Topology::Star { .. } => {
+                if self.is_bootstrap_node(seq) {
+                    others.collect()
+                } else {
+                    others.filter(|&s| self.is_bootstrap_node(s)).collect()
+                }
+            }
+            Topology::Ring => {
+                let previous = if seq == 1 { instance_count } else { seq - 1 };
+                let next = if seq == instance_count { 1 } else { seq + 1 };
+                vec![previous, next]
+            }
+            Topology::Line => [seq - 1, seq + 1]
+                .into_iter()
+                .filter(|&s| s >= 1 && s <= instance_count)
+                .collect(),
+            Topology::RandomRegular { degree } => {
+                random_regular_graph(instance_count, degree, seed)
+                    .into_iter()
+                    .filter_map(|(a, b)| match seq {
+                        s if s == a => Some(b),
+                        s if s == b => Some(a),
+                        _ => None,
+                    })
+                    .collect()
+            }
+            Topology::RandomSubset { peers } => {
+                let mut rng = XorShiftRng::seed_from_u64(seed.wrapping_add(seq));
+                let mut others = others.collect::<Vec<_>>();
+                others.shuffle(&mut rng);
+                others.truncate(peers as usize);
+                others
+            }
+        };
+
+        // A ring of two nodes has the same node on both sides.
+        let mut seen = HashSet::new();
+        peers.into_iter().filter(|&s| seen.insert(s)).collect()
+    }
+}
+
+/// Generates the edges of a random connected `degree`-regular graph by edge switching: starting
+/// from a circulant graph, which is regular and connected, pairs of edges are switched at random
+/// as long as the graph stays simple and connected.
+fn random_regular_graph(instance_count: u64, degree: u64, seed: u64) -> Vec<(u64, u64)> {
+    let mut rng = XorShiftRng::seed_from_u64(seed);
+    let n = instance_count;
+
+    // Each node is connected to the `degree / 2` nodes on either side, and to the opposite one if
+    // the degree is odd, in which case the number of nodes is even.
+    let mut edges = vec![];
+    for seq in 1..=n {
+        for k in 1..=degree / 2 {
+            edges.push(edge(seq, (seq + k - 1) % n + 1));
+        }
+        if degree % 2 == 1 && seq <= n / 2 {
+            edges.push(edge(seq, seq + n / 2));
+        }
+    }
+    let mut edge_set = edges.iter().copied().collect::<HashSet<_>>();
+
+    for _ in 0..edges.len() * SWITCHES_PER_EDGE {
+        let (i, j) = (rng.gen_range(0..edges.len()), rng.gen_range(0..edges.len()));
+        let ((a, b), (c, d)) = (edges[i], edges[j]);
+        // Either of the two ways to rewire the endpoints.
+        let (e1, e2) = if rng.gen_bool(0.5) {
+            ((a, d), (c, b))
+        } else {
+            ((a, c), (b, d))
+        };
+        if e1.0 == e1.1 || e2.0 == e2.1 {
+            continue;
+        }
+        let (e1, e2) = (edge(e1.0, e1.1), edge(e2.0, e2.1));
+        if e1 == e2 || edge_set.contains(&e1) || edge_set.contains(&e2) {
+            continue;
+        }
+
+        edges[i] = e1;
+        edges[j] = e2;
+        if is_connected(n, &edges) {
+            edge_set.remove(&(a, b));
+            edge_set.remove(&(c, d));
+            edge_set.insert(e1);
+            edge_set.insert(e2);
+        } else {
+            edges[i] = (a, b);
+            edges[j] = (c, d);
+        }
+    }
+
+    edges
+}
+
+fn edge(a: u64, b: u64) -> (u64, u64) {
+    (a.min(b), a.max(b))
+}
+
+/// Whether all the nodes, from 1 to `n`, are reachable from each other through the edges.
+fn is_connected(n: u64, edges: &[(u64, u64)]) -> bool {
+    let mut adjacency = vec![vec![]; n as usize + 1];
+    for &(a, b) in edges {
+        adjacency[a as usize].push(b as usize);
+        adjacency[b as usize].push(a as usize);
+    }
+
+    let mut visited = vec![false; n as usize + 1];
+    visited[1] = true;
+    let mut stack = vec![1];
+    let mut reached = 1;
+    while let Some(seq) = stack.pop() {
+        for &peer in adjacency[seq].iter() {
+            if !visited[peer] {
+                visited[peer] = true;
+                reached += 1;
+                stack.push(peer);
+            }
+        }
+    }
+    reached == n
+}
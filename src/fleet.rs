@@ -0,0 +1,91 @@
+use crate::mock::{Behaviours, Mock};
+use discv5::enr::CombinedKey;
+use discv5::{Discv5, Enr, ListenConfig};
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::Arc;
+
+/// An identity hosted in the fleet.
+pub(crate) struct Member<N> {
+    pub(crate) enr: Enr,
+    pub(crate) node: N,
+}
+
+/// Hosts many discv5 or mock identities in a single test instance, each on its own UDP port with
+/// its own keys and sessions. This makes attacks with hundreds of Sybil ids practical without
+/// running a container per identity.
+pub(crate) struct Fleet<N> {
+    pub(crate) members: Vec<Member<N>>,
+}
+
+impl<N> Fleet<N> {
+    pub(crate) fn enrs(&self) -> Vec<Enr> {
+        self.members.iter().map(|m| m.enr.clone()).collect()
+    }
+
+    /// Builds an ENR and a listen config for each key, assigning the ports in order from
+    /// `base_port`.
+    fn identities(
+        ip: IpAddr,
+        base_port: u16,
+        keys: Vec<CombinedKey>,
+    ) -> Vec<(Enr, CombinedKey, ListenConfig)> {
+        keys.into_iter()
+            .enumerate()
+            .map(|(i, key)| {
+                let port = base_port + u16::try_from(i).expect("Valid as u16");
+                let enr = Enr::builder()
+                    .ip(ip)
+                    .udp4(port)
+                    .build(&key)
+                    .expect("Construct an Enr");
+                let listen_config = ListenConfig::Ipv4 {
+                    ip: Ipv4Addr::UNSPECIFIED,
+                    port,
+                };
+                (enr, key, listen_config)
+            })
+            .collect()
+    }
+}
+
+impl Fleet<Arc<Discv5>> {
+    /// Starts a discv5 node for each key. `config` builds the config of each node from its listen
+    /// config. The nodes are shared so that they can run requests concurrently in tasks.
+    pub(crate) async fn start_discv5(
+        ip: IpAddr,
+        base_port: u16,
+        keys: Vec<CombinedKey>,
+        config: impl Fn(ListenConfig) -> discv5::Config,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut members = vec![];
+        for (enr, key, listen_config) in Self::identities(ip, base_port, keys) {
+            let mut discv5: Discv5 = Discv5::new(enr.clone(), key, config(listen_config))?;
+            discv5.start().await.expect("Start Discovery v5 server");
+            members.push(Member {
+                enr,
+                node: Arc::new(discv5),
+            });
+        }
+
+        Ok(Fleet { members })
+    }
+}
+
+impl Fleet<Mock> {
+    /// Starts a mock for each key. `behaviours` builds the behaviours of each mock.
+    pub(crate) async fn start_mock(
+        ip: IpAddr,
+        base_port: u16,
+        keys: Vec<CombinedKey>,
+        config: impl Fn(ListenConfig) -> discv5::Config,
+        behaviours: impl Fn() -> Behaviours,
+    ) -> Self {
+        let mut members = vec![];
+        for (enr, key, listen_config) in Self::identities(ip, base_port, keys) {
+            let mock = Mock::start(enr.clone(), key, config(listen_config), behaviours()).await;
+            members.push(Member { enr, node: mock });
+        }
+
+        Fleet { members }
+    }
+}
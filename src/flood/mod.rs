@@ -0,0 +1,382 @@
+use crate::capture;
+use crate::mock::{Action, Behaviours, DeclarativeBehaviour, Mock};
+use crate::utils::{cpu_time, get_param, publish_and_collect};
+use chrono::Local;
+use discv5::enr::{CombinedKey, NodeId};
+use discv5::{Discv5, Enr, ListenConfig};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::Ipv4Addr;
+use std::time::{Duration, Instant};
+use testground::client::Client;
+use testground::WriteQuery;
+use tracing::{error, info, warn};
+
+const STATE_COMPLETED_TO_COLLECT_INSTANCE_INFORMATION: &str =
+    "STATE_COMPLETED_TO_COLLECT_INSTANCE_INFORMATION";
+const STATE_FLOOD_STARTED: &str = "STATE_FLOOD_STARTED";
+const STATE_FLOOD_FINISHED: &str = "STATE_FLOOD_FINISHED";
+const STATE_DONE: &str = "STATE_DONE";
+
+// The flooders send the packets in batches at this interval.
+const BATCH_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+enum Role {
+    Victim,
+    Honest,
+    Flooder,
+}
+
+impl From<&str> for Role {
+    fn from(test_group_id: &str) -> Self {
+        match test_group_id {
+            "victim" => Role::Victim,
+            "honest" => Role::Honest,
+            "flooders" => Role::Flooder,
+            _ => unreachable!(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct InstanceInfo {
+    enr: Enr,
+    role: Role,
+}
+
+struct Params {
+    // The duration of the flood in seconds.
+    duration: u64,
+    // The number of random packets each flooder sends per second.
+    packets_per_second: u64,
+    // The number of node ids each flooder sends the random packets from.
+    num_node_ids: usize,
+    // Whether the victim enables the packet filter, which rate-limits unsolicited packets.
+    enable_packet_filter: bool,
+}
+
+impl Params {
+    fn new(instance_params: &HashMap<String, String>) -> Result<Self, String> {
+        Ok(Params {
+            duration: get_param("duration", instance_params)?,
+            packets_per_second: get_param("packets_per_second", instance_params)?,
+            num_node_ids: get_param("num_node_ids", instance_params)?,
+            enable_packet_filter: get_param("enable_packet_filter", instance_params)?,
+        })
+    }
+}
+
+pub(crate) async fn run(client: Client) -> Result<(), Box<dyn std::error::Error>> {
+    let run_parameters = client.run_parameters();
+    let params = Params::new(&run_parameters.test_instance_params)?;
+    if params.packets_per_second == 0 {
+        return Err("`packets_per_second` must be at least 1.".into());
+    }
+    let role: Role = run_parameters.test_group_id.as_str().into();
+    client.record_message(format!(
+        "role: {:?}, group_seq: {}",
+        role,
+        client.group_seq()
+    ));
+
+    // ////////////////////////
+    // Construct a local Enr
+    // ////////////////////////
+    let enr_key = CombinedKey::generate_secp256k1();
+    let enr = Enr::builder()
+        .ip(run_parameters
+            .data_network_ip()?
+            .expect("IP address for the data network"))
+        .udp4(9000)
+        .build(&enr_key)
+        .expect("Construct an Enr");
+
+    // //////////////////////////////////////////////////////////////
+    // Collect information of all participants in the test case
+    // //////////////////////////////////////////////////////////////
+    let instance_info = InstanceInfo {
+        enr: enr.clone(),
+        role: role.clone(),
+    };
+    let participants = publish_and_collect(&client, instance_info).await?;
+    capture::register_enrs(participants.iter().map(|p| &p.enr));
+    let victim = participants
+        .iter()
+        .find(|p| p.role == Role::Victim)
+        .expect("victim")
+        .clone();
+    let honest = participants
+        .into_iter()
+        .filter(|p| p.role == Role::Honest)
+        .collect::<Vec<_>>();
+
+    client
+        .signal_and_wait(
+            STATE_COMPLETED_TO_COLLECT_INSTANCE_INFORMATION,
+            run_parameters.test_instance_count,
+        )
+        .await?;
+
+    // ////////////////////////
+    // Discv5 config
+    // ////////////////////////
+    let listen_config = ListenConfig::Ipv4 {
+        ip: Ipv4Addr::UNSPECIFIED,
+        port: 9000,
+    };
+    let mut config_builder = discv5::ConfigBuilder::new(listen_config);
+    if role == Role::Victim && params.enable_packet_filter {
+        config_builder.enable_packet_filter();
+    }
+    let config = config_builder.build();
+
+    // //////////////////////////////////////////////////////////////
+    // Play the role
+    // //////////////////////////////////////////////////////////////
+    match role {
+        Role::Victim => play_victim(client, enr, enr_key, config, &params, &honest).await?,
+        Role::Honest => play_honest(client, enr, enr_key, config, &victim, &honest).await?,
+        Role::Flooder => play_flooder(client, enr, enr_key, config, &params, &victim).await?,
+    }
+
+    Ok(())
+}
+
+async fn play_victim(
+    client: Client,
+    enr: Enr,
+    enr_key: CombinedKey,
+    config: discv5::Config,
+    params: &Params,
+    honest: &[InstanceInfo],
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut discv5: Discv5 = Discv5::new(enr, enr_key, config)?;
+    // The honest peers find each other through the victim.
+    for peer in honest {
+        discv5.add_enr(peer.enr.clone())?;
+    }
+    discv5.start().await.expect("Start Discovery v5 server");
+
+    client
+        .signal_and_wait(
+            STATE_FLOOD_STARTED,
+            client.run_parameters().test_instance_count,
+        )
+        .await?;
+    let cpu_time_start = cpu_time();
+    let started_at = Instant::now();
+
+    client
+        .signal_and_wait(
+            STATE_FLOOD_FINISHED,
+            client.run_parameters().test_instance_count,
+        )
+        .await?;
+
+    // //////////////////////////////////////////////////////////////
+    // Record metrics
+    // //////////////////////////////////////////////////////////////
+    let elapsed = started_at.elapsed();
+    let metrics = discv5.metrics();
+    let run_parameters = client.run_parameters();
+    let mut write_query = WriteQuery::new(
+        Local::now().into(),
+        format!(
+            "discv5-testground_{}_{}",
+            run_parameters.test_case, run_parameters.test_run
+        ),
+    )
+    .add_field("active_sessions", metrics.active_sessions as u64)
+    .add_field(
+        "unsolicited_requests_per_second",
+        metrics.unsolicited_requests_per_second,
+    )
+    .add_field("bytes_sent", metrics.bytes_sent as u64)
+    .add_field("bytes_recv", metrics.bytes_recv as u64)
+    .add_field("enable_packet_filter", params.enable_packet_filter)
+    .add_tag("role", "victim");
+    match (cpu_time_start, cpu_time()) {
+        (Some(start), Some(end)) => {
+            // The share of a single core the victim has used during the flood.
+            let cpu_usage = (end - start).as_secs_f64() / elapsed.as_secs_f64();
+            info!("CPU usage during the flood: {cpu_usage}");
+            write_query = write_query.add_field("cpu_usage", cpu_usage);
+        }
+        _ => warn!("Failed to read the CPU time of the process."),
+    }
+    client.record_metric(write_query).await?;
+
+    client
+        .signal_and_wait(STATE_DONE, client.run_parameters().test_instance_count)
+        .await?;
+
+    client.record_success().await?;
+    Ok(())
+}
+
+async fn play_honest(
+    client: Client,
+    enr: Enr,
+    enr_key: CombinedKey,
+    config: discv5::Config,
+    victim: &InstanceInfo,
+    honest: &[InstanceInfo],
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut discv5: Discv5 = Discv5::new(enr, enr_key, config)?;
+    discv5.add_enr(victim.enr.clone())?;
+    discv5.start().await.expect("Start Discovery v5 server");
+
+    client
+        .signal_and_wait(
+            STATE_FLOOD_STARTED,
+            client.run_parameters().test_instance_count,
+        )
+        .await?;
+
+    // Let the flood hit the victim before running the query.
+    tokio::time::sleep(Duration::from_secs(1)).await;
+
+    // //////////////////////////////////////////////////////////////
+    // Run FINDNODE query during the flood
+    // //////////////////////////////////////////////////////////////
+    let started_at = Instant::now();
+    let result = discv5.find_node(NodeId::random()).await;
+    let latency = started_at.elapsed();
+
+    let local_node_id = discv5.local_enr().node_id();
+    let completed = match &result {
+        Ok(enrs) => honest
+            .iter()
+            .filter(|peer| peer.enr.node_id() != local_node_id)
+            .all(|peer| enrs.iter().any(|enr| enr.node_id() == peer.enr.node_id())),
+        Err(e) => {
+            error!("Failed to run query: {e}");
+            false
+        }
+    };
+    info!("FINDNODE query completed: {completed}, latency: {latency:?}");
+
+    client
+        .signal_and_wait(
+            STATE_FLOOD_FINISHED,
+            client.run_parameters().test_instance_count,
+        )
+        .await?;
+
+    // //////////////////////////////////////////////////////////////
+    // Record metrics
+    // //////////////////////////////////////////////////////////////
+    let run_parameters = client.run_parameters();
+    let write_query = WriteQuery::new(
+        Local::now().into(),
+        format!(
+            "discv5-testground_{}_{}",
+            run_parameters.test_case, run_parameters.test_run
+        ),
+    )
+    .add_field("find_node_completed", completed)
+    .add_field("find_node_latency_ms", latency.as_millis() as u64)
+    .add_tag("role", "honest");
+    client.record_metric(write_query).await?;
+
+    client
+        .signal_and_wait(STATE_DONE, client.run_parameters().test_instance_count)
+        .await?;
+
+    if completed {
+        client.record_success().await?;
+    } else {
+        client
+            .record_failure(
+                "The FINDNODE query failed to find the other honest peers during the flood.",
+            )
+            .await?;
+    }
+    Ok(())
+}
+
+async fn play_flooder(
+    client: Client,
+    enr: Enr,
+    enr_key: CombinedKey,
+    config: discv5::Config,
+    params: &Params,
+    victim: &InstanceInfo,
+) -> Result<(), Box<dyn std::error::Error>> {
+    // The flooder never completes a handshake, so WHOAREYOUs from the victim are just counted.
+    let behaviours = Behaviours::Declarative(DeclarativeBehaviour {
+        whoareyou: vec![Action::Ignore("Flooding".to_string())],
+        handshake: vec![Action::Ignore("Flooding".to_string())],
+        message: vec![Action::Ignore("Flooding".to_string())],
+        message_without_session: vec![Action::Ignore("Flooding".to_string())],
+    });
+    let mut mock = Mock::start(enr, enr_key, config, behaviours).await;
+    let node_ids = mock.alias_node_ids(params.num_node_ids);
+
+    client
+        .signal_and_wait(
+            STATE_FLOOD_STARTED,
+            client.run_parameters().test_instance_count,
+        )
+        .await?;
+
+    // //////////////////////////////////////////////////////////////
+    // Flood the victim
+    // //////////////////////////////////////////////////////////////
+    let batches = params.duration * 1000 / BATCH_INTERVAL.as_millis() as u64;
+    let mut interval = tokio::time::interval(BATCH_INTERVAL);
+    let mut node_id_iter = node_ids.iter().cycle();
+    let mut packets_sent = 0;
+    for batch in 1..=batches {
+        interval.tick().await;
+        // The remainder of the rate that doesn't fill a packet is carried over to the next batch.
+        let packets = params.packets_per_second * batch * BATCH_INTERVAL.as_millis() as u64 / 1000;
+        let batch_size = (packets - packets_sent) as usize;
+        packets_sent = packets;
+        let batch = node_id_iter.by_ref().take(batch_size).cloned().collect();
+        mock.send_random_packets(victim.enr.clone(), batch)?;
+    }
+    // Wait for the WHOAREYOUs in flight.
+    tokio::time::sleep(Duration::from_secs(1)).await;
+    let stats = mock.stats().await?;
+    info!("Flood finished. {stats:?}");
+
+    client
+        .signal_and_wait(
+            STATE_FLOOD_FINISHED,
+            client.run_parameters().test_instance_count,
+        )
+        .await?;
+
+    // //////////////////////////////////////////////////////////////
+    // Record metrics
+    // //////////////////////////////////////////////////////////////
+    let run_parameters = client.run_parameters();
+    let write_query = WriteQuery::new(
+        Local::now().into(),
+        format!(
+            "discv5-testground_{}_{}",
+            run_parameters.test_case, run_parameters.test_run
+        ),
+    )
+    .add_field("random_packets_sent", stats.random_packets_sent)
+    .add_field("whoareyou_received", stats.whoareyou_received)
+    .add_field(
+        "whoareyou_per_second",
+        stats.whoareyou_received as f64 / params.duration as f64,
+    )
+    .add_field(
+        "whoareyou_response_rate",
+        stats.whoareyou_received as f64 / stats.random_packets_sent.max(1) as f64,
+    )
+    .add_tag("role", "flooder");
+    client.record_metric(write_query).await?;
+
+    client
+        .signal_and_wait(STATE_DONE, client.run_parameters().test_instance_count)
+        .await?;
+
+    client.record_success().await?;
+    Ok(())
+}
@@ -0,0 +1,365 @@
+use crate::capture;
+use crate::mock::{
+    Action, Behaviour, Behaviours, ChallengeEnrSeq, Expect, HandshakeEnr, Mock, Request, Response,
+    Step, VerificationKind,
+};
+use crate::utils::publish_and_collect;
+use discv5::enr::CombinedKey;
+use discv5::rpc::{RequestBody, ResponseBody};
+use discv5::{Discv5, Enr, Event, ListenConfig};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashSet, VecDeque};
+use std::net::Ipv4Addr;
+use std::time::Duration;
+use testground::client::Client;
+use tokio::sync::mpsc;
+use tracing::{error, info};
+
+const STATE_DISCV5_STARTED: &str = "state_discv5_started";
+const STATE_HANDSHAKES_SENT: &str = "state_handshakes_sent";
+const STATE_FINISHED: &str = "state_finished";
+
+// The sequence number of the ENRs of both instances. It is above 1 so that a stale `enr_seq`, which
+// is lower by one, is not zero. The stale ENR of the mock has a lower one.
+const ENR_SEQ: u64 = 2;
+
+const TALK_PROTOCOL: &[u8] = b"PROTOCOL";
+
+// The `enr_seq`s the mock sends in WHOAREYOU, in order.
+const CHALLENGE_ENR_SEQS: [ChallengeEnrSeq; 4] = [
+    ChallengeEnrSeq::Zero,
+    ChallengeEnrSeq::Known,
+    ChallengeEnrSeq::Stale,
+    ChallengeEnrSeq::Future,
+];
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct InstanceInfo {
+    // The sequence number of this test instance within the test.
+    seq: u64,
+    enr: Enr,
+}
+
+/// The handshakes the mock initiates, in order. Each one depends on what the discv5 node has
+/// learned from the previous ones.
+#[derive(Clone, Copy)]
+enum HandshakeCase {
+    Missing,
+    Valid,
+    Stale,
+    SocketMismatch,
+}
+
+const HANDSHAKE_CASES: [HandshakeCase; 4] = [
+    HandshakeCase::Missing,
+    HandshakeCase::Valid,
+    HandshakeCase::Stale,
+    HandshakeCase::SocketMismatch,
+];
+
+pub(crate) async fn run(client: Client) -> Result<(), Box<dyn std::error::Error>> {
+    let run_parameters = client.run_parameters();
+    let ip = run_parameters
+        .data_network_ip()?
+        .expect("IP address for the data network");
+
+    // ////////////////////////
+    // Construct local Enr
+    // ////////////////////////
+    let enr_key = CombinedKey::generate_secp256k1();
+    let mut enr_builder = Enr::builder();
+    enr_builder.ip(ip).udp4(9000).seq(ENR_SEQ);
+    let enr = enr_builder.build(&enr_key).expect("enr");
+
+    // //////////////////////////////////////////////////////////////
+    // Collect information of all participants in the test case
+    // //////////////////////////////////////////////////////////////
+    let instance_info = InstanceInfo {
+        seq: client.global_seq(),
+        enr: enr.clone(),
+    };
+    client.record_message(format!(
+        "seq: {}, node_id: {}, ip: {}",
+        instance_info.seq,
+        instance_info.enr.node_id(),
+        ip
+    ));
+
+    let participants = publish_and_collect(&client, instance_info).await?;
+    capture::register_enrs(participants.iter().map(|p| &p.enr));
+    let another_instance_info = participants
+        .into_iter()
+        .find(|p| p.seq != client.global_seq())
+        .expect("Another instance");
+
+    // ////////////////////////
+    // Discv5 config
+    // ////////////////////////
+    let listen_config = ListenConfig::Ipv4 {
+        ip: Ipv4Addr::UNSPECIFIED,
+        port: 9000,
+    };
+    let config = discv5::ConfigBuilder::new(listen_config)
+        .request_timeout(Duration::from_secs(3))
+        .build();
+
+    match client.global_seq() {
+        1 => run_discv5(client, enr, enr_key, config, another_instance_info).await?,
+        2 => run_mock(client, enr, enr_key, config, another_instance_info).await?,
+        _ => unreachable!(),
+    }
+
+    Ok(())
+}
+
+async fn run_discv5(
+    client: Client,
+    enr: Enr,
+    enr_key: CombinedKey,
+    config: discv5::Config,
+    mock: InstanceInfo,
+) -> Result<(), Box<dyn std::error::Error>> {
+    // ////////////////////////
+    // Start discv5
+    // ////////////////////////
+    let mut discv5: Discv5 = Discv5::new(enr, enr_key, config)?;
+    discv5.start().await.expect("Start Discovery v5 server");
+
+    // Record the TALKREQs delivered, which tells the handshakes accepted.
+    let (talk_send, mut talk_recv) = mpsc::unbounded_channel();
+    let mut event_stream = discv5.event_stream().await.expect("Discv5Event");
+    tokio::spawn(async move {
+        while let Some(event) = event_stream.recv().await {
+            if let Event::TalkRequest(talk_request) = event {
+                info!("TalkRequest: {:?}", talk_request);
+                let _ = talk_send.send(talk_request.body().to_vec());
+                let response = talk_request.body().to_vec();
+                if let Err(e) = talk_request.respond(response) {
+                    error!("Failed to send response: {e:?}");
+                }
+            }
+        }
+    });
+
+    client
+        .signal_and_wait(
+            STATE_DISCV5_STARTED,
+            client.run_parameters().test_instance_count,
+        )
+        .await?;
+
+    // Wait until the mock has sent the handshakes.
+    client
+        .signal_and_wait(
+            STATE_HANDSHAKES_SENT,
+            client.run_parameters().test_instance_count,
+        )
+        .await?;
+
+    let mut errors = vec![];
+    let mut delivered = HashSet::new();
+    while let Ok(body) = talk_recv.try_recv() {
+        delivered.insert(String::from_utf8_lossy(&body).to_string());
+    }
+    for case in HANDSHAKE_CASES {
+        if delivered.contains(case.name()) != case.accepted() {
+            errors.push(format!(
+                "The handshake ({}) should{} have been accepted.",
+                case.name(),
+                if case.accepted() { "" } else { " not" }
+            ));
+        }
+    }
+
+    // Send PINGs to let the mock send WHOAREYOUs with each `enr_seq`.
+    for enr_seq in CHALLENGE_ENR_SEQS.iter() {
+        if let Err(e) = discv5.send_ping(mock.enr.clone()).await {
+            errors.push(format!("Failed to send PING. {enr_seq:?}: {e}"));
+        }
+    }
+
+    client
+        .signal_and_wait(STATE_FINISHED, client.run_parameters().test_instance_count)
+        .await?;
+
+    if errors.is_empty() {
+        client.record_success().await?;
+    } else {
+        for e in errors.iter() {
+            error!("{e}");
+        }
+        client.record_failure(errors.join(", ")).await?;
+    }
+    Ok(())
+}
+
+async fn run_mock(
+    client: Client,
+    enr: Enr,
+    enr_key: CombinedKey,
+    config: discv5::Config,
+    discv5_node: InstanceInfo,
+) -> Result<(), Box<dyn std::error::Error>> {
+    // The key is moved into the mock, so build the ENRs in advance.
+    let handshake_enrs = HANDSHAKE_CASES
+        .iter()
+        .map(|case| case.handshake_enr(&enr, &enr_key))
+        .collect::<Vec<_>>();
+
+    // ////////////////////////
+    // Start mock
+    // ////////////////////////
+    // The discv5 node sends a PING for each `enr_seq`. The first one is sent without session, and
+    // the rest are sent over the session established by the previous one. Note that the mock
+    // still holds the session of the last handshake it has sent, which the discv5 node has
+    // rejected, so the first PING can't be told from a message over a broken session.
+    let mut behaviours = VecDeque::new();
+    for (i, enr_seq) in CHALLENGE_ENR_SEQS.iter().enumerate() {
+        behaviours.push_back(Step::Ordered(Behaviour {
+            expect: if i == 0 {
+                Expect::Any
+            } else {
+                Expect::Message(Request::Ping)
+            },
+            actions: vec![Action::SendWhoAreYouWithEnrSeq(enr_seq.clone())],
+        }));
+        behaviours.push_back(Step::Ordered(Behaviour {
+            expect: Expect::Handshake(Request::Ping),
+            actions: vec![
+                Action::EstablishSession,
+                Action::SendResponse(Response::Default),
+            ],
+        }));
+    }
+    let mut mock = Mock::start(enr, enr_key, config, Behaviours::Sequential(behaviours)).await;
+    mock.add_enr(discv5_node.enr.clone())?;
+
+    client
+        .signal_and_wait(
+            STATE_DISCV5_STARTED,
+            client.run_parameters().test_instance_count,
+        )
+        .await?;
+
+    // //////////////////////////////////////////////////////////////
+    // Send handshakes with the ENRs
+    // //////////////////////////////////////////////////////////////
+    let mut errors = vec![];
+    for (case, handshake_enr) in HANDSHAKE_CASES.into_iter().zip(handshake_enrs) {
+        let body = RequestBody::Talk {
+            protocol: TALK_PROTOCOL.to_vec(),
+            request: case.name().as_bytes().to_vec(),
+        };
+        mock.send_handshake(discv5_node.enr.clone(), body, handshake_enr)?;
+
+        let response = tokio::time::timeout(Duration::from_secs(3), mock.recv_response()).await;
+        match (response, case.accepted()) {
+            (Ok(Some((_, response))), true) => match response.body {
+                ResponseBody::Talk { response } if response == case.name().as_bytes() => {}
+                body => errors.push(format!(
+                    "Received an unexpected response. case:{}, response:{body:?}",
+                    case.name()
+                )),
+            },
+            (Ok(Some((_, response))), false) => errors.push(format!(
+                "The handshake ({}) has been accepted. response:{response:?}",
+                case.name()
+            )),
+            (_, true) => errors.push(format!("No response to the handshake ({}).", case.name())),
+            (_, false) => info!("The handshake ({}) has been rejected.", case.name()),
+        }
+    }
+
+    client
+        .signal_and_wait(
+            STATE_HANDSHAKES_SENT,
+            client.run_parameters().test_instance_count,
+        )
+        .await?;
+
+    // //////////////////////////////////////////////////////////////
+    // Check the ENRs in the handshakes initiated by the discv5 node
+    // //////////////////////////////////////////////////////////////
+    // Each handshake is reported for the ENR and the id-nonce signature. The ENR check fails if the
+    // ENR is left out in reply to a zero or stale `enr_seq`, or attached in reply to the others.
+    let kinds = [
+        VerificationKind::HandshakeEnr,
+        VerificationKind::IdNonceSignature,
+    ];
+    for enr_seq in CHALLENGE_ENR_SEQS.iter() {
+        for kind in kinds.iter() {
+            match tokio::time::timeout(Duration::from_secs(10), mock.recv_verification()).await {
+                Ok(Some(verification)) => {
+                    if &verification.kind != kind {
+                        errors.push(format!(
+                            "Received an unexpected verification. {enr_seq:?}, expected:{kind:?}, actual:{:?}",
+                            verification.kind
+                        ));
+                    } else if let Err(e) = verification.result {
+                        errors.push(format!(
+                            "{enr_seq:?}: {e} node:{}",
+                            verification.node_address
+                        ));
+                    }
+                }
+                _ => errors.push(format!(
+                    "No handshake in reply to WHOAREYOU. {enr_seq:?}, {kind:?}"
+                )),
+            }
+        }
+    }
+
+    client
+        .signal_and_wait(STATE_FINISHED, client.run_parameters().test_instance_count)
+        .await?;
+
+    if errors.is_empty() {
+        client.record_success().await?;
+    } else {
+        for e in errors.iter() {
+            error!("{e}");
+        }
+        client.record_failure(errors.join(", ")).await?;
+    }
+    Ok(())
+}
+
+impl HandshakeCase {
+    fn name(&self) -> &'static str {
+        match self {
+            HandshakeCase::Missing => "missing",
+            HandshakeCase::Valid => "valid",
+            HandshakeCase::Stale => "stale",
+            HandshakeCase::SocketMismatch => "socket-mismatch",
+        }
+    }
+
+    fn accepted(&self) -> bool {
+        match self {
+            // The discv5 node doesn't know the mock yet, so the handshake is rejected.
+            HandshakeCase::Missing => false,
+            HandshakeCase::Valid => true,
+            // The discv5 node keeps the newer ENR it has learned from the previous handshake.
+            HandshakeCase::Stale => true,
+            // The newer ENR is adopted, but it doesn't match the socket the mock sends from.
+            HandshakeCase::SocketMismatch => false,
+        }
+    }
+
+    fn handshake_enr(&self, enr: &Enr, enr_key: &CombinedKey) -> HandshakeEnr {
+        let build_enr = |seq: u64, port: u16| {
+            let mut enr = enr.clone();
+            // Set the sequence number last since updating a field increments it.
+            enr.set_udp4(port, enr_key).expect("udp4");
+            enr.set_seq(seq, enr_key).expect("seq");
+            Box::new(enr)
+        };
+
+        match self {
+            HandshakeCase::Missing => HandshakeEnr::Omit,
+            HandshakeCase::Valid => HandshakeEnr::Auto,
+            HandshakeCase::Stale => HandshakeEnr::Custom(build_enr(ENR_SEQ - 1, 9000)),
+            HandshakeCase::SocketMismatch => HandshakeEnr::Custom(build_enr(ENR_SEQ + 1, 9001)),
+        }
+    }
+}
@@ -1,16 +1,20 @@
 mod params;
 
+use crate::capture;
 use crate::ip_change::params::Params;
-use crate::utils::publish_and_collect;
+use crate::network::{assign_ip, unused_ips};
+use crate::trace::Tracer;
+use crate::utils::{publish_and_collect, publish_and_collect_on};
+use chrono::Local;
 use discv5::enr::CombinedKey;
-use discv5::{Discv5, Enr, ListenConfig};
+use discv5::{Discv5, Enr, Event, ListenConfig};
 use serde::{Deserialize, Serialize};
 use std::net::{IpAddr, Ipv4Addr};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use testground::client::Client;
-use testground::network_conf::{
-    FilterAction, LinkShape, NetworkConfiguration, RoutingPolicyType, DEFAULT_DATA_NETWORK,
-};
+use testground::WriteQuery;
+use tokio::sync::mpsc;
+use tracing::{error, info, warn};
 
 const STATE_COMPLETED_TO_CONNECT: &str = "state_completed_to_connect";
 
@@ -52,6 +56,25 @@ pub(crate) async fn run(client: Client) -> Result<(), Box<dyn std::error::Error>
         .build();
     let mut discv5: Discv5 = Discv5::new(enr.clone(), enr_key, config)?;
     discv5.start().await.expect("Start Discovery v5 server");
+    let tracer = Tracer::new(
+        &run_parameters.test_run,
+        client.global_seq(),
+        "discv5",
+        &enr,
+    );
+
+    // Observe Discv5 events, passing the socket updates on.
+    let (socket_updated_sender, mut socket_updated) = mpsc::unbounded_channel();
+    let mut event_stream = discv5.event_stream().await.expect("Discv5Event");
+    let event_tracer = tracer.clone();
+    tokio::spawn(async move {
+        while let Some(event) = event_stream.recv().await {
+            event_tracer.record_event(&event);
+            if let Event::SocketUpdated(socket_addr) = event {
+                let _ = socket_updated_sender.send((socket_addr, Instant::now()));
+            }
+        }
+    });
 
     // //////////////////////////////////////////////////////////////
     // Collect information of all participants in the test case
@@ -68,6 +91,7 @@ pub(crate) async fn run(client: Client) -> Result<(), Box<dyn std::error::Error>
     ));
 
     let participants = publish_and_collect(&client, instance_info.clone()).await?;
+    capture::register_enrs(participants.iter().map(|p| &p.enr));
 
     // //////////////////////////////////////////////////////////////
     // Construct topology
@@ -109,21 +133,132 @@ pub(crate) async fn run(client: Client) -> Result<(), Box<dyn std::error::Error>
     // //////////////////////////////////////////////////////////////
     tokio::time::sleep(Duration::from_secs(params.duration_before)).await;
 
+    let mut errors = vec![];
+    let mut write_query = WriteQuery::new(
+        Local::now().into(),
+        format!(
+            "discv5-testground_{}_{}",
+            run_parameters.test_case, run_parameters.test_run
+        ),
+    )
+    .add_tag("instance_seq", instance_info.seq);
+
     if instance_info.seq == 1 {
         let new_ip = change_ip(&client, &participants).await?;
+        let changed_at = Instant::now();
         client.record_message(format!(
             "IP address has been changed from {} to {}.",
             ip, new_ip
         ));
+
+        // //////////////////////////////////////////////////////////////
+        // Wait for the socket to be updated
+        // //////////////////////////////////////////////////////////////
+        // The votes for the new address replace the old ones as the peers answer the PINGs sent
+        // every `ping_interval`. Allow for a round of PINGs which was in flight during the
+        // change, and for the old votes to expire after `vote_duration`.
+        let bound = Duration::from_secs(params.ping_interval * 2 + params.vote_duration);
+        let updated_at = tokio::time::timeout(bound, async {
+            while let Some((socket_addr, updated_at)) = socket_updated.recv().await {
+                if socket_addr.ip() == new_ip {
+                    return Some(updated_at);
+                }
+                warn!("The socket has been updated to an unexpected address: {socket_addr}");
+            }
+            None
+        })
+        .await
+        .ok()
+        .flatten();
+        let delay = updated_at.map(|updated_at| updated_at - changed_at);
+        match delay {
+            Some(delay) => info!("The socket has been updated in {delay:?}."),
+            None => errors.push(format!(
+                "The socket has not been updated to {new_ip} within {bound:?}."
+            )),
+        }
+
+        tokio::time::sleep(
+            Duration::from_secs(params.duration_after).saturating_sub(changed_at.elapsed()),
+        )
+        .await;
+
+        write_query = write_query
+            .add_field("socket_updated", delay.is_some())
+            .add_field("socket_update_bound_ms", bound.as_millis() as u64);
+        if let Some(delay) = delay {
+            write_query = write_query.add_field("socket_update_delay_ms", delay.as_millis() as u64);
+        }
+    } else {
+        tokio::time::sleep(Duration::from_secs(params.duration_after)).await;
     }
 
-    tokio::time::sleep(Duration::from_secs(params.duration_after)).await;
+    // //////////////////////////////////////////////////////////////
+    // Check the peers have followed Node1
+    // //////////////////////////////////////////////////////////////
+    let participants = publish_and_collect_on(
+        &client,
+        "ip_change_enrs",
+        InstanceInfo {
+            seq: instance_info.seq,
+            enr: discv5.local_enr(),
+        },
+    )
+    .await?;
+    capture::register_enrs(participants.iter().map(|p| &p.enr));
+    let node1 = participants
+        .into_iter()
+        .find(|p| p.seq == 1)
+        .expect("Node1")
+        .enr;
+
+    if instance_info.seq != 1 {
+        // Node1's ENR in the routing table should be the one with the new address.
+        let known = discv5.find_enr(&node1.node_id());
+        let enr_updated = known
+            .as_ref()
+            .map(|enr| enr.seq() >= node1.seq() && enr.ip4() == node1.ip4())
+            .unwrap_or(false);
+        if !enr_updated {
+            errors.push(format!(
+                "The routing table has {:?} as Node1's ENR, expected {node1}.",
+                known
+            ));
+        }
 
-    if instance_info.seq == 1 {
-        println!("debug: {:?}", discv5.table_entries());
+        // FINDNODE against Node1 at the address the peer knows.
+        let started_at = Instant::now();
+        let find_node_succeeded = match discv5
+            .find_node_designated_peer(known.unwrap_or_else(|| node1.clone()), vec![0])
+            .await
+        {
+            Ok(enrs) => enrs.iter().any(|enr| enr.node_id() == node1.node_id()),
+            Err(e) => {
+                warn!("Failed to run FINDNODE against Node1: {e}");
+                false
+            }
+        };
+        let find_node_latency = started_at.elapsed();
+        if !find_node_succeeded {
+            errors.push("FINDNODE against Node1 has failed.".to_string());
+        }
+
+        write_query = write_query
+            .add_field("node1_enr_updated", enr_updated)
+            .add_field("find_node_succeeded", find_node_succeeded)
+            .add_field("find_node_latency_ms", find_node_latency.as_millis() as u64);
     }
+    client.record_metric(write_query).await?;
 
-    client.record_success().await?;
+    tracer.write(&run_parameters.test_outputs_path)?;
+    if errors.is_empty() {
+        client.record_success().await?;
+    } else {
+        for e in errors.iter() {
+            error!("{e}");
+        }
+        client.record_failure(errors.join(", ")).await?;
+    }
     Ok(())
 }
 
@@ -136,55 +271,11 @@ async fn change_ip(
         .map(|p| p.enr.ip4().expect("ip4"))
         .collect::<Vec<_>>();
 
-    let subnet = client.run_parameters().test_subnet;
-
-    let new_ip = {
-        let mut iter = subnet.iter();
-        // Calling `next()` twice in order to skip network address and the first one. The first one is reserved by Testground.
-        iter.next();
-        iter.next();
-
-        iter.find(|i| {
-            if let IpAddr::V4(ipv4) = i {
-                !participants_ip4.contains(ipv4)
-            } else {
-                false
-            }
-        })
+    let new_ip = unused_ips(client, &participants_ip4)
+        .next()
         .expect("unused ip")
-    };
-
-    client
-        .configure_network(NetworkConfiguration {
-            network: DEFAULT_DATA_NETWORK.to_owned(),
-            ipv4: Some(format!("{}/{}", new_ip, subnet.prefix()).parse().unwrap()),
-            ipv6: None,
-            enable: true,
-            default: LinkShape {
-                latency: client
-                    .run_parameters()
-                    .test_instance_params
-                    .get("latency")
-                    .ok_or("latency is not specified")?
-                    .parse::<u64>()?
-                    * 1_000_000, // Translate from millisecond to nanosecond
-                jitter: 0,
-                bandwidth: 1048576, // 1Mib
-                filter: FilterAction::Accept,
-                loss: 0.0,
-                corrupt: 0.0,
-                corrupt_corr: 0.0,
-                reorder: 0.0,
-                reorder_corr: 0.0,
-                duplicate: 0.0,
-                duplicate_corr: 0.0,
-            },
-            rules: None,
-            callback_state: "ip_change".to_owned(),
-            callback_target: Some(1),
-            routing_policy: RoutingPolicyType::DenyAll,
-        })
-        .await?;
+        .into();
+    assign_ip(client, new_ip, "ip_change", 1).await?;
 
     Ok(new_ip)
 }
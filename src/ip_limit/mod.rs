@@ -0,0 +1,370 @@
+use crate::capture;
+use crate::fleet::Fleet;
+use crate::network::{
+    assign_ip, unused_ips, MAX_NODES_PER_SUBNET_BUCKET, MAX_NODES_PER_SUBNET_TABLE,
+};
+use crate::utils::{get_param, publish_and_collect, publish_and_collect_on};
+use chrono::Local;
+use discv5::enr::{CombinedKey, NodeId};
+use discv5::{Discv5, Enr, Key, ListenConfig};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::net::{IpAddr, Ipv4Addr};
+use testground::client::Client;
+use testground::WriteQuery;
+use tokio::task::JoinSet;
+use tracing::{error, info, warn};
+
+const STATE_CONNECTED: &str = "STATE_CONNECTED";
+const STATE_DONE: &str = "STATE_DONE";
+
+// The state the network configuration of the instances moving to a new address is signalled with.
+const CALLBACK_STATE_IP_ASSIGNED: &str = "ip_assigned";
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+enum Role {
+    Victim,
+    Honest,
+    Attackers,
+}
+
+impl From<&str> for Role {
+    fn from(test_group_id: &str) -> Self {
+        match test_group_id {
+            "victim" => Role::Victim,
+            "honest" => Role::Honest,
+            "attackers" => Role::Attackers,
+            _ => unreachable!(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct AddressInfo {
+    role: Role,
+    // The address given by Testground.
+    ip: Ipv4Addr,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct InstanceInfo {
+    role: Role,
+    // The ENRs of the identities hosted in the instance.
+    enrs: Vec<Enr>,
+}
+
+pub(crate) async fn run(client: Client) -> Result<(), Box<dyn std::error::Error>> {
+    let run_parameters = client.run_parameters();
+    let role: Role = run_parameters.test_group_id.as_str().into();
+    let ip = match run_parameters
+        .data_network_ip()?
+        .expect("IP address for the data network")
+    {
+        IpAddr::V4(ip) => ip,
+        IpAddr::V6(_) => return Err("The data network must be IPv4.".into()),
+    };
+    client.record_message(format!(
+        "role: {:?}, group_seq: {}, ip: {}",
+        role,
+        client.group_seq(),
+        ip
+    ));
+
+    // //////////////////////////////////////////////////////////////
+    // Move the honest nodes and the attackers to their addresses
+    // //////////////////////////////////////////////////////////////
+    let used = publish_and_collect(
+        &client,
+        AddressInfo {
+            role: role.clone(),
+            ip,
+        },
+    )
+    .await?
+    .into_iter()
+    .map(|a| a.ip)
+    .collect::<Vec<_>>();
+
+    let ip = match pick_ip(&client, &role, &used)? {
+        Some(new_ip) => {
+            assign_ip(
+                &client,
+                new_ip.into(),
+                CALLBACK_STATE_IP_ASSIGNED,
+                run_parameters.test_instance_count - 1,
+            )
+            .await?;
+            client.record_message(format!("IP address has been changed to {new_ip}."));
+            new_ip
+        }
+        None => ip,
+    };
+
+    match role {
+        Role::Victim => play_victim(client, ip).await?,
+        Role::Honest | Role::Attackers => play_peers(client, ip, role).await?,
+    }
+
+    Ok(())
+}
+
+/// Picks the new address of the instance: all the attackers get one from the same /24 subnet,
+/// and each honest node gets one from a /24 subnet of its own. None of the subnets has any of
+/// the addresses in `used`. The victim keeps its address.
+///
+/// Every instance picks the addresses in the same order, so that they never collide.
+fn pick_ip(
+    client: &Client,
+    role: &Role,
+    used: &[Ipv4Addr],
+) -> Result<Option<Ipv4Addr>, Box<dyn std::error::Error>> {
+    let used_subnets = used.iter().map(subnet).collect::<HashSet<_>>();
+    let mut free_subnets: BTreeMap<[u8; 3], Vec<Ipv4Addr>> = BTreeMap::new();
+    for ip in unused_ips(client, used).filter(|ip| !used_subnets.contains(&subnet(ip))) {
+        free_subnets.entry(subnet(&ip)).or_default().push(ip);
+    }
+    let mut free_subnets = free_subnets.into_values();
+    let attackers_subnet = free_subnets.next();
+
+    let index = usize::try_from(client.group_seq() - 1).expect("Valid as usize");
+    let ip = match role {
+        Role::Victim => return Ok(None),
+        Role::Attackers => attackers_subnet.and_then(|ips| ips.get(index).copied()),
+        Role::Honest => free_subnets.nth(index).and_then(|ips| ips.first().copied()),
+    };
+
+    match ip {
+        Some(ip) => Ok(Some(ip)),
+        None => Err("The data network has not enough /24 subnets for the instances.".into()),
+    }
+}
+
+fn subnet(ip: &Ipv4Addr) -> [u8; 3] {
+    let octets = ip.octets();
+    [octets[0], octets[1], octets[2]]
+}
+
+async fn play_victim(client: Client, ip: Ipv4Addr) -> Result<(), Box<dyn std::error::Error>> {
+    let run_parameters = client.run_parameters();
+    let ip_limit: bool = get_param("ip_limit", &run_parameters.test_instance_params)?;
+
+    // ////////////////////////
+    // Start discv5
+    // ////////////////////////
+    let enr_key = CombinedKey::generate_secp256k1();
+    let enr = Enr::builder()
+        .ip4(ip)
+        .udp4(9000)
+        .build(&enr_key)
+        .expect("Construct an Enr");
+    let listen_config = ListenConfig::Ipv4 {
+        ip: Ipv4Addr::UNSPECIFIED,
+        port: 9000,
+    };
+    let mut config_builder = discv5::ConfigBuilder::new(listen_config);
+    if ip_limit {
+        config_builder.ip_limit();
+    }
+    let mut discv5: Discv5 = Discv5::new(enr.clone(), enr_key, config_builder.build())?;
+    discv5.start().await.expect("Start Discovery v5 server");
+
+    // //////////////////////////////////////////////////////////////
+    // Collect information of all participants in the test case
+    // //////////////////////////////////////////////////////////////
+    let participants = publish_and_collect_on(
+        &client,
+        "ip_limit_enrs",
+        InstanceInfo {
+            role: Role::Victim,
+            enrs: vec![enr],
+        },
+    )
+    .await?;
+    capture::register_enrs(participants.iter().flat_map(|p| p.enrs.iter()));
+    let ids_of = |role: Role| {
+        participants
+            .iter()
+            .filter(|p| p.role == role)
+            .flat_map(|p| p.enrs.iter().map(Enr::node_id))
+            .collect::<HashSet<_>>()
+    };
+    let honest_ids = ids_of(Role::Honest);
+    let attacker_ids = ids_of(Role::Attackers);
+
+    // Wait until the peers have connected to the victim.
+    client
+        .signal_and_wait(STATE_CONNECTED, run_parameters.test_instance_count)
+        .await?;
+
+    // //////////////////////////////////////////////////////////////
+    // Check the routing table
+    // //////////////////////////////////////////////////////////////
+    let local_key: Key<NodeId> = discv5.local_enr().node_id().into();
+    let table_entries = discv5.table_entries();
+    let mut per_bucket: BTreeMap<(u64, [u8; 3]), usize> = BTreeMap::new();
+    let mut per_table: BTreeMap<[u8; 3], usize> = BTreeMap::new();
+    for (node_id, enr, _) in table_entries.iter() {
+        let ip = match enr.ip4() {
+            Some(ip) => ip,
+            None => continue,
+        };
+        let distance = local_key
+            .log2_distance(&(*node_id).into())
+            .expect("Not the local node");
+        *per_bucket.entry((distance, subnet(&ip))).or_default() += 1;
+        *per_table.entry(subnet(&ip)).or_default() += 1;
+    }
+    let honest_entries = table_entries
+        .iter()
+        .filter(|(node_id, _, _)| honest_ids.contains(node_id))
+        .count();
+    let attacker_entries = table_entries
+        .iter()
+        .filter(|(node_id, _, _)| attacker_ids.contains(node_id))
+        .count();
+    info!(
+        "table_entries: {}, honest_entries: {honest_entries}/{}, attacker_entries: {attacker_entries}/{}, per_table: {per_table:?}",
+        table_entries.len(),
+        honest_ids.len(),
+        attacker_ids.len(),
+    );
+
+    let mut errors = vec![];
+    if ip_limit {
+        for ((distance, subnet), count) in per_bucket.iter() {
+            if *count > MAX_NODES_PER_SUBNET_BUCKET {
+                errors.push(format!(
+                    "The bucket at distance {distance} has {count} nodes from {subnet:?}, exceeding the limit {MAX_NODES_PER_SUBNET_BUCKET}."
+                ));
+            }
+        }
+        for (subnet, count) in per_table.iter() {
+            if *count > MAX_NODES_PER_SUBNET_TABLE {
+                errors.push(format!(
+                    "The table has {count} nodes from {subnet:?}, exceeding the limit {MAX_NODES_PER_SUBNET_TABLE}."
+                ));
+            }
+        }
+    }
+    // The limits must not keep out the honest nodes, which are spread over the subnets.
+    if honest_entries < honest_ids.len() {
+        errors.push(format!(
+            "Only {honest_entries} of {} honest nodes are in the table.",
+            honest_ids.len()
+        ));
+    }
+
+    // //////////////////////////////////////////////////////////////
+    // Record metrics
+    // //////////////////////////////////////////////////////////////
+    let write_query = WriteQuery::new(
+        Local::now().into(),
+        format!(
+            "discv5-testground_{}_{}",
+            run_parameters.test_case, run_parameters.test_run
+        ),
+    )
+    .add_field("table_entries", table_entries.len() as u64)
+    .add_field("honest_entries", honest_entries as u64)
+    .add_field("attackers", attacker_ids.len() as u64)
+    .add_field("attacker_entries", attacker_entries as u64)
+    .add_tag("ip_limit", ip_limit);
+    client.record_metric(write_query).await?;
+
+    client
+        .signal_and_wait(STATE_DONE, run_parameters.test_instance_count)
+        .await?;
+
+    if errors.is_empty() {
+        client.record_success().await?;
+    } else {
+        for e in errors.iter() {
+            error!("{e}");
+        }
+        client.record_failure(errors.join(", ")).await?;
+    }
+    Ok(())
+}
+
+async fn play_peers(
+    client: Client,
+    ip: Ipv4Addr,
+    role: Role,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let run_parameters = client.run_parameters();
+    // Each attacker instance hosts several identities on its address, while an honest instance
+    // hosts one.
+    let num_identities: usize = match role {
+        Role::Attackers => get_param("num_identities", &run_parameters.test_instance_params)?,
+        _ => 1,
+    };
+
+    // ////////////////////////
+    // Start the fleet
+    // ////////////////////////
+    let keys = (0..num_identities)
+        .map(|_| CombinedKey::generate_secp256k1())
+        .collect();
+    let fleet = Fleet::start_discv5(ip.into(), 9000, keys, |listen_config| {
+        discv5::ConfigBuilder::new(listen_config).build()
+    })
+    .await?;
+
+    // //////////////////////////////////////////////////////////////
+    // Collect information of all participants in the test case
+    // //////////////////////////////////////////////////////////////
+    let participants = publish_and_collect_on(
+        &client,
+        "ip_limit_enrs",
+        InstanceInfo {
+            role,
+            enrs: fleet.enrs(),
+        },
+    )
+    .await?;
+    capture::register_enrs(participants.iter().flat_map(|p| p.enrs.iter()));
+    let victim = participants
+        .into_iter()
+        .find(|p| p.role == Role::Victim)
+        .expect("victim")
+        .enrs
+        .remove(0);
+
+    // //////////////////////////////////////////////////////////////
+    // Connect to the victim
+    // //////////////////////////////////////////////////////////////
+    // Each identity establishes a session with the victim, which makes the victim insert it into
+    // its routing table as an incoming node.
+    let mut tasks = JoinSet::new();
+    for member in fleet.members.iter() {
+        let discv5 = member.node.clone();
+        let victim = victim.clone();
+        tasks.spawn(async move { discv5.send_ping(victim).await });
+    }
+    let mut failures = 0;
+    while let Some(result) = tasks.join_next().await {
+        match result {
+            Ok(Ok(_)) => {}
+            Ok(Err(e)) => {
+                warn!("Failed to send PING: {e}");
+                failures += 1;
+            }
+            Err(e) => {
+                warn!("Failed to join the task: {e}");
+                failures += 1;
+            }
+        }
+    }
+    info!("Connected to the victim. failures: {failures}/{num_identities}");
+
+    client
+        .signal_and_wait(STATE_CONNECTED, run_parameters.test_instance_count)
+        .await?;
+
+    client
+        .signal_and_wait(STATE_DONE, run_parameters.test_instance_count)
+        .await?;
+
+    client.record_success().await?;
+    Ok(())
+}
@@ -0,0 +1,337 @@
+use crate::capture;
+use crate::fleet::Fleet;
+use crate::mock::{
+    Action, Behaviours, CustomResponse, CustomResponseId, DeclarativeBehaviour, Response,
+};
+use crate::utils::{get_param, publish_and_collect};
+use chrono::Local;
+use discv5::enr::CombinedKey;
+use discv5::rpc::ResponseBody;
+use discv5::{Discv5, Enr, ListenConfig};
+use serde::{Deserialize, Serialize};
+use std::net::{IpAddr, Ipv4Addr, SocketAddrV4};
+use std::num::NonZeroU16;
+use std::time::Duration;
+use testground::client::Client;
+use testground::WriteQuery;
+use tracing::{error, info, warn};
+
+const STATE_COMPLETED_TO_COLLECT_INSTANCE_INFORMATION: &str =
+    "STATE_COMPLETED_TO_COLLECT_INSTANCE_INFORMATION";
+const STATE_DONE: &str = "STATE_DONE";
+
+// The address the attackers try to make the victim advertise. This is in TEST-NET-3, so that
+// nobody can be reached at it.
+const FORGED_IP: Ipv4Addr = Ipv4Addr::new(203, 0, 113, 1);
+const FORGED_PORT: u16 = 30303;
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+enum Role {
+    Victim,
+    Honest,
+    Attackers,
+}
+
+impl From<&str> for Role {
+    fn from(test_group_id: &str) -> Self {
+        match test_group_id {
+            "victim" => Role::Victim,
+            "honest" => Role::Honest,
+            "attackers" => Role::Attackers,
+            _ => unreachable!(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct InstanceInfo {
+    role: Role,
+    // The ENRs of the identities hosted in the instance.
+    enrs: Vec<Enr>,
+}
+
+pub(crate) async fn run(client: Client) -> Result<(), Box<dyn std::error::Error>> {
+    let run_parameters = client.run_parameters();
+    let role: Role = run_parameters.test_group_id.as_str().into();
+    let ip = run_parameters
+        .data_network_ip()?
+        .expect("IP address for the data network");
+    client.record_message(format!(
+        "role: {:?}, group_seq: {}",
+        role,
+        client.group_seq()
+    ));
+
+    match role {
+        Role::Victim => play_victim(client, ip).await?,
+        Role::Honest => play_honest(client, ip).await?,
+        Role::Attackers => play_attackers(client, ip).await?,
+    }
+
+    Ok(())
+}
+
+async fn play_victim(client: Client, ip: IpAddr) -> Result<(), Box<dyn std::error::Error>> {
+    let run_parameters = client.run_parameters();
+    let ping_interval: u64 = get_param("ping_interval", &run_parameters.test_instance_params)?;
+    let vote_duration: u64 = get_param("vote_duration", &run_parameters.test_instance_params)?;
+    let enr_peer_update_min: usize =
+        get_param("enr_peer_update_min", &run_parameters.test_instance_params)?;
+
+    // ////////////////////////
+    // Start discv5
+    // ////////////////////////
+    let enr_key = CombinedKey::generate_secp256k1();
+    let enr = Enr::builder()
+        .ip(ip)
+        .udp4(9000)
+        .build(&enr_key)
+        .expect("Construct an Enr");
+    let listen_config = ListenConfig::Ipv4 {
+        ip: Ipv4Addr::UNSPECIFIED,
+        port: 9000,
+    };
+    let config = discv5::ConfigBuilder::new(listen_config)
+        .ping_interval(Duration::from_secs(ping_interval))
+        .vote_duration(Duration::from_secs(vote_duration))
+        .enr_peer_update_min(enr_peer_update_min)
+        .build();
+    let mut discv5: Discv5 = Discv5::new(enr.clone(), enr_key, config)?;
+    discv5.start().await.expect("Start Discovery v5 server");
+
+    // //////////////////////////////////////////////////////////////
+    // Collect information of all participants in the test case
+    // //////////////////////////////////////////////////////////////
+    let participants = publish_and_collect(
+        &client,
+        InstanceInfo {
+            role: Role::Victim,
+            enrs: vec![enr],
+        },
+    )
+    .await?;
+    capture::register_enrs(participants.iter().flat_map(|p| p.enrs.iter()));
+    let enrs_of = |role: Role| {
+        participants
+            .iter()
+            .filter(|p| p.role == role)
+            .flat_map(|p| p.enrs.clone())
+            .collect::<Vec<_>>()
+    };
+    let honest = enrs_of(Role::Honest);
+    let attackers = enrs_of(Role::Attackers);
+
+    client
+        .signal_and_wait(
+            STATE_COMPLETED_TO_COLLECT_INSTANCE_INFORMATION,
+            run_parameters.test_instance_count,
+        )
+        .await?;
+
+    // //////////////////////////////////////////////////////////////
+    // Let the attackers vote one by one
+    // //////////////////////////////////////////////////////////////
+    // Votes are counted only from the PONGs to the periodic PINGs, sent to the connected peers the
+    // victim has contacted. So connect to each peer and wait for its first periodic PING.
+    let forged_socket = SocketAddrV4::new(FORGED_IP, FORGED_PORT);
+    let wait = Duration::from_secs(ping_interval + 2);
+    let mut errors = vec![];
+
+    for peer in honest.iter() {
+        connect(&discv5, peer).await;
+    }
+    tokio::time::sleep(wait).await;
+    info!(
+        "Connected to {} honest peers. local_socket: {:?}",
+        honest.len(),
+        discv5.local_enr().udp4_socket()
+    );
+
+    let mut flipped_at = None;
+    for (i, attacker) in attackers.iter().enumerate() {
+        let num_attackers = i + 1;
+        connect(&discv5, attacker).await;
+        tokio::time::sleep(wait).await;
+
+        let local_socket = discv5.local_enr().udp4_socket();
+        info!("attackers: {num_attackers}, local_socket: {local_socket:?}");
+        if local_socket == Some(forged_socket) {
+            flipped_at = Some(num_attackers);
+            break;
+        }
+    }
+
+    // The victim must keep its address while the honest peers are the majority, or the attackers
+    // are fewer than `enr_peer_update_min`. A tie is decided arbitrarily by discv5.
+    match flipped_at {
+        Some(num_attackers) if num_attackers < honest.len() => errors.push(format!(
+            "The attackers have flipped the victim's address despite the honest majority. attackers:{num_attackers}, honest:{}",
+            honest.len()
+        )),
+        Some(num_attackers) if num_attackers < enr_peer_update_min => errors.push(format!(
+            "The attackers have flipped the victim's address with fewer votes than enr_peer_update_min. attackers:{num_attackers}, enr_peer_update_min:{enr_peer_update_min}"
+        )),
+        Some(num_attackers) => warn!(
+            "The attackers have flipped the victim's address to {forged_socket}. attackers:{num_attackers}, honest:{}",
+            honest.len()
+        ),
+        None => info!(
+            "The victim has kept its address. attackers:{}, honest:{}",
+            attackers.len(),
+            honest.len()
+        ),
+    }
+
+    // //////////////////////////////////////////////////////////////
+    // Record metrics
+    // //////////////////////////////////////////////////////////////
+    let write_query = WriteQuery::new(
+        Local::now().into(),
+        format!(
+            "discv5-testground_{}_{}",
+            run_parameters.test_case, run_parameters.test_run
+        ),
+    )
+    .add_field("honest", honest.len() as u64)
+    .add_field("attackers", attackers.len() as u64)
+    .add_field("flipped", flipped_at.is_some())
+    // Zero if the attackers haven't flipped the address.
+    .add_field("attackers_to_flip", flipped_at.unwrap_or_default() as u64)
+    .add_tag("enr_peer_update_min", enr_peer_update_min as u64);
+    client.record_metric(write_query).await?;
+
+    client
+        .signal_and_wait(STATE_DONE, run_parameters.test_instance_count)
+        .await?;
+
+    if errors.is_empty() {
+        client.record_success().await?;
+    } else {
+        for e in errors.iter() {
+            error!("{e}");
+        }
+        client.record_failure(errors.join(", ")).await?;
+    }
+    Ok(())
+}
+
+/// Inserts the peer into the routing table as an outgoing node, which makes the PONGs from the
+/// peer count as votes.
+async fn connect(discv5: &Discv5, peer: &Enr) {
+    if let Err(e) = discv5.add_enr(peer.clone()) {
+        warn!("Failed to add an ENR: {e}");
+    }
+    if let Err(e) = discv5.send_ping(peer.clone()).await {
+        warn!("Failed to send PING: {e}");
+    }
+}
+
+async fn play_honest(client: Client, ip: IpAddr) -> Result<(), Box<dyn std::error::Error>> {
+    let run_parameters = client.run_parameters();
+    let num_honest: usize = get_param("num_honest", &run_parameters.test_instance_params)?;
+
+    // ////////////////////////
+    // Start the fleet
+    // ////////////////////////
+    // The honest peers are discv5 nodes, which report the socket they have observed.
+    let keys = (0..num_honest)
+        .map(|_| CombinedKey::generate_secp256k1())
+        .collect();
+    let fleet = Fleet::start_discv5(ip, 9000, keys, |listen_config| {
+        discv5::ConfigBuilder::new(listen_config).build()
+    })
+    .await?;
+
+    // //////////////////////////////////////////////////////////////
+    // Collect information of all participants in the test case
+    // //////////////////////////////////////////////////////////////
+    let participants = publish_and_collect(
+        &client,
+        InstanceInfo {
+            role: Role::Honest,
+            enrs: fleet.enrs(),
+        },
+    )
+    .await?;
+    capture::register_enrs(participants.iter().flat_map(|p| p.enrs.iter()));
+
+    client
+        .signal_and_wait(
+            STATE_COMPLETED_TO_COLLECT_INSTANCE_INFORMATION,
+            run_parameters.test_instance_count,
+        )
+        .await?;
+
+    client
+        .signal_and_wait(STATE_DONE, run_parameters.test_instance_count)
+        .await?;
+
+    client.record_success().await?;
+    Ok(())
+}
+
+async fn play_attackers(client: Client, ip: IpAddr) -> Result<(), Box<dyn std::error::Error>> {
+    let run_parameters = client.run_parameters();
+    let num_attackers: usize = get_param("num_attackers", &run_parameters.test_instance_params)?;
+
+    // ////////////////////////
+    // Start the fleet
+    // ////////////////////////
+    // The attackers are mocks, which answer every PING with the forged socket.
+    let forged_pong = || {
+        Action::SendResponse(Response::Custom(vec![CustomResponse {
+            id: CustomResponseId::InboundRequestId,
+            body: ResponseBody::Pong {
+                // The sequence number of the ENRs built by the fleet.
+                enr_seq: 1,
+                ip: IpAddr::V4(FORGED_IP),
+                port: NonZeroU16::new(FORGED_PORT).expect("Non-zero port"),
+            },
+        }]))
+    };
+    let keys = (0..num_attackers)
+        .map(|_| CombinedKey::generate_secp256k1())
+        .collect();
+    let fleet = Fleet::start_mock(
+        ip,
+        9000,
+        keys,
+        |listen_config| discv5::ConfigBuilder::new(listen_config).build(),
+        || {
+            Behaviours::Declarative(DeclarativeBehaviour {
+                whoareyou: vec![Action::Ignore("Unexpected WHOAREYOU".to_string())],
+                handshake: vec![Action::EstablishSession, forged_pong()],
+                message: vec![forged_pong()],
+                message_without_session: vec![Action::SendWhoAreYou],
+            })
+        },
+    )
+    .await;
+
+    // //////////////////////////////////////////////////////////////
+    // Collect information of all participants in the test case
+    // //////////////////////////////////////////////////////////////
+    let participants = publish_and_collect(
+        &client,
+        InstanceInfo {
+            role: Role::Attackers,
+            enrs: fleet.enrs(),
+        },
+    )
+    .await?;
+    capture::register_enrs(participants.iter().flat_map(|p| p.enrs.iter()));
+
+    client
+        .signal_and_wait(
+            STATE_COMPLETED_TO_COLLECT_INSTANCE_INFORMATION,
+            run_parameters.test_instance_count,
+        )
+        .await?;
+
+    client
+        .signal_and_wait(STATE_DONE, run_parameters.test_instance_count)
+        .await?;
+
+    client.record_success().await?;
+    Ok(())
+}
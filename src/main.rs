@@ -1,11 +1,29 @@
+mod capture;
+mod churn;
 mod concurrent_requests;
+mod conformance;
 mod eclipse;
+mod enr_refresh;
 mod enr_update;
 mod find_node;
+mod fleet;
+mod flood;
+mod handshake_enr;
 mod ip_change;
+mod ip_limit;
+mod ip_vote_poisoning;
 mod mock;
+mod nat;
+mod network;
+mod nodes_response;
+mod protocol_id;
+mod protocol_id_isolation;
+mod replay;
 mod sandbox;
+mod session_cache;
+mod sybil_fleet;
 mod talk;
+mod trace;
 mod utils;
 
 use testground::client::Client;
@@ -25,6 +43,8 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         .with_env_filter(env_filter)
         .try_init();
 
+    capture::start(&client)?;
+
     // ////////////////////////
     // Configure network
     // ////////////////////////
@@ -65,6 +85,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     // //////////////////////////////////////////////////////////////
     match client.run_parameters().test_case.clone().as_str() {
         "find-node" => find_node::run(client.clone()).await?,
+        "churn" => churn::run(client).await?,
         "concurrent-requests" => concurrent_requests::run(client).await?,
         "concurrent-requests_whoareyou-timeout" => {
             concurrent_requests::whoareyou_timeout::run(client).await?
@@ -72,14 +93,29 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         "concurrent-requests_before-establishing-session" => {
             concurrent_requests::before_establishing_session::run(client).await?
         }
+        "conformance" => conformance::run(client).await?,
         "eclipse-attack-monopolizing-by-incoming-nodes" => {
             eclipse::MonopolizingByIncomingNodes::new()
                 .run(client.clone())
                 .await?
         }
+        "eclipse-attack-poisoned-nodes-responses" => {
+            eclipse::PoisonedNodes::new().run(client.clone()).await?
+        }
+        "enr-refresh" => enr_refresh::run(client).await?,
         "enr-update" => enr_update::run(client.clone()).await?,
+        "flood" => flood::run(client).await?,
+        "handshake-enr" => handshake_enr::run(client).await?,
         "ip-change" => ip_change::run(client).await?,
+        "ip-limit" => ip_limit::run(client).await?,
+        "ip-vote-poisoning" => ip_vote_poisoning::run(client).await?,
+        "nat" => nat::run(client).await?,
+        "nodes-response" => nodes_response::run(client).await?,
+        "protocol-id-isolation" => protocol_id_isolation::run(client).await?,
+        "replay" => replay::run(client).await?,
         "sandbox" => sandbox::run(client).await?,
+        "session-cache" => session_cache::run(client).await?,
+        "sybil-fleet" => sybil_fleet::run(client).await?,
         "talk" => talk::run(client).await?,
         _ => unreachable!(),
     };
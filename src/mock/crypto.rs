@@ -2,8 +2,9 @@ use crate::mock::ecdh::ecdh;
 use aes_gcm::aead::generic_array::GenericArray;
 use aes_gcm::aead::{Aead, NewAead, Payload};
 use aes_gcm::Aes128Gcm;
-use discv5::enr::k256::sha2::Sha256;
-use discv5::enr::{k256, CombinedKey, NodeId};
+use discv5::enr::k256::ecdsa::signature::{DigestSigner, DigestVerifier};
+use discv5::enr::k256::sha2::{Digest, Sha256};
+use discv5::enr::{k256, CombinedKey, CombinedPublicKey, NodeId};
 use discv5::packet::{ChallengeData, MessageNonce};
 use hkdf::Hkdf;
 
@@ -11,9 +12,37 @@ const NODE_ID_LENGTH: usize = 32;
 const INFO_LENGTH: usize = 26 + 2 * NODE_ID_LENGTH;
 const KEY_LENGTH: usize = 16;
 const KEY_AGREEMENT_STRING: &str = "discovery v5 key agreement";
+const ID_SIGNATURE_TEXT: &str = "discovery v5 identity proof";
 
 type Key = [u8; KEY_LENGTH];
 
+/// Generates the session keys as the initiator of a handshake. This returns the initiator key, the
+/// recipient key and the ephemeral public key.
+pub(crate) fn generate_session_keys(
+    local_id: &NodeId,
+    remote_id: &NodeId,
+    remote_pubkey: &CombinedPublicKey,
+    challenge_data: &ChallengeData,
+) -> Result<(Key, Key, Vec<u8>), String> {
+    let (secret, ephem_pubkey) = {
+        match remote_pubkey {
+            CombinedPublicKey::Secp256k1(remote_pubkey) => {
+                let ephem_key = k256::ecdsa::SigningKey::random(&mut rand::thread_rng());
+                let secret = ecdh(remote_pubkey, &ephem_key);
+                let ephem_pubkey = ephem_key.verifying_key().to_sec1_bytes().to_vec();
+                (secret, ephem_pubkey)
+            }
+            CombinedPublicKey::Ed25519(_) => {
+                return Err("Error::KeyTypeNotSupported(Ed25519)".to_string())
+            }
+        }
+    };
+
+    let (initiator_key, recipient_key) = derive_key(&secret, local_id, remote_id, challenge_data)?;
+
+    Ok((initiator_key, recipient_key, ephem_pubkey))
+}
+
 /// Derives the session keys for a public key type that matches the local keypair.
 pub(crate) fn derive_keys_from_pubkey(
     local_key: &CombinedKey,
@@ -65,6 +94,59 @@ fn derive_key(
     Ok((initiator_key, recipient_key))
 }
 
+/// Signs the id-nonce to prove the ownership of the local key in a handshake.
+pub(crate) fn sign_nonce(
+    signing_key: &CombinedKey,
+    challenge_data: &ChallengeData,
+    ephem_pubkey: &[u8],
+    dst_id: &NodeId,
+) -> Result<Vec<u8>, String> {
+    let signing_nonce = generate_signing_nonce(challenge_data, ephem_pubkey, dst_id);
+
+    match signing_key {
+        CombinedKey::Secp256k1(key) => {
+            let signature: k256::ecdsa::Signature = key
+                .try_sign_digest(Sha256::new().chain_update(signing_nonce))
+                .map_err(|e| format!("Failed to sign message: {e}"))?;
+            Ok(signature.to_vec())
+        }
+        CombinedKey::Ed25519(_) => Err("Error::KeyTypeNotSupported(Ed25519)".to_string()),
+    }
+}
+
+/// Verifies the id-nonce signature in a handshake against the public key of the initiator.
+pub(crate) fn verify_nonce(
+    remote_pubkey: &CombinedPublicKey,
+    remote_ephem_pubkey: &[u8],
+    challenge_data: &ChallengeData,
+    dst_id: &NodeId,
+    sig: &[u8],
+) -> Result<(), String> {
+    let signing_nonce = generate_signing_nonce(challenge_data, remote_ephem_pubkey, dst_id);
+
+    match remote_pubkey {
+        CombinedPublicKey::Secp256k1(key) => {
+            let sig = k256::ecdsa::Signature::try_from(sig)
+                .map_err(|e| format!("Invalid signature: {e}"))?;
+            key.verify_digest(Sha256::new().chain_update(signing_nonce), &sig)
+                .map_err(|e| format!("Failed to verify signature: {e}"))
+        }
+        CombinedPublicKey::Ed25519(_) => Err("Error::KeyTypeNotSupported(Ed25519)".to_string()),
+    }
+}
+
+fn generate_signing_nonce(
+    challenge_data: &ChallengeData,
+    ephem_pubkey: &[u8],
+    dst_id: &NodeId,
+) -> Vec<u8> {
+    let mut data = ID_SIGNATURE_TEXT.as_bytes().to_vec();
+    data.extend_from_slice(challenge_data.as_ref());
+    data.extend_from_slice(ephem_pubkey);
+    data.extend_from_slice(dst_id.raw().as_ref());
+    data
+}
+
 pub(crate) fn encrypt_message(
     key: &Key,
     message_nonce: MessageNonce,
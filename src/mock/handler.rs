@@ -1,20 +1,32 @@
+use crate::capture;
 use crate::mock;
+use crate::mock::crypto::verify_nonce;
 use crate::mock::session::Session;
-use crate::mock::socket::Socket;
-use crate::mock::{Action, Behaviours, CustomResponse, CustomResponseId, Expect, Request};
+use crate::mock::socket::{Socket, MAX_PACKET_SIZE};
+use crate::mock::transcript::Transcript;
+use crate::mock::{
+    Action, Behaviours, ChallengeEnrSeq, CustomResponse, CustomResponseId, EnrUpdate, Expect,
+    HandshakeEnr, NodesResponse, NodesTotal, PacketSize, PacketType, Replay, Request, Stats, Step,
+    Verification, VerificationKind,
+};
 use discv5::enr::{CombinedKey, NodeId};
 use discv5::handler::{NodeAddress, NodeContact};
-use discv5::packet::{ChallengeData, IdNonce, Packet, PacketKind};
-use discv5::rpc::{Message, RequestBody};
+use discv5::packet::ProtocolIdentity;
+use discv5::packet::{ChallengeData, IdNonce, MessageNonce, Packet, PacketKind};
+use discv5::rpc::{Message, RequestBody, RequestId, ResponseBody};
 use discv5::socket::{InboundPacket, OutboundPacket};
-use discv5::{DefaultProtocolId, Enr};
-use std::collections::HashMap;
-use std::net::IpAddr;
+use discv5::{Enr, Key};
+use std::collections::{HashMap, VecDeque};
+use std::marker::PhantomData;
+use std::net::{IpAddr, SocketAddr};
 use std::num::NonZeroU16;
-use tokio::sync::mpsc;
 use tokio::sync::mpsc::{Receiver, UnboundedSender};
+use tokio::sync::{mpsc, oneshot};
 use tracing::{info, warn};
 
+/// The maximum number of packets kept for replay. The oldest ones are dropped first.
+const MAX_CAPTURED_PACKETS: usize = 1024;
+
 #[derive(Debug)]
 /// A Challenge (WHOAREYOU) object used to handle and send WHOAREYOU requests.
 pub struct Challenge {
@@ -22,39 +34,105 @@ pub struct Challenge {
     pub data: ChallengeData,
     /// The remote's ENR if we know it. We can receive a challenge from an unknown node.
     pub remote_enr: Option<Enr>,
+    /// The `enr_seq` sent in the challenge.
+    pub enr_seq: u64,
+}
+
+/// A request sent by the mock, awaiting its response(s).
+struct ActiveRequest {
+    node_address: NodeAddress,
+    request: discv5::rpc::Request,
+    /// The number of NODES responses received so far.
+    received_nodes: u64,
+}
+
+/// A packet the mock has sent or received, kept as it was on the wire so that it can be replayed.
+struct CapturedPacket {
+    direction: Direction,
+    /// The peer of the packet. This is `None` for an inbound WHOAREYOU packet since it doesn't
+    /// carry the source node id.
+    node_address: Option<NodeAddress>,
+    packet: Packet,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Direction {
+    Inbound,
+    Outbound,
 }
 
 pub(crate) enum HandlerIn {
-    SendRandomPacket(NodeContact),
+    SendRandomPacket(Box<NodeContact>),
+    /// Send a random packet from each of the node ids, without capturing them.
+    SendRandomPackets(Box<NodeContact>, Vec<NodeId>),
+    /// Send a request. If no session exists, the mock initiates a handshake to establish one.
+    SendRequest(Box<NodeContact>, RequestBody),
+    /// Start a new handshake regardless of an existing session.
+    SendHandshake(Box<NodeContact>, RequestBody, HandshakeEnr),
+    AddEnr(Box<Enr>),
+    Stats(oneshot::Sender<Stats>),
+    /// Update the local ENR, replying with the updated one.
+    UpdateEnr(EnrUpdate, oneshot::Sender<Result<Enr, String>>),
+    /// Resend a captured packet to its peer as it is.
+    ReplayPacket(Replay),
+    Transcript(oneshot::Sender<Transcript>),
 }
 
-pub(crate) enum HandlerOut {}
+pub(crate) enum HandlerOut {
+    /// A response to a request sent by the mock.
+    Response(discv5::rpc::Request, discv5::rpc::Response),
+}
 
-pub(crate) struct Handler {
+/// The handler of the mock, which encodes and decodes the packets with the protocol id `P`.
+pub(crate) struct Handler<P> {
     enr: Enr,
     local_key: CombinedKey,
     node_id: NodeId,
     from_mock: mpsc::UnboundedReceiver<HandlerIn>,
+    to_mock: mpsc::Sender<HandlerOut>,
+    verifications: mpsc::Sender<Verification>,
     socket: Socket,
     behaviours: Behaviours,
     active_challenges: HashMap<NodeAddress, Challenge>,
     sessions: HashMap<NodeAddress, Session>,
     captured_requests: Vec<discv5::rpc::Request>,
+    active_requests: HashMap<RequestId, ActiveRequest>,
+    captured_responses: Vec<discv5::rpc::Response>,
+    /// Requests waiting for a WHOAREYOU to initiate a handshake, keyed by the nonce of the random
+    /// packet sent.
+    pending_handshakes: HashMap<MessageNonce, (NodeContact, discv5::rpc::Request, HandshakeEnr)>,
+    captured_packets: VecDeque<CapturedPacket>,
+    /// The number of captured packets dropped to stay within `MAX_CAPTURED_PACKETS`.
+    captured_packets_dropped: usize,
+    /// The ENRs of the remote nodes, added by the mock or received in handshakes.
+    known_enrs: HashMap<NodeId, Enr>,
+    stats: Stats,
+    transcript: Transcript,
+    _protocol_id: PhantomData<P>,
 }
 
-impl Handler {
+impl<P: ProtocolIdentity + Send + 'static> Handler<P> {
     pub(crate) async fn spawn(
         enr: Enr,
         enr_key: CombinedKey,
         config: discv5::Config,
         behaviours: Behaviours,
-    ) -> (UnboundedSender<HandlerIn>, Receiver<HandlerOut>) {
+    ) -> (
+        UnboundedSender<HandlerIn>,
+        Receiver<HandlerOut>,
+        Receiver<Verification>,
+    ) {
         let (handler_send, from_mock) = mpsc::unbounded_channel();
-        let (_to_mock, handler_recv) = mpsc::channel(50);
+        let (to_mock, handler_recv) = mpsc::channel(50);
+        let (verifications, verifications_recv) = mpsc::channel(50);
 
         let node_id = enr.node_id();
+        let request_timeout = config.request_timeout;
+        if let Some(socket_addr) = enr.udp4_socket() {
+            capture::register_mock(socket_addr.into());
+        }
 
-        let socket = Socket::new(
+        let socket = Socket::new::<P>(
             config.executor.clone().expect("Executor must exist"),
             node_id,
             config.listen_config.clone(),
@@ -66,22 +144,33 @@ impl Handler {
             .clone()
             .expect("Executor must be present")
             .spawn(Box::pin(async move {
-                let mut handler = Handler {
+                let mut handler = Handler::<P> {
                     enr,
                     local_key: enr_key,
                     node_id,
                     from_mock,
+                    to_mock,
+                    verifications,
                     socket,
                     behaviours,
                     active_challenges: HashMap::new(),
                     sessions: HashMap::new(),
                     captured_requests: vec![],
+                    active_requests: HashMap::new(),
+                    captured_responses: vec![],
+                    pending_handshakes: HashMap::new(),
+                    captured_packets: VecDeque::new(),
+                    captured_packets_dropped: 0,
+                    known_enrs: HashMap::new(),
+                    stats: Stats::default(),
+                    transcript: Transcript::new(node_id, request_timeout),
+                    _protocol_id: PhantomData,
                 };
 
                 handler.start().await;
             }));
 
-        (handler_send, handler_recv)
+        (handler_send, handler_recv, verifications_recv)
     }
 
     pub(crate) async fn start(&mut self) {
@@ -97,23 +186,112 @@ impl Handler {
         }
     }
 
-    pub(crate) async fn process_handler_request(&self, handler_request: HandlerIn) {
+    pub(crate) async fn process_handler_request(&mut self, handler_request: HandlerIn) {
         match handler_request {
             HandlerIn::SendRandomPacket(node_contact) => {
                 let packet = Packet::new_random(&self.node_id).unwrap();
-                let outbound_packet = OutboundPacket {
-                    node_address: node_contact.node_address(),
-                    packet,
-                };
-
-                if let Err(e) = self.socket.send.send(outbound_packet).await {
-                    warn!("Failed to send OutboundPacket to SendHandler: {e}");
+                self.send(node_contact.node_address(), packet, None).await;
+                self.stats.random_packets_sent += 1;
+            }
+            HandlerIn::SendRequest(node_contact, body) => {
+                if self.sessions.contains_key(&node_contact.node_address()) {
+                    self.send_request(node_contact.node_address(), body).await;
+                } else {
+                    self.start_handshake(*node_contact, body, HandshakeEnr::Auto)
+                        .await;
+                }
+            }
+            HandlerIn::SendHandshake(node_contact, body, handshake_enr) => {
+                // Drop the existing session so that the responses are decrypted with the new one.
+                self.sessions.remove(&node_contact.node_address());
+                self.start_handshake(*node_contact, body, handshake_enr)
+                    .await;
+            }
+            HandlerIn::SendRandomPackets(node_contact, node_ids) => {
+                for node_id in node_ids {
+                    let outbound_packet = OutboundPacket {
+                        node_address: node_contact.node_address(),
+                        packet: Packet::new_random(&node_id).unwrap(),
+                    };
+                    self.socket.send.send(outbound_packet).await.unwrap();
+                    self.stats.random_packets_sent += 1;
+                }
+            }
+            HandlerIn::AddEnr(enr) => {
+                self.known_enrs.insert(enr.node_id(), *enr);
+            }
+            HandlerIn::Stats(tx) => {
+                if tx.send(self.stats.clone()).is_err() {
+                    warn!("Failed to send the stats to the mock.");
+                }
+            }
+            HandlerIn::UpdateEnr(update, tx) => {
+                let result = self.update_enr(update);
+                if tx.send(result).is_err() {
+                    warn!("Failed to send the updated ENR to the mock.");
+                }
+            }
+            HandlerIn::ReplayPacket(replay) => self.replay_packet(replay).await,
+            HandlerIn::Transcript(tx) => {
+                if tx.send(self.transcript.clone()).is_err() {
+                    warn!("Failed to send the transcript to the mock.");
                 }
             }
         }
     }
 
+    fn update_enr(&mut self, update: EnrUpdate) -> Result<Enr, String> {
+        match update {
+            EnrUpdate::Seq => self
+                .enr
+                .set_seq(self.enr.seq() + 1, &self.local_key)
+                .map_err(|e| format!("Failed to update the sequence number: {e:?}"))?,
+            EnrUpdate::Field(key, value) => {
+                self.enr
+                    .insert(key.as_bytes(), &value.as_slice(), &self.local_key)
+                    .map_err(|e| format!("Failed to insert {key}: {e:?}"))?;
+            }
+            EnrUpdate::Socket(socket_addr) => self
+                .enr
+                .set_udp_socket(socket_addr, &self.local_key)
+                .map_err(|e| format!("Failed to update the socket: {e:?}"))?,
+        }
+        info!("Updated the ENR. seq:{}, enr:{}", self.enr.seq(), self.enr);
+        Ok(self.enr.clone())
+    }
+
     pub(crate) async fn process_inbound_packet(&mut self, inbound_packet: InboundPacket) {
+        self.capture_inbound_packet(&inbound_packet);
+        self.record_inbound_packet(&inbound_packet);
+        if let PacketKind::WhoAreYou { .. } = inbound_packet.header.kind {
+            self.stats.whoareyou_received += 1;
+        }
+
+        // WHOAREYOU packets in reply to the handshakes initiated by the mock are handled
+        // regardless of the behaviours.
+        if let PacketKind::WhoAreYou { enr_seq, .. } = inbound_packet.header.kind {
+            if let Some((node_contact, request, handshake_enr)) = self
+                .pending_handshakes
+                .remove(&inbound_packet.header.message_nonce)
+            {
+                self.send_handshake(
+                    &inbound_packet,
+                    enr_seq,
+                    node_contact,
+                    request,
+                    handshake_enr,
+                )
+                .await;
+                return;
+            }
+        }
+
+        // Responses to the requests sent by the mock are handled regardless of the behaviours.
+        if let Some(response) = self.decode_response(&inbound_packet) {
+            self.handle_response(node_address(&inbound_packet), response);
+            return;
+        }
+
         match self.behaviours {
             Behaviours::Declarative(_) => {
                 self.process_inbound_packet_declarative(inbound_packet)
@@ -136,12 +314,8 @@ impl Handler {
         };
 
         match inbound_packet_kind {
-            PacketKind::Message { src_id } => {
-                let node_address = NodeAddress {
-                    socket_addr: inbound_packet.src_address,
-                    node_id: src_id,
-                };
-                if self.sessions.contains_key(&node_address) {
+            PacketKind::Message { .. } => {
+                if self.has_session(&inbound_packet) {
                     self.do_actions(inbound_packet, behaviour.message.clone())
                         .await;
                 } else {
@@ -164,134 +338,243 @@ impl Handler {
         &mut self,
         inbound_packet: InboundPacket,
     ) {
-        let inbound_packet_kind = inbound_packet.header.kind.clone();
-        let behaviours = match self.behaviours {
-            Behaviours::Declarative(_) => unreachable!(),
-            Behaviours::Sequential(ref mut behaviours) => behaviours,
-        };
-        macro_rules! next_behaviour {
-            () => {{
-                behaviours.pop_front().expect(
-                    format!("No behaviour. inbound_packet:{:?}", inbound_packet_kind).as_str(),
-                )
-            }};
+        match self.next_sequential_actions(&inbound_packet) {
+            Ok(actions) => self.do_actions(inbound_packet, actions).await,
+            Err(e) => panic!("{e}"),
         }
+    }
 
-        match inbound_packet.header.kind.clone() {
-            PacketKind::WhoAreYou { id_nonce, .. } => {
-                let behaviour = next_behaviour!();
-                match behaviour.expect {
-                    Expect::WhoAreYou => {
-                        info!("Received WHOAREYOU packet. id_nonce:{:?}", id_nonce)
-                    }
-                    _ => panic!(
-                        "Unexpected inbound packet. expected:{:?}, actual:{:?}",
-                        behaviour.expect, inbound_packet.header.kind
-                    ),
-                }
+    /// Finds the step which the inbound packet matches, advancing the steps, and returns the
+    /// actions to do.
+    fn next_sequential_actions(
+        &mut self,
+        inbound_packet: &InboundPacket,
+    ) -> Result<Vec<Action>, String> {
+        loop {
+            let steps = match &self.behaviours {
+                Behaviours::Declarative(_) => unreachable!(),
+                Behaviours::Sequential(steps) => steps,
+            };
+            let step = steps.front().ok_or_else(|| {
+                format!(
+                    "No behaviour. inbound_packet:{:?}",
+                    inbound_packet.header.kind
+                )
+            })?;
 
-                self.do_actions(inbound_packet, behaviour.actions).await;
-            }
-            PacketKind::Handshake {
-                src_id: _,
-                id_nonce_sig: _,
-                ephem_pubkey,
-                enr_record,
-            } => {
-                let behaviour = next_behaviour!();
-                let expected_request_kind = match behaviour.expect {
-                    Expect::Handshake(expected_request_kind) => {
-                        info!("Received Handshake.");
-                        expected_request_kind
+            match step {
+                Step::Ordered(behaviour) => {
+                    self.match_expect(&behaviour.expect, inbound_packet)
+                        .map_err(|e| {
+                            format!(
+                                "Unexpected inbound packet. expected:{:?}, {e}",
+                                behaviour.expect
+                            )
+                        })?;
+                    log_matched(&behaviour.expect, inbound_packet);
+                    match self.steps_mut().pop_front() {
+                        Some(Step::Ordered(behaviour)) => return Ok(behaviour.actions),
+                        _ => unreachable!(),
                     }
-                    _ => panic!(
-                        "Unexpected inbound packet. expected:{:?}, actual:{:?}",
-                        behaviour.expect, inbound_packet_kind
-                    ),
-                };
+                }
+                Step::Unordered(group) => {
+                    let matched = group.iter().position(|b| {
+                        b.matched < b.max
+                            && self
+                                .match_expect(&b.behaviour.expect, inbound_packet)
+                                .is_ok()
+                    });
+                    let unsatisfied = group.iter().find(|b| b.matched < b.min).map(|b| {
+                        format!(
+                            "Unexpected inbound packet. expected:{:?} at least {} times but matched {} times, actual:{:?}",
+                            b.behaviour.expect, b.min, b.matched, inbound_packet.header.kind
+                        )
+                    });
 
-                {
-                    let node_address = node_address(&inbound_packet);
-                    let challenge = self.active_challenges.get(&node_address).unwrap();
-                    let (session, _) = Session::establish_from_challenge(
-                        &self.local_key,
-                        &self.node_id,
-                        &node_address.node_id,
-                        challenge,
-                        &ephem_pubkey,
-                        enr_record,
-                    )
-                    .unwrap();
-                    let request = match decode_message(&session, &inbound_packet) {
-                        Message::Request(request) => request,
-                        _ => unreachable!(),
-                    };
-                    if !check_request_kind(&request, &expected_request_kind) {
-                        panic!("Unexpected request kind. expected:{expected_request_kind:?}, actual:{request:?}");
+                    let steps = self.steps_mut();
+                    match (matched, unsatisfied) {
+                        (Some(index), _) => {
+                            let group = match steps.front_mut() {
+                                Some(Step::Unordered(group)) => group,
+                                _ => unreachable!(),
+                            };
+                            log_matched(&group[index].behaviour.expect, inbound_packet);
+                            group[index].matched += 1;
+                            let actions = group[index].behaviour.actions.clone();
+                            if group.iter().all(|b| b.matched >= b.max) {
+                                steps.pop_front();
+                            }
+                            return Ok(actions);
+                        }
+                        // The group is complete, so match the packet against the next step.
+                        (None, None) => {
+                            steps.pop_front();
+                        }
+                        (None, Some(e)) => return Err(e),
                     }
                 }
+            }
+        }
+    }
 
-                self.do_actions(inbound_packet, behaviour.actions).await;
+    fn steps_mut(&mut self) -> &mut VecDeque<Step> {
+        match self.behaviours {
+            Behaviours::Declarative(_) => unreachable!(),
+            Behaviours::Sequential(ref mut steps) => steps,
+        }
+    }
+
+    /// Checks whether the inbound packet matches the expectation, without changing any state.
+    fn match_expect(&self, expect: &Expect, inbound_packet: &InboundPacket) -> Result<(), String> {
+        let actual = &inbound_packet.header.kind;
+        match (expect, actual) {
+            (Expect::Any, _) => Ok(()),
+            (Expect::WhoAreYou, PacketKind::WhoAreYou { .. }) => Ok(()),
+            (Expect::MessageWithoutSession, PacketKind::Message { .. }) => {
+                if self.sessions.contains_key(&node_address(inbound_packet)) {
+                    return Err("actual:SessionExists".to_string());
+                }
+                Ok(())
             }
-            PacketKind::Message { src_id } => {
-                let node_address = NodeAddress {
-                    socket_addr: inbound_packet.src_address,
-                    node_id: src_id,
-                };
-                let behaviour = next_behaviour!();
-
-                // Expect
-                match behaviour.expect {
-                    Expect::MessageWithoutSession => {
-                        // Check session existence
-                        if self.sessions.contains_key(&node_address) {
-                            panic!("Unexpected inbound packet. expected:MessageWithoutSession, actual:SessionExists");
-                        }
-                        info!("Received Message without session.");
+            (Expect::Message(expected_request), PacketKind::Message { .. }) => {
+                let session = self
+                    .sessions
+                    .get(&node_address(inbound_packet))
+                    .ok_or("Session does not exist.")?;
+                match try_decode_message(session, inbound_packet)? {
+                    Message::Request(request) if check_request(&request, expected_request) => {
+                        Ok(())
                     }
-                    Expect::Message(expected_request) => {
-                        // Check if we have a session.
-                        if let Some(session) = self.sessions.get(&node_address) {
-                            match decode_message(session, &inbound_packet) {
-                                Message::Request(request) => {
-                                    if !check_request_kind(&request, &expected_request) {
-                                        panic!("Unexpected request. {:?}", request);
-                                    }
-                                }
-                                Message::Response(_) => todo!(),
-                                // Message::RelayInitNotification(_) => todo!(),
-                                // Message::RelayMsgNotification(_) => todo!(),
-                            }
-                        } else {
-                            panic!("Session does not exist.")
-                        }
+                    message => Err(format!("actual:{message:?}")),
+                }
+            }
+            (Expect::Handshake(expected_request), PacketKind::Handshake { .. }) => {
+                match self.decode_handshake_message(inbound_packet)? {
+                    Message::Request(request) if check_request(&request, expected_request) => {
+                        Ok(())
                     }
-                    _ => panic!(
-                        "Unexpected inbound packet. expected:{:?}, actual:{:?}",
-                        behaviour.expect, inbound_packet_kind
-                    ),
+                    message => Err(format!("actual:{message:?}")),
                 }
+            }
+            _ => Err(format!("actual:{actual:?}")),
+        }
+    }
 
-                // Action
-                self.do_actions(inbound_packet, behaviour.actions).await;
-            } // PacketKind::SessionMessage { .. } => todo!(),
+    /// Decodes the message in a handshake packet with a session established tentatively from the
+    /// active challenge. The actual one is established by `Action::EstablishSession`.
+    fn decode_handshake_message(&self, inbound_packet: &InboundPacket) -> Result<Message, String> {
+        let (ephem_pubkey, enr_record) = match &inbound_packet.header.kind {
+            PacketKind::Handshake {
+                ephem_pubkey,
+                enr_record,
+                ..
+            } => (ephem_pubkey, enr_record),
+            _ => return Err("Not a handshake packet.".to_string()),
+        };
+        let node_address = node_address(inbound_packet);
+        let challenge = self
+            .active_challenges
+            .get(&node_address)
+            .ok_or("No active challenge.")?;
+        let (session, _) = Session::establish_from_challenge(
+            &self.local_key,
+            &self.node_id,
+            &node_address.node_id,
+            challenge,
+            ephem_pubkey,
+            enr_record.clone(),
+        )?;
+        try_decode_message(&session, inbound_packet)
+    }
+
+    fn record_inbound_packet(&mut self, inbound_packet: &InboundPacket) {
+        let (node_id, message) = match inbound_packet.header.kind {
+            PacketKind::WhoAreYou { .. } => (None, None),
+            PacketKind::Message { .. } => (
+                Some(node_address(inbound_packet).node_id),
+                self.sessions
+                    .get(&node_address(inbound_packet))
+                    .and_then(|session| try_decode_message(session, inbound_packet).ok()),
+            ),
+            PacketKind::Handshake { .. } => (
+                Some(node_address(inbound_packet).node_id),
+                self.decode_handshake_message(inbound_packet).ok(),
+            ),
+        };
+        self.transcript.record(
+            &Direction::Inbound,
+            node_id,
+            inbound_packet.src_address,
+            &inbound_packet.header.kind,
+            message.as_ref(),
+        );
+        if capture::is_enabled() {
+            capture::record_mock_packet(
+                capture::Direction::Inbound,
+                self.local_socket(),
+                inbound_packet.src_address,
+                node_id,
+                &inbound_packet.header,
+                message.as_ref(),
+                // The authenticated data consists of the IV and the header.
+                inbound_packet.authenticated_data.len() + inbound_packet.message.len(),
+            );
         }
     }
 
+    /// The socket of the mock, as advertised in its ENR.
+    fn local_socket(&self) -> SocketAddr {
+        self.enr
+            .udp4_socket()
+            .map(SocketAddr::from)
+            .unwrap_or_else(|| SocketAddr::from(([0, 0, 0, 0], 0)))
+    }
+
     async fn do_actions(&mut self, inbound_packet: InboundPacket, actions: Vec<Action>) {
         for action in actions {
             match action {
                 Action::Ignore(reason) => info!("Ignoring a packet. reason:{reason}"),
-                Action::SendWhoAreYou => self.send_challenge(&inbound_packet).await,
+                Action::SendWhoAreYou => {
+                    self.send_challenge(&inbound_packet, ChallengeEnrSeq::Zero)
+                        .await
+                }
+                Action::SendWhoAreYouWithEnrSeq(enr_seq) => {
+                    self.send_challenge(&inbound_packet, enr_seq).await
+                }
                 Action::EstablishSession => match inbound_packet.header.kind.clone() {
                     PacketKind::Handshake {
                         src_id: _,
-                        id_nonce_sig: _,
+                        id_nonce_sig,
                         ephem_pubkey,
                         enr_record,
                     } => {
                         let node_address = node_address(&inbound_packet);
                         if let Some(challenge) = self.active_challenges.remove(&node_address) {
+                            let result = verify_handshake_enr(
+                                &node_address,
+                                &challenge,
+                                enr_record.as_ref(),
+                            );
+                            self.report(Verification {
+                                node_address: node_address.clone(),
+                                kind: VerificationKind::HandshakeEnr,
+                                result,
+                            });
+                            // The session is established even if the signature is invalid, so
+                            // that the test can go on and see what happens next.
+                            let result = verify_id_nonce_sig(
+                                &self.node_id,
+                                &node_address,
+                                &challenge,
+                                enr_record.as_ref(),
+                                &ephem_pubkey,
+                                &id_nonce_sig,
+                            );
+                            self.report(Verification {
+                                node_address: node_address.clone(),
+                                kind: VerificationKind::IdNonceSignature,
+                                result,
+                            });
                             self.establish_session(
                                 node_address.clone(),
                                 challenge,
@@ -316,10 +599,20 @@ impl Handler {
                         };
                         match response {
                             mock::Response::Default => {
-                                self.send_default_response(node_address, request).await
+                                self.send_default_response(node_address, request, false)
+                                    .await
+                            }
+                            mock::Response::DefaultWithObservedSocket => {
+                                self.send_default_response(node_address, request, true)
+                                    .await
                             }
                             mock::Response::Custom(responses) => {
-                                self.send_custom_responses(node_address, responses).await
+                                self.send_custom_responses(node_address, &request, responses)
+                                    .await
+                            }
+                            mock::Response::Nodes(response) => {
+                                self.send_nodes_response(node_address, &request, response)
+                                    .await
                             }
                         }
                     } else {
@@ -327,6 +620,9 @@ impl Handler {
                     }
                 }
                 Action::CaptureRequest => self.capture_request(&inbound_packet),
+                Action::SendRequest(body) => {
+                    self.send_request(node_address(&inbound_packet), body).await
+                }
             }
         }
     }
@@ -349,28 +645,282 @@ impl Handler {
         }
     }
 
-    async fn send_challenge(&mut self, inbound_packet: &InboundPacket) {
+    fn decode_response(&self, inbound_packet: &InboundPacket) -> Option<discv5::rpc::Response> {
+        if !matches!(inbound_packet.header.kind, PacketKind::Message { .. }) {
+            return None;
+        }
+
+        let session = self.sessions.get(&node_address(inbound_packet))?;
+        match try_decode_message(session, inbound_packet) {
+            Ok(Message::Response(response)) => Some(response),
+            _ => None,
+        }
+    }
+
+    fn handle_response(&mut self, node_address: NodeAddress, response: discv5::rpc::Response) {
+        let active_request = match self.active_requests.get_mut(&response.id) {
+            Some(active_request) => active_request,
+            None => return warn!("Received a response to an unknown request. {response:?}"),
+        };
+
+        if active_request.node_address != node_address {
+            return warn!(
+                "Received a response from an unexpected node. expected:{}, actual:{}, response:{:?}",
+                active_request.node_address, node_address, response
+            );
+        }
+
+        // A NODES response can be split into multiple packets, so wait until all of them have
+        // been received before removing the request.
+        let request = active_request.request.clone();
+        let completed = match &response.body {
+            ResponseBody::Nodes { total, .. } => {
+                active_request.received_nodes += 1;
+                active_request.received_nodes >= *total
+            }
+            _ => true,
+        };
+        if completed {
+            self.active_requests.remove(&response.id);
+        }
+
+        info!("Received a response. {response:?}");
+        self.captured_responses.push(response.clone());
+        // Don't block the handler even if nobody receives the responses.
+        if let Err(e) = self
+            .to_mock
+            .try_send(HandlerOut::Response(request, response))
+        {
+            warn!("Failed to send the response to the mock: {e}");
+        }
+    }
+
+    async fn send_request(&mut self, node_address: NodeAddress, body: RequestBody) {
+        let request = discv5::rpc::Request {
+            id: RequestId::random(),
+            body,
+        };
+
+        let packet = if let Some(session) = self.sessions.get_mut(&node_address) {
+            session.encrypt_message::<P>(self.node_id, &request.clone().encode())
+        } else {
+            return warn!(
+                "Session is not established. Dropping request {} for node: {}",
+                request, node_address.node_id
+            );
+        };
+
+        match packet {
+            Ok(packet) => {
+                info!("Sending a request to {}. {:?}", node_address, request);
+                let message = Message::Request(request.clone());
+                self.active_requests.insert(
+                    request.id.clone(),
+                    ActiveRequest {
+                        node_address: node_address.clone(),
+                        request,
+                        received_nodes: 0,
+                    },
+                );
+                self.send(node_address, packet, Some(&message)).await;
+            }
+            Err(e) => warn!("Could not encrypt request: {:?}", e),
+        }
+    }
+
+    /// Sends a random packet to let the remote node send a WHOAREYOU, and then the request is sent
+    /// with the handshake.
+    async fn start_handshake(
+        &mut self,
+        node_contact: NodeContact,
+        body: RequestBody,
+        handshake_enr: HandshakeEnr,
+    ) {
+        let request = discv5::rpc::Request {
+            id: RequestId::random(),
+            body,
+        };
+        let packet = Packet::new_random(&self.node_id).unwrap();
+
+        info!(
+            "Starting a handshake with {}. {:?}",
+            node_contact.node_address(),
+            request
+        );
+        self.pending_handshakes.insert(
+            packet.header.message_nonce,
+            (node_contact.clone(), request, handshake_enr),
+        );
+        self.send(node_contact.node_address(), packet, None).await;
+    }
+
+    async fn send_handshake(
+        &mut self,
+        inbound_packet: &InboundPacket,
+        enr_seq: u64,
+        node_contact: NodeContact,
+        request: discv5::rpc::Request,
+        handshake_enr: HandshakeEnr,
+    ) {
+        let challenge_data = ChallengeData::try_from(inbound_packet.authenticated_data.as_slice())
+            .expect("Must be the correct challenge size");
+
+        let enr_record = match handshake_enr {
+            // Attach our ENR if the remote node doesn't know the latest one.
+            HandshakeEnr::Auto if enr_seq < self.enr.seq() => Some(self.enr.clone()),
+            HandshakeEnr::Auto | HandshakeEnr::Omit => None,
+            HandshakeEnr::Custom(enr) => Some(*enr),
+        };
+
+        match Session::encrypt_with_header::<P>(
+            &self.local_key,
+            self.node_id,
+            &node_contact,
+            &challenge_data,
+            enr_record,
+            &request.clone().encode(),
+        ) {
+            Ok((packet, session)) => {
+                let node_address = node_contact.node_address();
+                info!("Sending handshake to {}", node_address);
+                let message = Message::Request(request.clone());
+                self.sessions.insert(node_address.clone(), session);
+                self.active_requests.insert(
+                    request.id.clone(),
+                    ActiveRequest {
+                        node_address: node_address.clone(),
+                        request,
+                        received_nodes: 0,
+                    },
+                );
+                self.send(node_address, packet, Some(&message)).await;
+            }
+            Err(e) => warn!("Failed to build a handshake packet: {e}"),
+        }
+    }
+
+    fn capture_inbound_packet(&mut self, inbound_packet: &InboundPacket) {
+        // The authenticated data begins with the IV of the packet.
+        let mut iv = [0u8; 16];
+        iv.copy_from_slice(&inbound_packet.authenticated_data[..16]);
+
+        let node_address = match inbound_packet.header.kind {
+            PacketKind::WhoAreYou { .. } => None,
+            _ => Some(node_address(inbound_packet)),
+        };
+
+        self.capture_packet(CapturedPacket {
+            direction: Direction::Inbound,
+            node_address,
+            packet: Packet {
+                iv: u128::from_be_bytes(iv),
+                header: inbound_packet.header.clone(),
+                message: inbound_packet.message.clone(),
+            },
+        });
+    }
+
+    fn capture_packet(&mut self, captured_packet: CapturedPacket) {
+        if self.captured_packets.len() == MAX_CAPTURED_PACKETS {
+            self.captured_packets.pop_front();
+            self.captured_packets_dropped += 1;
+        }
+        self.captured_packets.push_back(captured_packet);
+    }
+
+    async fn replay_packet(&mut self, replay: Replay) {
+        let captured_packet = match &replay {
+            Replay::Index(index) => index
+                .checked_sub(self.captured_packets_dropped)
+                .and_then(|index| self.captured_packets.get(index)),
+            Replay::LastSent(packet_type) => self.captured_packets.iter().rev().find(|p| {
+                p.direction == Direction::Outbound
+                    && PacketType::from(&p.packet.header.kind) == *packet_type
+            }),
+        };
+
+        let (node_address, packet) = match captured_packet {
+            Some(CapturedPacket {
+                node_address: Some(node_address),
+                packet,
+                ..
+            }) => (node_address.clone(), packet.clone()),
+            Some(_) => return warn!("The captured packet has no peer to replay to. {replay:?}"),
+            None => return warn!("No captured packet to replay. {replay:?}"),
+        };
+
+        info!(
+            "Replaying a packet to {}. nonce:{:?}",
+            node_address, packet.header.message_nonce
+        );
+        // Replayed packets are not captured again so that they don't change the indices.
+        self.transcript.record_replay(
+            node_address.node_id,
+            node_address.socket_addr,
+            &packet.header.kind,
+        );
+        let outbound_packet = OutboundPacket {
+            node_address,
+            packet,
+        };
+        if let Err(e) = self.socket.send.send(outbound_packet).await {
+            warn!("Failed to replay the packet: {e}");
+        }
+    }
+
+    async fn send_challenge(&mut self, inbound_packet: &InboundPacket, enr_seq: ChallengeEnrSeq) {
         let node_address = node_address(inbound_packet);
+        let remote_enr = self.known_enrs.get(&node_address.node_id).cloned();
+        let enr_seq = match (enr_seq, &remote_enr) {
+            (ChallengeEnrSeq::Zero, _) => 0,
+            (ChallengeEnrSeq::Known, Some(enr)) => enr.seq(),
+            (ChallengeEnrSeq::Stale, Some(enr)) => enr.seq().saturating_sub(1),
+            (ChallengeEnrSeq::Future, Some(enr)) => enr.seq() + 1,
+            (ChallengeEnrSeq::Fixed(enr_seq), _) => enr_seq,
+            (enr_seq, None) => {
+                warn!(
+                    "The ENR of {} is unknown. Sending zero instead of {:?}.",
+                    node_address, enr_seq
+                );
+                0
+            }
+        };
         let id_nonce: IdNonce = rand::random();
-        let packet = Packet::new_whoareyou(inbound_packet.header.message_nonce, id_nonce, 0);
-        let challenge_data =
-            ChallengeData::try_from(packet.authenticated_data::<DefaultProtocolId>().as_slice())
-                .expect("Must be the correct challenge size");
+        let packet = Packet::new_whoareyou(inbound_packet.header.message_nonce, id_nonce, enr_seq);
+        let challenge_data = ChallengeData::try_from(packet.authenticated_data::<P>().as_slice())
+            .expect("Must be the correct challenge size");
 
-        info!("Sending WHOAREYOU to {}", node_address);
-        self.send(node_address.clone(), packet).await;
+        info!("Sending WHOAREYOU to {}. enr_seq:{}", node_address, enr_seq);
+        self.send(node_address.clone(), packet, None).await;
         self.active_challenges.insert(
             node_address,
             Challenge {
                 data: challenge_data,
-                remote_enr: None,
+                remote_enr,
+                enr_seq,
             },
         );
     }
 
+    /// Whether a session exists with the node which has sent the packet, and the packet can be
+    /// decrypted with it.
+    fn has_session(&self, inbound_packet: &InboundPacket) -> bool {
+        has_session(&self.sessions, inbound_packet)
+    }
+
+    fn report(&self, verification: Verification) {
+        if let Err(e) = &verification.result {
+            warn!("Verification failed. {:?}: {e}", verification.kind);
+        }
+        // Don't block the handler even if nobody receives the results.
+        if let Err(e) = self.verifications.try_send(verification) {
+            warn!("Failed to send the verification to the mock: {e}");
+        }
+    }
+
     async fn send_response(&mut self, node_address: NodeAddress, response: discv5::rpc::Response) {
         let packet = if let Some(session) = self.sessions.get_mut(&node_address) {
-            session.encrypt_message(self.node_id, &response.encode())
+            session.encrypt_message::<P>(self.node_id, &response.clone().encode())
         } else {
             return warn!(
                 "Session is not established. Dropping response {} for node: {}",
@@ -379,12 +929,41 @@ impl Handler {
         };
 
         match packet {
-            Ok(packet) => self.send(node_address, packet).await,
+            Ok(packet) => {
+                let message = Message::Response(response);
+                self.send(node_address, packet, Some(&message)).await
+            }
             Err(e) => warn!("Could not encrypt response: {:?}", e),
         }
     }
 
-    async fn send(&mut self, node_address: NodeAddress, packet: Packet) {
+    /// Sends a packet, capturing it for replay and recording it in the transcript along with the
+    /// message it carries, if any.
+    async fn send(&mut self, node_address: NodeAddress, packet: Packet, message: Option<&Message>) {
+        self.transcript.record(
+            &Direction::Outbound,
+            Some(node_address.node_id),
+            node_address.socket_addr,
+            &packet.header.kind,
+            message,
+        );
+        if capture::is_enabled() {
+            capture::record_mock_packet(
+                capture::Direction::Outbound,
+                self.local_socket(),
+                node_address.socket_addr,
+                Some(node_address.node_id),
+                &packet.header,
+                message,
+                packet.clone().encode::<P>(&node_address.node_id).len(),
+            );
+        }
+        self.capture_packet(CapturedPacket {
+            direction: Direction::Outbound,
+            node_address: Some(node_address.clone()),
+            packet: packet.clone(),
+        });
+
         let outbound_packet = OutboundPacket {
             node_address,
             packet,
@@ -396,38 +975,85 @@ impl Handler {
         &mut self,
         node_address: NodeAddress,
         request: discv5::rpc::Request,
+        observed_socket: bool,
     ) {
         match request.body {
             RequestBody::Ping { .. } => {
+                let socket_addr = if observed_socket {
+                    node_address.socket_addr
+                } else {
+                    SocketAddr::new(
+                        IpAddr::from(self.enr.ip4().unwrap()),
+                        self.enr.udp4().unwrap(),
+                    )
+                };
                 self.send_response(
                     node_address,
                     discv5::rpc::Response {
                         id: request.id,
                         body: discv5::rpc::ResponseBody::Pong {
                             enr_seq: self.enr.seq(),
-                            ip: IpAddr::from(self.enr.ip4().unwrap()),
-                            port: NonZeroU16::new(self.enr.udp4().unwrap()).unwrap(),
+                            ip: socket_addr.ip(),
+                            port: NonZeroU16::new(socket_addr.port()).unwrap(),
                         },
                     },
                 )
                 .await;
             }
-            RequestBody::FindNode { .. } => todo!(),
-            RequestBody::Talk { .. } => todo!(),
+            RequestBody::FindNode { ref distances } => {
+                // Answer with the local ENR for distance 0, and the known ENRs at the other
+                // distances.
+                let local_key: Key<NodeId> = self.node_id.into();
+                let mut nodes = vec![];
+                for distance in distances.iter() {
+                    if *distance == 0 {
+                        nodes.push(self.enr.clone());
+                        self.stats.enr_requests_answered += 1;
+                    } else {
+                        nodes.extend(
+                            self.known_enrs
+                                .values()
+                                .filter(|enr| {
+                                    local_key.log2_distance(&enr.node_id().into())
+                                        == Some(*distance)
+                                })
+                                .cloned(),
+                        );
+                    }
+                }
+                self.send_nodes_response(
+                    node_address,
+                    &request,
+                    NodesResponse {
+                        id: CustomResponseId::InboundRequestId,
+                        nodes,
+                        total: NodesTotal::Actual,
+                        packet_size: PacketSize::Limited,
+                    },
+                )
+                .await;
+            }
+            RequestBody::Talk { .. } => {
+                self.send_response(
+                    node_address,
+                    discv5::rpc::Response {
+                        id: request.id,
+                        body: ResponseBody::Talk { response: vec![] },
+                    },
+                )
+                .await;
+            }
         }
     }
 
     async fn send_custom_responses(
         &mut self,
         node_address: NodeAddress,
+        request: &discv5::rpc::Request,
         responses: Vec<CustomResponse>,
     ) {
         for res in responses {
-            let id = match res.id {
-                CustomResponseId::CapturedRequestId(index) => {
-                    self.captured_requests.get(index).unwrap().id.clone()
-                }
-            };
+            let id = self.response_id(&res.id, request);
             self.send_response(
                 node_address.clone(),
                 discv5::rpc::Response { id, body: res.body },
@@ -436,6 +1062,66 @@ impl Handler {
         }
     }
 
+    async fn send_nodes_response(
+        &mut self,
+        node_address: NodeAddress,
+        request: &discv5::rpc::Request,
+        response: NodesResponse,
+    ) {
+        let id = self.response_id(&response.id, request);
+        let packets = match response.packet_size {
+            PacketSize::Limited => split_nodes(response.nodes),
+            PacketSize::Unlimited => vec![response.nodes],
+        };
+        let total = match response.total {
+            NodesTotal::Actual => packets.len() as u64,
+            NodesTotal::Fixed(total) => total,
+        };
+
+        for nodes in packets {
+            self.send_response(
+                node_address.clone(),
+                discv5::rpc::Response {
+                    id: id.clone(),
+                    body: ResponseBody::Nodes { total, nodes },
+                },
+            )
+            .await;
+        }
+    }
+
+    /// Resolves the id of a response to the `request`, which is the inbound request being
+    /// responded to.
+    fn response_id(&self, id: &CustomResponseId, request: &discv5::rpc::Request) -> RequestId {
+        match id {
+            CustomResponseId::InboundRequestId => request.id.clone(),
+            CustomResponseId::CapturedRequestId(index) => self.captured_request(*index).id.clone(),
+            CustomResponseId::NextRequestId(index) => {
+                let mut id = self.captured_request(*index).id.0.clone();
+                // Increment as a big-endian integer, carrying over the overflowed bytes.
+                for byte in id.iter_mut().rev() {
+                    let (incremented, overflowed) = byte.overflowing_add(1);
+                    *byte = incremented;
+                    if !overflowed {
+                        break;
+                    }
+                }
+                RequestId(id)
+            }
+            CustomResponseId::Raw(id) => RequestId(id.clone()),
+            CustomResponseId::Random => RequestId::random(),
+        }
+    }
+
+    fn captured_request(&self, index: usize) -> &discv5::rpc::Request {
+        self.captured_requests.get(index).unwrap_or_else(|| {
+            panic!(
+                "The request {index} has not been captured, only {} have been.",
+                self.captured_requests.len()
+            )
+        })
+    }
+
     async fn establish_session(
         &mut self,
         node_address: NodeAddress,
@@ -451,7 +1137,10 @@ impl Handler {
             ephem_pubkey,
             enr_record,
         ) {
-            Ok((session, _enr)) => {
+            Ok((session, enr)) => {
+                if let Some(enr) = enr {
+                    self.known_enrs.insert(node_address.node_id, enr);
+                }
                 self.sessions.insert(node_address, session);
             }
             Err(error) => panic!("{}", error),
@@ -461,23 +1150,186 @@ impl Handler {
     }
 }
 
+fn has_session(sessions: &HashMap<NodeAddress, Session>, inbound_packet: &InboundPacket) -> bool {
+    match sessions.get(&node_address(inbound_packet)) {
+        Some(session) => try_decode_message(session, inbound_packet).is_ok(),
+        None => false,
+    }
+}
+
+/// Checks the ENR attached to, or left out of, a handshake against the challenge. The ENR must be
+/// attached if, and only if, the `enr_seq` in the challenge is lower than that of the ENR, and it
+/// must match the node which has sent the handshake. Since the mock only knows the ENR it has been
+/// told about, a missing ENR is detected if the challenge is older than the known one.
+fn verify_handshake_enr(
+    node_address: &NodeAddress,
+    challenge: &Challenge,
+    enr_record: Option<&Enr>,
+) -> Result<(), String> {
+    let enr = match enr_record {
+        Some(enr) => enr,
+        None if challenge.enr_seq == 0 => {
+            return Err("The ENR is left out although the challenge has enr_seq zero.".to_string())
+        }
+        None => match &challenge.remote_enr {
+            Some(known_enr) if challenge.enr_seq < known_enr.seq() => {
+                return Err(format!(
+                    "The ENR is left out although the challenge is older than the known one. challenge_enr_seq:{}, known_enr_seq:{}",
+                    challenge.enr_seq,
+                    known_enr.seq()
+                ))
+            }
+            _ => return Ok(()),
+        },
+    };
+
+    if enr.node_id() != node_address.node_id {
+        return Err(format!(
+            "The node id of the ENR doesn't match. expected:{}, actual:{}",
+            node_address.node_id,
+            enr.node_id()
+        ));
+    }
+    if enr.seq() <= challenge.enr_seq {
+        return Err(format!(
+            "The ENR is attached although it isn't newer than the challenge. enr_seq:{}, challenge_enr_seq:{}",
+            enr.seq(),
+            challenge.enr_seq
+        ));
+    }
+    if let Some(known_enr) = &challenge.remote_enr {
+        if enr.seq() < known_enr.seq() {
+            return Err(format!(
+                "The ENR is older than the known one. enr_seq:{}, known_enr_seq:{}",
+                enr.seq(),
+                known_enr.seq()
+            ));
+        }
+    }
+    if let Some(socket_addr) = enr.udp4_socket() {
+        if SocketAddr::V4(socket_addr) != node_address.socket_addr {
+            return Err(format!(
+                "The ENR doesn't match the source socket. enr:{}, source:{}",
+                socket_addr, node_address.socket_addr
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Verifies the id-nonce signature in a handshake. The public key of the initiator is taken from
+/// the newer one of the attached ENR and the known ENR, as discv5 does.
+fn verify_id_nonce_sig(
+    local_id: &NodeId,
+    node_address: &NodeAddress,
+    challenge: &Challenge,
+    enr_record: Option<&Enr>,
+    ephem_pubkey: &[u8],
+    id_nonce_sig: &[u8],
+) -> Result<(), String> {
+    let enr = match (enr_record, challenge.remote_enr.as_ref()) {
+        (Some(new_enr), Some(known_enr)) if new_enr.seq() <= known_enr.seq() => known_enr,
+        (Some(new_enr), _) => new_enr,
+        (None, Some(known_enr)) => known_enr,
+        (None, None) => return Err("No ENR to get the public key from.".to_string()),
+    };
+
+    if enr.node_id() != node_address.node_id {
+        return Err(format!(
+            "The ENR belongs to another node. expected:{}, actual:{}",
+            node_address.node_id,
+            enr.node_id()
+        ));
+    }
+
+    verify_nonce(
+        &enr.public_key(),
+        ephem_pubkey,
+        &challenge.data,
+        local_id,
+        id_nonce_sig,
+    )
+}
+
 fn decode_message(session: &Session, inbound_packet: &InboundPacket) -> discv5::rpc::Message {
+    try_decode_message(session, inbound_packet).unwrap()
+}
+
+fn try_decode_message(
+    session: &Session,
+    inbound_packet: &InboundPacket,
+) -> Result<discv5::rpc::Message, String> {
     // Decrypt the message
-    let message = session
-        .decrypt_message(
-            inbound_packet.header.message_nonce,
-            &inbound_packet.message,
-            &inbound_packet.authenticated_data,
-        )
-        .expect("Decrypt message");
+    let message = session.decrypt_message(
+        inbound_packet.header.message_nonce,
+        &inbound_packet.message,
+        &inbound_packet.authenticated_data,
+    )?;
+
+    discv5::rpc::Message::decode(&message).map_err(|e| format!("Failed to decode message: {e:?}"))
+}
 
-    discv5::rpc::Message::decode(&message).unwrap()
+/// Splits the ENRs into groups which fit in a single packet. This follows the way discv5 builds
+/// NODES responses in `Service::send_nodes_response`.
+fn split_nodes(nodes: Vec<Enr>) -> Vec<Vec<Enr>> {
+    // The estimated total overhead for a regular message. See discv5 for the details.
+    const MESSAGE_OVERHEAD: usize = 104;
+
+    let mut packets: Vec<Vec<Enr>> = vec![vec![]];
+    let mut total_size = 0;
+    for enr in nodes {
+        let entry_size = enr.size();
+        if entry_size + total_size < MAX_PACKET_SIZE - MESSAGE_OVERHEAD {
+            total_size += entry_size;
+            packets.last_mut().expect("Must have a packet").push(enr);
+        } else {
+            total_size = entry_size;
+            packets.push(vec![enr]);
+        }
+    }
+    packets
 }
 
-fn check_request_kind(request: &discv5::rpc::Request, expected: &Request) -> bool {
-    match expected {
-        Request::FindNode => matches!(request.body, RequestBody::FindNode { .. }),
-        Request::Ping => matches!(request.body, RequestBody::Ping { .. }),
+/// Logs the inbound packet which a step has been matched with.
+fn log_matched(expect: &Expect, inbound_packet: &InboundPacket) {
+    match (expect, &inbound_packet.header.kind) {
+        (Expect::WhoAreYou, PacketKind::WhoAreYou { id_nonce, .. }) => {
+            info!("Received WHOAREYOU packet. id_nonce:{:?}", id_nonce);
+        }
+        (Expect::MessageWithoutSession, _) => info!("Received Message without session."),
+        (Expect::Handshake(_), _) => info!("Received Handshake."),
+        _ => {}
+    }
+}
+
+fn check_request(request: &discv5::rpc::Request, expected: &Request) -> bool {
+    match (expected, &request.body) {
+        (Request::FindNode, RequestBody::FindNode { .. }) => true,
+        (Request::FindNodeDistances(expected), RequestBody::FindNode { distances }) => {
+            let mut expected = expected.clone();
+            let mut distances = distances.clone();
+            expected.sort_unstable();
+            distances.sort_unstable();
+            expected == distances
+        }
+        (Request::Ping, RequestBody::Ping { .. }) => true,
+        (Request::PingEnrSeq(expected), RequestBody::Ping { enr_seq }) => expected == enr_seq,
+        (
+            Request::TalkWith {
+                protocol: expected_protocol,
+                request: expected_request,
+            },
+            RequestBody::Talk { protocol, request },
+        ) => {
+            expected_protocol == protocol
+                && match expected_request {
+                    Some(expected_request) => expected_request == request,
+                    None => true,
+                }
+        }
+        (Request::MaxIdLength(max), _) => request.id.0.len() <= *max,
+        (Request::AllOf(matchers), _) => matchers.iter().all(|m| check_request(request, m)),
+        _ => false,
     }
 }
 
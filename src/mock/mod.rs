@@ -3,19 +3,24 @@ mod ecdh;
 mod handler;
 mod session;
 mod socket;
+mod transcript;
 
-use crate::mock::handler::{Handler, HandlerIn};
-use discv5::enr::CombinedKey;
-use discv5::handler::NodeContact;
-use discv5::{Enr, IpMode};
+use crate::mock::handler::{Handler, HandlerIn, HandlerOut};
+pub(crate) use crate::mock::transcript::{compare_transcript, Transcript};
+use discv5::enr::{CombinedKey, NodeId};
+use discv5::handler::{NodeAddress, NodeContact};
+use discv5::packet::{PacketKind, ProtocolIdentity};
+use discv5::rpc::RequestBody;
+use discv5::{DefaultProtocolId, Enr, IpMode, Key};
 use std::collections::VecDeque;
-use tokio::sync::mpsc;
+use std::net::SocketAddr;
+use tokio::sync::{mpsc, oneshot};
 use tracing::info;
 
 #[allow(dead_code)]
 pub enum Behaviours {
     Declarative(DeclarativeBehaviour),
-    Sequential(VecDeque<Behaviour>),
+    Sequential(VecDeque<Step>),
 }
 
 pub struct DeclarativeBehaviour {
@@ -30,39 +35,253 @@ pub struct Behaviour {
     pub actions: Vec<Action>,
 }
 
+/// A step of the sequential behaviours.
+pub enum Step {
+    /// A behaviour which must match the next inbound packet.
+    Ordered(Behaviour),
+    /// Behaviours which match the next inbound packets in any order, e.g. the requests a node
+    /// sends concurrently. Each inbound packet is matched against the behaviours in order, so put
+    /// wildcards last. The group is complete once every behaviour has matched its maximum count,
+    /// or an inbound packet matches none of them and every behaviour has matched its minimum
+    /// count. In the latter case, the packet is matched against the next step.
+    Unordered(Vec<UnorderedBehaviour>),
+}
+
+/// A behaviour in an unordered group, which matches `min..=max` inbound packets.
+pub struct UnorderedBehaviour {
+    behaviour: Behaviour,
+    min: usize,
+    max: usize,
+    /// The number of inbound packets matched so far.
+    matched: usize,
+}
+
+impl UnorderedBehaviour {
+    pub(crate) fn new(behaviour: Behaviour, min: usize, max: usize) -> Self {
+        assert!(min <= max, "min must not exceed max. min:{min}, max:{max}");
+        UnorderedBehaviour {
+            behaviour,
+            min,
+            max,
+            matched: 0,
+        }
+    }
+
+    pub(crate) fn once(behaviour: Behaviour) -> Self {
+        Self::new(behaviour, 1, 1)
+    }
+
+    /// Matches any number of inbound packets, including none.
+    pub(crate) fn any_number(behaviour: Behaviour) -> Self {
+        Self::new(behaviour, 0, usize::MAX)
+    }
+}
+
 #[derive(Debug)]
 pub enum Expect {
     WhoAreYou,
     MessageWithoutSession,
     Handshake(Request),
     Message(Request),
+    /// Any inbound packet.
+    Any,
 }
 
+/// A matcher for the request in an inbound packet.
+#[allow(dead_code)]
 #[derive(Debug)]
 pub enum Request {
+    /// Any FINDNODE request.
     FindNode,
+    /// A FINDNODE request for exactly the given distances, in any order. See `lookup_distances()`
+    /// for the distances discv5 requests in a lookup.
+    FindNodeDistances(Vec<u64>),
+    /// Any PING request.
     Ping,
+    /// A PING request with the given `enr_seq`, i.e. the sequence number of the sender's ENR.
+    PingEnrSeq(u64),
+    /// A TALKREQ request with the given protocol and, if specified, the given body.
+    TalkWith {
+        protocol: Vec<u8>,
+        request: Option<Vec<u8>>,
+    },
+    /// A request whose id is at most the given number of bytes. The spec limits request ids to 8
+    /// bytes.
+    MaxIdLength(usize),
+    /// A request which matches all the given matchers, e.g. a kind and a predicate on its id.
+    AllOf(Vec<Request>),
+}
+
+/// The distances discv5 requests from `peer` in a lookup for `target`: the log2 distance between
+/// them, followed by the adjacent ones. See `findnode_log2distance` in discv5.
+#[allow(dead_code)]
+pub(crate) fn lookup_distances(target: &NodeId, peer: &NodeId) -> Vec<u64> {
+    // The number of distances discv5 requests per peer. See `DISTANCES_TO_REQUEST_PER_PEER`.
+    const NUM_DISTANCES: usize = 3;
+
+    let peer_key: Key<NodeId> = (*peer).into();
+    let distance = match peer_key.log2_distance(&(*target).into()) {
+        Some(distance) => distance,
+        None => return vec![],
+    };
+
+    let mut distances = vec![distance];
+    let mut difference = 1;
+    while distances.len() < NUM_DISTANCES {
+        if distance + difference <= 256 {
+            distances.push(distance + difference);
+        }
+        if distances.len() < NUM_DISTANCES {
+            if let Some(d) = distance.checked_sub(difference) {
+                distances.push(d);
+            }
+        }
+        difference += 1;
+    }
+    distances.truncate(NUM_DISTANCES);
+    distances
 }
 
+#[allow(dead_code)]
 #[derive(Clone)]
 pub enum Action {
     Ignore(String),
     SendWhoAreYou,
+    /// Send a WHOAREYOU with the given `enr_seq`, to let the node decide whether to attach its ENR
+    /// to the handshake. `SendWhoAreYou` always sends zero.
+    SendWhoAreYouWithEnrSeq(ChallengeEnrSeq),
+    /// Establish a session from the handshake. The ENR attached to, or left out of, the handshake
+    /// is checked against the challenge, and the result is reported as a `Verification`.
     EstablishSession,
     SendResponse(Response),
     CaptureRequest,
+    /// Send a request to the node which has sent the inbound packet. A session with the node must
+    /// have been established.
+    SendRequest(RequestBody),
+}
+
+/// The `enr_seq` to put into a WHOAREYOU packet.
+#[allow(dead_code)]
+#[derive(Clone, Debug)]
+pub enum ChallengeEnrSeq {
+    /// Zero, which means the mock doesn't know the ENR of the node.
+    Zero,
+    /// The sequence number of the ENR the mock knows. See `Mock::add_enr()`.
+    Known,
+    /// Lower than the sequence number of the ENR the mock knows.
+    Stale,
+    /// Higher than the sequence number of the ENR the mock knows.
+    Future,
+    Fixed(u64),
+}
+
+/// The ENR the mock attaches to a handshake packet it sends.
+#[allow(dead_code)]
+#[derive(Clone, Debug)]
+pub enum HandshakeEnr {
+    /// Attach the local ENR if the `enr_seq` in the WHOAREYOU is lower than that of the local
+    /// ENR, as discv5 does.
+    Auto,
+    /// Never attach an ENR.
+    Omit,
+    /// Always attach the given ENR, e.g. a stale one or one which doesn't match the socket the
+    /// mock sends packets from.
+    Custom(Box<Enr>),
+}
+
+/// The result of a check the mock has made on a packet received from a node.
+#[derive(Debug)]
+pub struct Verification {
+    pub node_address: NodeAddress,
+    pub kind: VerificationKind,
+    pub result: Result<(), String>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum VerificationKind {
+    /// Whether the ENR attached to, or left out of, a handshake is consistent with the challenge.
+    HandshakeEnr,
+    /// Whether the id-nonce signature in a handshake is valid for the public key of the node.
+    IdNonceSignature,
+}
+
+/// Counters of the packets the mock has sent or received.
+#[derive(Clone, Debug, Default)]
+pub struct Stats {
+    pub random_packets_sent: u64,
+    pub whoareyou_received: u64,
+    /// The number of FINDNODE requests with distance 0 the mock has answered with its own ENR.
+    pub enr_requests_answered: u64,
+}
+
+/// A change to the local ENR of the mock. Each one increments the sequence number, which the
+/// mock advertises in PONG responses and uses to decide whether to attach its ENR to handshakes.
+#[allow(dead_code)]
+#[derive(Clone, Debug)]
+pub enum EnrUpdate {
+    /// Only increment the sequence number.
+    Seq,
+    /// Insert a key/value pair into the ENR.
+    Field(String, Vec<u8>),
+    /// Advertise another UDP socket. Note that the mock keeps listening on the socket it has been
+    /// started with.
+    Socket(SocketAddr),
+}
+
+/// Specifies a packet to replay out of the packets the mock has sent or received.
+#[allow(dead_code)]
+#[derive(Clone, Debug)]
+pub enum Replay {
+    /// The packet at the given index, in the order the mock has sent or received them. Only the
+    /// last 1024 packets are kept.
+    Index(usize),
+    /// The last packet of the given type the mock has sent.
+    LastSent(PacketType),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum PacketType {
+    Message,
+    WhoAreYou,
+    Handshake,
+}
+
+impl From<&PacketKind> for PacketType {
+    fn from(kind: &PacketKind) -> Self {
+        match kind {
+            PacketKind::Message { .. } => PacketType::Message,
+            PacketKind::WhoAreYou { .. } => PacketType::WhoAreYou,
+            PacketKind::Handshake { .. } => PacketType::Handshake,
+        }
+    }
 }
 
 #[allow(dead_code)]
 #[derive(Clone)]
 pub enum Response {
+    /// The default response to the request. PONG reports the socket in the local ENR.
     Default,
+    /// Same as `Default`, but PONG reports the socket the PING has been received from, as discv5
+    /// does.
+    DefaultWithObservedSocket,
     Custom(Vec<CustomResponse>),
+    Nodes(NodesResponse),
 }
 
 #[derive(Clone)]
 pub enum CustomResponseId {
+    /// The id of the inbound request being responded to.
+    InboundRequestId,
+    /// The id of the request captured by `Action::CaptureRequest` at the given index.
     CapturedRequestId(usize),
+    /// The id of the captured request at the given index, incremented by one as a big-endian
+    /// integer. This guesses the id of the next request on implementations that allocate request
+    /// ids from a counter.
+    NextRequestId(usize),
+    /// An arbitrary id. Note that ids longer than 8 bytes are invalid in discv5.
+    Raw(Vec<u8>),
+    /// A random id which doesn't match any request.
+    Random,
 }
 
 #[derive(Clone)]
@@ -71,9 +290,39 @@ pub struct CustomResponse {
     pub body: discv5::rpc::ResponseBody,
 }
 
+/// A NODES response which is split into multiple packets in the same way as discv5 does.
+#[derive(Clone)]
+pub struct NodesResponse {
+    pub id: CustomResponseId,
+    pub nodes: Vec<Enr>,
+    pub total: NodesTotal,
+    pub packet_size: PacketSize,
+}
+
+#[derive(Clone)]
+pub enum NodesTotal {
+    /// The actual number of packets the response is split into.
+    Actual,
+    /// A fixed value regardless of the number of packets, to send an inconsistent `total`.
+    Fixed(u64),
+}
+
+#[derive(Clone)]
+pub enum PacketSize {
+    /// Split the ENRs into packets which fit in the maximum packet size.
+    Limited,
+    /// Put all the ENRs into a single packet even if it exceeds the maximum packet size.
+    Unlimited,
+}
+
 pub(crate) struct Mock {
+    node_id: NodeId,
     /// The channel to send messages to the handler.
     to_handler: mpsc::UnboundedSender<HandlerIn>,
+    /// The channel to receive messages from the handler.
+    from_handler: mpsc::Receiver<HandlerOut>,
+    /// The channel to receive the results of the checks made by the handler.
+    verifications: mpsc::Receiver<Verification>,
 }
 
 impl Mock {
@@ -83,9 +332,26 @@ impl Mock {
         config: discv5::Config,
         behaviours: Behaviours,
     ) -> Self {
-        let (to_handler, _from_handler) = Handler::spawn(enr, enr_key, config, behaviours).await;
+        Self::start_with_protocol_id::<DefaultProtocolId>(enr, enr_key, config, behaviours).await
+    }
+
+    /// Starts the mock speaking the protocol id `P` instead of the default one.
+    pub(crate) async fn start_with_protocol_id<P: ProtocolIdentity + Send + 'static>(
+        enr: Enr,
+        enr_key: CombinedKey,
+        config: discv5::Config,
+        behaviours: Behaviours,
+    ) -> Self {
+        let node_id = enr.node_id();
+        let (to_handler, from_handler, verifications) =
+            Handler::<P>::spawn(enr, enr_key, config, behaviours).await;
 
-        Mock { to_handler }
+        Mock {
+            node_id,
+            to_handler,
+            from_handler,
+            verifications,
+        }
     }
 
     pub(crate) fn send_random_packet(&mut self, enr: Enr) -> Result<(), String> {
@@ -96,9 +362,150 @@ impl Mock {
             node_contact.socket_addr()
         );
         self.to_handler
-            .send(HandlerIn::SendRandomPacket(node_contact))
+            .send(HandlerIn::SendRandomPacket(Box::new(node_contact)))
+            .map_err(|e| format!("Failed to send message to the handler: {e}"))?;
+
+        Ok(())
+    }
+
+    /// Sends a random packet to the node from each of the given node ids. The packets are neither
+    /// captured for replay nor recorded in the transcript, to keep the memory usage low while
+    /// flooding. Note that the WHOAREYOUs in reply are still recorded like any inbound packet.
+    pub(crate) fn send_random_packets(
+        &mut self,
+        enr: Enr,
+        node_ids: Vec<NodeId>,
+    ) -> Result<(), String> {
+        let node_contact = NodeContact::try_from_enr(enr, IpMode::Ip4).unwrap();
+        self.to_handler
+            .send(HandlerIn::SendRandomPackets(
+                Box::new(node_contact),
+                node_ids,
+            ))
+            .map_err(|e| format!("Failed to send message to the handler: {e}"))?;
+
+        Ok(())
+    }
+
+    /// Generates node ids the mock can pretend to be. They share the first 16 bytes with the local
+    /// node id, which the packets sent to them are masked with, so that the mock can decode the
+    /// packets in reply to the ones sent from them.
+    pub(crate) fn alias_node_ids(&self, n: usize) -> Vec<NodeId> {
+        (0..n)
+            .map(|_| {
+                let mut raw = self.node_id.raw();
+                raw[16..].copy_from_slice(&rand::random::<[u8; 16]>());
+                NodeId::new(&raw)
+            })
+            .collect()
+    }
+
+    pub(crate) async fn stats(&mut self) -> Result<Stats, String> {
+        let (tx, rx) = oneshot::channel();
+        self.to_handler
+            .send(HandlerIn::Stats(tx))
+            .map_err(|e| format!("Failed to send message to the handler: {e}"))?;
+        rx.await
+            .map_err(|e| format!("Failed to receive stats from the handler: {e}"))
+    }
+
+    /// Sends a request to the node. If no session exists, the mock initiates a handshake with the
+    /// node. The response(s) can be received with `recv_response()`.
+    pub(crate) fn send_request(&mut self, enr: Enr, body: RequestBody) -> Result<(), String> {
+        let node_contact = NodeContact::try_from_enr(enr, IpMode::Ip4).unwrap();
+        info!(
+            "Sending request to {} {}. {:?}",
+            node_contact.node_id(),
+            node_contact.socket_addr(),
+            body
+        );
+        self.to_handler
+            .send(HandlerIn::SendRequest(Box::new(node_contact), body))
+            .map_err(|e| format!("Failed to send message to the handler: {e}"))?;
+
+        Ok(())
+    }
+
+    /// Starts a new handshake with the node even if a session exists, sending the request in the
+    /// handshake packet along with the given ENR. The response(s) can be received with
+    /// `recv_response()`.
+    pub(crate) fn send_handshake(
+        &mut self,
+        enr: Enr,
+        body: RequestBody,
+        handshake_enr: HandshakeEnr,
+    ) -> Result<(), String> {
+        let node_contact = NodeContact::try_from_enr(enr, IpMode::Ip4).unwrap();
+        info!(
+            "Sending handshake to {} {}. {:?}, {:?}",
+            node_contact.node_id(),
+            node_contact.socket_addr(),
+            body,
+            handshake_enr
+        );
+        self.to_handler
+            .send(HandlerIn::SendHandshake(
+                Box::new(node_contact),
+                body,
+                handshake_enr,
+            ))
+            .map_err(|e| format!("Failed to send message to the handler: {e}"))?;
+
+        Ok(())
+    }
+
+    /// Lets the mock know the ENR of a node, which is used for `ChallengeEnrSeq`.
+    pub(crate) fn add_enr(&mut self, enr: Enr) -> Result<(), String> {
+        self.to_handler
+            .send(HandlerIn::AddEnr(Box::new(enr)))
+            .map_err(|e| format!("Failed to send message to the handler: {e}"))?;
+
+        Ok(())
+    }
+
+    /// Updates the local ENR of the mock, returning the updated one.
+    pub(crate) async fn update_enr(&mut self, update: EnrUpdate) -> Result<Enr, String> {
+        info!("Updating the ENR. {:?}", update);
+        let (tx, rx) = oneshot::channel();
+        self.to_handler
+            .send(HandlerIn::UpdateEnr(update, tx))
+            .map_err(|e| format!("Failed to send message to the handler: {e}"))?;
+        rx.await
+            .map_err(|e| format!("Failed to receive the ENR from the handler: {e}"))?
+    }
+
+    /// Resends a packet the mock has sent or received to its peer as it is.
+    pub(crate) fn replay_packet(&mut self, replay: Replay) -> Result<(), String> {
+        info!("Replaying a packet. {:?}", replay);
+        self.to_handler
+            .send(HandlerIn::ReplayPacket(replay))
             .map_err(|e| format!("Failed to send message to the handler: {e}"))?;
 
         Ok(())
     }
+
+    /// Returns the transcript of the packets the mock has sent and received so far. See
+    /// `compare_transcript()` to check it against a golden one.
+    pub(crate) async fn transcript(&mut self) -> Result<Transcript, String> {
+        let (tx, rx) = oneshot::channel();
+        self.to_handler
+            .send(HandlerIn::Transcript(tx))
+            .map_err(|e| format!("Failed to send message to the handler: {e}"))?;
+        rx.await
+            .map_err(|e| format!("Failed to receive the transcript from the handler: {e}"))
+    }
+
+    /// Receives a response to a request sent by the mock, along with the request.
+    pub(crate) async fn recv_response(
+        &mut self,
+    ) -> Option<(discv5::rpc::Request, discv5::rpc::Response)> {
+        match self.from_handler.recv().await? {
+            HandlerOut::Response(request, response) => Some((request, response)),
+        }
+    }
+
+    /// Receives the result of a check the mock has made on a packet received from a node.
+    pub(crate) async fn recv_verification(&mut self) -> Option<Verification> {
+        self.verifications.recv().await
+    }
 }
@@ -1,8 +1,11 @@
-use crate::mock::crypto::derive_keys_from_pubkey;
+use crate::mock::crypto::{derive_keys_from_pubkey, generate_session_keys, sign_nonce};
 use crate::mock::handler::Challenge;
 use discv5::enr::{CombinedKey, NodeId};
-use discv5::packet::{MessageNonce, Packet, PacketHeader, PacketKind};
-use discv5::{DefaultProtocolId, Enr};
+use discv5::handler::NodeContact;
+use discv5::packet::{
+    ChallengeData, MessageNonce, Packet, PacketHeader, PacketKind, ProtocolIdentity,
+};
+use discv5::Enr;
 use zeroize::Zeroize;
 
 /// The message nonce length (in bytes).
@@ -34,7 +37,7 @@ impl Session {
         // id_nonce_sig: &[u8],
         ephem_pubkey: &[u8],
         enr_record: Option<Enr>,
-    ) -> Result<(Session, Enr), String> {
+    ) -> Result<(Session, Option<Enr>), String> {
         // generate session keys
         let (decryption_key, encryption_key) = derive_keys_from_pubkey(
             local_key,
@@ -49,10 +52,70 @@ impl Session {
             decryption_key,
         };
 
-        Ok((Session::new(keys), enr_record.unwrap()))
+        // Prefer the newer one, as discv5 does.
+        let enr = match (enr_record, challenge.remote_enr.as_ref()) {
+            (Some(new_enr), Some(known_enr)) if new_enr.seq() <= known_enr.seq() => {
+                Some(known_enr.clone())
+            }
+            (Some(new_enr), _) => Some(new_enr),
+            (None, known_enr) => known_enr.cloned(),
+        };
+
+        Ok((Session::new(keys), enr))
+    }
+
+    /// Initiates a session in response to a WHOAREYOU. This returns the handshake packet which
+    /// carries the message, along with the session.
+    pub(crate) fn encrypt_with_header<P: ProtocolIdentity>(
+        local_key: &CombinedKey,
+        local_id: NodeId,
+        remote_contact: &NodeContact,
+        challenge_data: &ChallengeData,
+        enr_record: Option<Enr>,
+        message: &[u8],
+    ) -> Result<(Packet, Session), String> {
+        // generate session keys
+        let (encryption_key, decryption_key, ephem_pubkey) = generate_session_keys(
+            &local_id,
+            &remote_contact.node_id(),
+            &remote_contact.public_key(),
+            challenge_data,
+        )?;
+
+        let keys = Keys {
+            encryption_key,
+            decryption_key,
+        };
+
+        // construct the nonce signature
+        let id_nonce_sig = sign_nonce(
+            local_key,
+            challenge_data,
+            &ephem_pubkey,
+            &remote_contact.node_id(),
+        )?;
+
+        // build a handshake packet
+        let message_nonce: MessageNonce = rand::random();
+        let mut packet = Packet::new_authheader(
+            local_id,
+            message_nonce,
+            id_nonce_sig,
+            ephem_pubkey,
+            enr_record,
+        );
+
+        packet.message = crate::mock::crypto::encrypt_message(
+            &keys.encryption_key,
+            message_nonce,
+            message,
+            &packet.authenticated_data::<P>(),
+        )?;
+
+        Ok((packet, Session::new(keys)))
     }
 
-    pub(crate) fn encrypt_message(
+    pub(crate) fn encrypt_message<P: ProtocolIdentity>(
         &mut self,
         src_id: NodeId,
         message: &[u8],
@@ -74,7 +137,7 @@ impl Session {
         };
 
         let mut authenticated_data = iv.to_be_bytes().to_vec();
-        authenticated_data.extend_from_slice(&header.encode::<DefaultProtocolId>());
+        authenticated_data.extend_from_slice(&header.encode::<P>());
 
         let cipher = crate::mock::crypto::encrypt_message(
             &self.keys.encryption_key,
@@ -1,7 +1,8 @@
+use crate::capture;
 use discv5::enr::NodeId;
-use discv5::packet::Packet;
+use discv5::packet::{Packet, ProtocolIdentity};
 use discv5::socket::{InboundPacket, OutboundPacket};
-use discv5::{DefaultProtocolId, Executor, ListenConfig};
+use discv5::{Executor, ListenConfig};
 use std::net::SocketAddr;
 use std::sync::Arc;
 use tokio::net::UdpSocket;
@@ -17,7 +18,8 @@ pub(crate) struct Socket {
 }
 
 impl Socket {
-    pub(crate) async fn new(
+    /// Binds the socket, which decodes and encodes the packets with the protocol id `P`.
+    pub(crate) async fn new<P: ProtocolIdentity + 'static>(
         executor: Box<dyn Executor + Send + Sync>,
         node_id: NodeId,
         listen_config: ListenConfig,
@@ -31,8 +33,8 @@ impl Socket {
             ListenConfig::DualStack { .. } => unreachable!(),
         };
 
-        let from_recv_handler = RecvHandler::spawn(executor.clone(), node_id, socket.clone());
-        let to_send_handler = SendHandler::spawn(executor, socket);
+        let from_recv_handler = RecvHandler::spawn::<P>(executor.clone(), node_id, socket.clone());
+        let to_send_handler = SendHandler::spawn::<P>(executor, socket);
 
         Socket {
             recv: from_recv_handler,
@@ -48,7 +50,7 @@ struct RecvHandler {
 }
 
 impl RecvHandler {
-    pub(crate) fn spawn(
+    pub(crate) fn spawn<P: ProtocolIdentity + 'static>(
         executor: Box<dyn Executor>,
         node_id: NodeId,
         socket: Arc<UdpSocket>,
@@ -63,28 +65,29 @@ impl RecvHandler {
         };
 
         executor.spawn(Box::pin(async move {
-            receive_handler.start().await;
+            receive_handler.start::<P>().await;
         }));
 
         handler_recv
     }
 
-    async fn start(&self) {
+    async fn start<P: ProtocolIdentity>(&self) {
         loop {
             let mut first_buffer = [0; MAX_PACKET_SIZE];
 
             if let Ok((length, src_address)) = self.socket.recv_from(&mut first_buffer).await {
                 // self.handle_inbound(src, length, &first_buffer).await;
-                let (packet, authenticated_data) = match Packet::decode::<DefaultProtocolId>(
-                    &self.node_id,
-                    &first_buffer[..length],
-                ) {
-                    Ok(p) => p,
-                    Err(e) => {
-                        warn!("Failed to decode packet: {e:?}");
-                        continue;
-                    }
-                };
+                let (packet, authenticated_data) =
+                    match Packet::decode::<P>(&self.node_id, &first_buffer[..length]) {
+                        Ok(p) => p,
+                        Err(e) => {
+                            warn!("Failed to decode packet: {e:?}");
+                            if let Ok(local) = self.socket.local_addr() {
+                                capture::record_undecodable(local, src_address, length);
+                            }
+                            continue;
+                        }
+                    };
 
                 let inbound = InboundPacket {
                     src_address,
@@ -107,7 +110,7 @@ struct SendHandler {
 }
 
 impl SendHandler {
-    pub(crate) fn spawn(
+    pub(crate) fn spawn<P: ProtocolIdentity + 'static>(
         executor: Box<dyn Executor>,
         socket: Arc<UdpSocket>,
     ) -> Sender<OutboundPacket> {
@@ -119,18 +122,18 @@ impl SendHandler {
         };
 
         executor.spawn(Box::pin(async move {
-            send_handler.start().await;
+            send_handler.start::<P>().await;
         }));
 
         to_send_handler
     }
 
-    async fn start(&mut self) {
+    async fn start<P: ProtocolIdentity>(&mut self) {
         loop {
             if let Some(outbound_packet) = self.from_handler.recv().await {
                 let encoded_packet = outbound_packet
                     .packet
-                    .encode::<DefaultProtocolId>(&outbound_packet.node_address.node_id);
+                    .encode::<P>(&outbound_packet.node_address.node_id);
                 let dest = &outbound_packet.node_address.socket_addr;
                 let _ = self.socket.send_to(&encoded_packet, dest).await.unwrap();
             }
@@ -0,0 +1,329 @@
+use crate::mock::handler::Direction;
+use crate::trace::{node_id_hex, now_ms, TraceKind};
+use discv5::enr::NodeId;
+use discv5::packet::PacketKind;
+use discv5::rpc::{Message, RequestBody, RequestId, ResponseBody};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+/// A normalized record of the packets the mock has sent and received, which can be compared
+/// against a golden file across runs. Node ids, sockets and request ids are aliased to stable
+/// names in the order they appear. The time between packets is bucketed coarsely: only a gap of a
+/// second or more is marked, so that the network latency and the scheduling don't make the
+/// transcripts differ, while a retry or a timeout does.
+///
+/// The packets are also kept as they are, for the trace of the instance. See `Tracer`.
+#[derive(Clone)]
+pub(crate) struct Transcript {
+    entries: Vec<String>,
+    packets: Vec<(u64, TraceKind)>,
+    // The request timeout of the node, which the longest gaps are marked with.
+    request_timeout: Duration,
+    last_recorded_at: Option<Instant>,
+    node_ids: HashMap<NodeId, String>,
+    sockets: HashMap<SocketAddr, String>,
+    request_ids: HashMap<RequestId, String>,
+}
+
+impl Transcript {
+    pub(crate) fn new(local_id: NodeId, request_timeout: Duration) -> Self {
+        let mut node_ids = HashMap::new();
+        node_ids.insert(local_id, "mock".to_string());
+        Transcript {
+            entries: vec![],
+            packets: vec![],
+            request_timeout,
+            last_recorded_at: None,
+            node_ids,
+            sockets: HashMap::new(),
+            request_ids: HashMap::new(),
+        }
+    }
+
+    /// The normalized lines of the transcript.
+    pub(crate) fn entries(&self) -> Vec<String> {
+        self.entries.clone()
+    }
+
+    /// The packets with the time they have been sent or received at, in milliseconds since the
+    /// UNIX epoch.
+    pub(crate) fn packets(&self) -> Vec<(u64, TraceKind)> {
+        self.packets.clone()
+    }
+
+    /// Records a packet. The node id is `None` for a WHOAREYOU received, which doesn't carry it,
+    /// in which case the peer is identified by its socket. The message is `None` if it can't be
+    /// decrypted, such as the one in a random packet.
+    pub(crate) fn record(
+        &mut self,
+        direction: &Direction,
+        node_id: Option<NodeId>,
+        socket_addr: SocketAddr,
+        packet: &PacketKind,
+        message: Option<&Message>,
+    ) {
+        self.trace_packet(
+            direction,
+            node_id,
+            socket_addr,
+            summarize(direction, packet, message),
+        );
+
+        let peer = match node_id {
+            Some(node_id) => self.node_alias(node_id),
+            None => self.socket_alias(socket_addr),
+        };
+        let packet = match packet {
+            PacketKind::Message { .. } => "Message".to_string(),
+            PacketKind::WhoAreYou { enr_seq, .. } => {
+                // WHOAREYOU carries no message.
+                return self.push(format!(
+                    "{} {peer} WHOAREYOU enr_seq={enr_seq}",
+                    direction_arrow(direction)
+                ));
+            }
+            PacketKind::Handshake { enr_record, .. } => match enr_record {
+                Some(enr) => format!("Handshake enr_seq={}", enr.seq()),
+                None => "Handshake enr=none".to_string(),
+            },
+        };
+        let message = match message {
+            Some(message) => self.format_message(message),
+            None => "?".to_string(),
+        };
+        self.push(format!(
+            "{} {peer} {packet} {message}",
+            direction_arrow(direction)
+        ));
+    }
+
+    /// Records a packet resent as it is, whose message is not decoded again.
+    pub(crate) fn record_replay(
+        &mut self,
+        node_id: NodeId,
+        socket_addr: SocketAddr,
+        packet: &PacketKind,
+    ) {
+        let peer = self.node_alias(node_id);
+        let packet = match packet {
+            PacketKind::Message { .. } => "Message",
+            PacketKind::WhoAreYou { .. } => "WHOAREYOU",
+            PacketKind::Handshake { .. } => "Handshake",
+        };
+        self.trace_packet(
+            &Direction::Outbound,
+            Some(node_id),
+            socket_addr,
+            format!("{packet} (replayed)"),
+        );
+        self.push(format!(
+            "{} {peer} {packet} (replayed)",
+            direction_arrow(&Direction::Outbound)
+        ));
+    }
+
+    fn trace_packet(
+        &mut self,
+        direction: &Direction,
+        node_id: Option<NodeId>,
+        socket_addr: SocketAddr,
+        summary: String,
+    ) {
+        let peer_socket = socket_addr.to_string();
+        let kind = match (direction, node_id) {
+            (Direction::Outbound, Some(node_id)) => TraceKind::Sent {
+                peer_node_id: node_id_hex(&node_id),
+                peer_socket,
+                summary,
+            },
+            (Direction::Outbound, None) => unreachable!("The peer of an outbound packet is known"),
+            (Direction::Inbound, node_id) => TraceKind::Received {
+                peer_node_id: node_id.as_ref().map(node_id_hex),
+                peer_socket,
+                summary,
+            },
+        };
+        self.packets.push((now_ms(), kind));
+    }
+
+    fn push(&mut self, entry: String) {
+        let now = Instant::now();
+        let elapsed = match self.last_recorded_at {
+            Some(last_recorded_at) => now.duration_since(last_recorded_at),
+            None => Duration::ZERO,
+        };
+        self.last_recorded_at = Some(now);
+        if elapsed >= self.request_timeout {
+            self.entries.push(format!("(+~timeout) {entry}"));
+        } else if elapsed >= Duration::from_secs(1) {
+            self.entries.push(format!("(+~1s) {entry}"));
+        } else {
+            self.entries.push(entry);
+        }
+    }
+
+    fn format_message(&mut self, message: &Message) -> String {
+        match message {
+            Message::Request(request) => {
+                let id = self.request_alias(&request.id);
+                match &request.body {
+                    RequestBody::Ping { enr_seq } => format!("PING {id} enr_seq={enr_seq}"),
+                    RequestBody::FindNode { distances } => {
+                        format!("FINDNODE {id} distances={distances:?}")
+                    }
+                    RequestBody::Talk { protocol, request } => format!(
+                        "TALKREQ {id} protocol={:?} request={:?}",
+                        String::from_utf8_lossy(protocol),
+                        String::from_utf8_lossy(request)
+                    ),
+                }
+            }
+            Message::Response(response) => {
+                let id = self.request_alias(&response.id);
+                match &response.body {
+                    ResponseBody::Pong { enr_seq, ip, port } => {
+                        let socket = self.socket_alias(SocketAddr::new(*ip, port.get()));
+                        format!("PONG {id} enr_seq={enr_seq} socket={socket}")
+                    }
+                    // The number of ENRs is left out since it depends on the random node ids.
+                    ResponseBody::Nodes { total, .. } => format!("NODES {id} total={total}"),
+                    ResponseBody::Talk { response } => format!(
+                        "TALKRESP {id} response={:?}",
+                        String::from_utf8_lossy(response)
+                    ),
+                }
+            }
+        }
+    }
+
+    fn node_alias(&mut self, node_id: NodeId) -> String {
+        let next = self.node_ids.len();
+        self.node_ids
+            .entry(node_id)
+            .or_insert_with(|| format!("node-{next}"))
+            .clone()
+    }
+
+    fn socket_alias(&mut self, socket_addr: SocketAddr) -> String {
+        let next = self.sockets.len() + 1;
+        self.sockets
+            .entry(socket_addr)
+            .or_insert_with(|| format!("socket-{next}"))
+            .clone()
+    }
+
+    fn request_alias(&mut self, id: &RequestId) -> String {
+        let next = self.request_ids.len() + 1;
+        self.request_ids
+            .entry(id.clone())
+            .or_insert_with(|| format!("req-{next}"))
+            .clone()
+    }
+}
+
+/// Summarizes a packet for the sequence diagrams, in the way the diagrams in README do.
+fn summarize(direction: &Direction, packet: &PacketKind, message: Option<&Message>) -> String {
+    match (packet, message) {
+        (PacketKind::WhoAreYou { .. }, _) => "WHOAREYOU".to_string(),
+        (PacketKind::Message { .. }, Some(message)) => message_name(message),
+        (PacketKind::Message { .. }, None) => match direction {
+            Direction::Outbound => "Random packet".to_string(),
+            Direction::Inbound => "Message (undecryptable)".to_string(),
+        },
+        (PacketKind::Handshake { .. }, Some(message)) => {
+            format!("Handshake message ({})", message_name(message))
+        }
+        (PacketKind::Handshake { .. }, None) => "Handshake message".to_string(),
+    }
+}
+
+fn message_name(message: &Message) -> String {
+    match message {
+        Message::Request(request) => match &request.body {
+            RequestBody::Ping { .. } => "PING".to_string(),
+            RequestBody::FindNode { distances } => format!("FINDNODE (distances: {distances:?})"),
+            RequestBody::Talk { .. } => "TALKREQ".to_string(),
+        },
+        Message::Response(response) => match &response.body {
+            ResponseBody::Pong { .. } => "PONG".to_string(),
+            ResponseBody::Nodes { total, .. } => format!("NODES (total: {total})"),
+            ResponseBody::Talk { .. } => "TALKRESP".to_string(),
+        },
+    }
+}
+
+fn direction_arrow(direction: &Direction) -> &'static str {
+    match direction {
+        Direction::Inbound => "<-",
+        Direction::Outbound => "->",
+    }
+}
+
+/// Compares a transcript against a golden one, returning a line-by-line diff if they differ.
+///
+/// Blank lines and lines beginning with `#` in the golden transcript are ignored. Consecutive
+/// lines beginning with `* ` form a group which matches the same lines in any order, for packets
+/// whose order depends on timing, such as the responses to concurrent requests.
+pub(crate) fn compare_transcript(actual: &[String], golden: &str) -> Result<(), String> {
+    let expected = golden
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .collect::<Vec<_>>();
+
+    let mut diff = vec![];
+    let mut i = 0;
+    while i < expected.len().max(actual.len()) {
+        let group_len = expected[i.min(expected.len())..]
+            .iter()
+            .take_while(|line| line.starts_with("* "))
+            .count();
+
+        if group_len > 0 {
+            let mut expected_group = expected[i..i + group_len]
+                .iter()
+                .map(|line| line.trim_start_matches("* "))
+                .collect::<Vec<_>>();
+            let end = (i + group_len).min(actual.len());
+            let mut actual_group = actual[i.min(end)..end]
+                .iter()
+                .map(String::as_str)
+                .collect::<Vec<_>>();
+            expected_group.sort_unstable();
+            actual_group.sort_unstable();
+            if expected_group != actual_group {
+                for line in expected_group {
+                    diff.push(format!("{:>3} - * {line}", i + 1));
+                }
+                for line in actual_group {
+                    diff.push(format!("{:>3} + * {line}", i + 1));
+                }
+            }
+            i += group_len;
+            continue;
+        }
+
+        match (expected.get(i), actual.get(i)) {
+            (Some(e), Some(a)) if *e == a.as_str() => {}
+            (e, a) => {
+                if let Some(e) = e {
+                    diff.push(format!("{:>3} - {e}", i + 1));
+                }
+                if let Some(a) = a {
+                    diff.push(format!("{:>3} + {a}", i + 1));
+                }
+            }
+        }
+        i += 1;
+    }
+
+    if diff.is_empty() {
+        Ok(())
+    } else {
+        Err(format!(
+            "The transcript differs from the golden one.\n{}",
+            diff.join("\n")
+        ))
+    }
+}
@@ -0,0 +1,443 @@
+mod relay;
+
+use crate::capture;
+use crate::nat::relay::{NatType, Relay};
+use crate::utils::{
+    generate_deterministic_keypair, get_param, publish_and_collect, publish_and_collect_on,
+    run_seed,
+};
+use chrono::Local;
+use discv5::enr::CombinedKey;
+use discv5::{Discv5, Enr, Event, ListenConfig};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use testground::client::Client;
+use testground::WriteQuery;
+use tracing::{error, info, warn};
+
+const STATE_COMPLETED_TO_COLLECT_INSTANCE_INFORMATION: &str =
+    "STATE_COMPLETED_TO_COLLECT_INSTANCE_INFORMATION";
+const STATE_DONE: &str = "STATE_DONE";
+
+// The address the node behind the NAT is bound to.
+const INTERNAL_PORT: u16 = 9000;
+// The public port of the full-cone NAT. The symmetric NAT picks an ephemeral port per mapping.
+const NAT_PUBLIC_PORT: u16 = 30000;
+
+// The sequence number of the peers' ENRs pointing to the private side of the NAT, which the node
+// behind the NAT knows. This is higher than the peers' own, so that the node never replaces them
+// with the ones it learns from the peers.
+const TRANSLATED_ENR_SEQ: u64 = 1 << 32;
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+enum Role {
+    Natted,
+    Peers,
+}
+
+impl From<&str> for Role {
+    fn from(test_group_id: &str) -> Self {
+        match test_group_id {
+            "natted" => Role::Natted,
+            "peers" => Role::Peers,
+            _ => unreachable!(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct InstanceInfo {
+    role: Role,
+    // The sequence number of this test instance within the test.
+    seq: u64,
+    enr: Enr,
+}
+
+pub(crate) async fn run(client: Client) -> Result<(), Box<dyn std::error::Error>> {
+    let run_parameters = client.run_parameters();
+    let role: Role = run_parameters.test_group_id.as_str().into();
+    let ip = run_parameters
+        .data_network_ip()?
+        .expect("IP address for the data network");
+    client.record_message(format!(
+        "role: {:?}, group_seq: {}",
+        role,
+        client.group_seq()
+    ));
+
+    match role {
+        Role::Natted => play_natted(client, ip).await?,
+        Role::Peers => play_peers(client, ip).await?,
+    }
+
+    Ok(())
+}
+
+async fn play_natted(client: Client, ip: IpAddr) -> Result<(), Box<dyn std::error::Error>> {
+    let run_parameters = client.run_parameters();
+    let nat_type: NatType = get_param("nat_type", &run_parameters.test_instance_params)?;
+    let enr_peer_update_min: usize =
+        get_param("enr_peer_update_min", &run_parameters.test_instance_params)?;
+    let ping_interval: u64 = get_param("ping_interval", &run_parameters.test_instance_params)?;
+
+    // ////////////////////////
+    // Start discv5
+    // ////////////////////////
+    // The node is bound to the loopback interface, so that it can reach the data network only
+    // through the NAT. Like a node behind a NAT, it doesn't know its public address at first.
+    let enr_key = CombinedKey::generate_secp256k1();
+    // The key also signs the ENR the stranger probes the node with, see below.
+    let secret_key = enr_key.encode();
+    let enr = Enr::builder().build(&enr_key).expect("Construct an Enr");
+    let listen_config = ListenConfig::Ipv4 {
+        ip: Ipv4Addr::LOCALHOST,
+        port: INTERNAL_PORT,
+    };
+    let config = discv5::ConfigBuilder::new(listen_config)
+        .enr_peer_update_min(enr_peer_update_min)
+        .ping_interval(Duration::from_secs(ping_interval))
+        .build();
+    let mut discv5: Discv5 = Discv5::new(enr.clone(), enr_key, config)?;
+    discv5.start().await.expect("Start Discovery v5 server");
+
+    // Observe Discv5 events, recording the socket updates.
+    let socket_updates = Arc::new(Mutex::new(Vec::<SocketAddr>::new()));
+    let mut event_stream = discv5.event_stream().await.expect("Discv5Event");
+    let updates = socket_updates.clone();
+    tokio::spawn(async move {
+        while let Some(event) = event_stream.recv().await {
+            if let Event::SocketUpdated(socket_addr) = event {
+                info!("Discv5Event::SocketUpdated {socket_addr}");
+                updates
+                    .lock()
+                    .expect("Lock the socket updates")
+                    .push(socket_addr);
+            }
+        }
+    });
+
+    // ////////////////////////
+    // Start the NAT
+    // ////////////////////////
+    let relay = Relay::new(
+        nat_type,
+        ip,
+        NAT_PUBLIC_PORT,
+        (Ipv4Addr::LOCALHOST, INTERNAL_PORT).into(),
+    )
+    .await?;
+
+    // //////////////////////////////////////////////////////////////
+    // Collect information of all participants in the test case
+    // //////////////////////////////////////////////////////////////
+    let participants = publish_and_collect(
+        &client,
+        InstanceInfo {
+            role: Role::Natted,
+            seq: client.global_seq(),
+            enr,
+        },
+    )
+    .await?;
+    capture::register_enrs(participants.iter().map(|p| &p.enr));
+    let mut peers = participants
+        .into_iter()
+        .filter(|p| p.role == Role::Peers)
+        .collect::<Vec<_>>();
+    peers.sort_by_key(|p| p.seq);
+    // The last peer is never contacted by the node, and probes it from outside.
+    let stranger = peers.pop().expect("A stranger");
+
+    client
+        .signal_and_wait(
+            STATE_COMPLETED_TO_COLLECT_INSTANCE_INFORMATION,
+            run_parameters.test_instance_count,
+        )
+        .await?;
+
+    // //////////////////////////////////////////////////////////////
+    // Map the peers onto the private side of the NAT
+    // //////////////////////////////////////////////////////////////
+    // The node knows all the peers, the stranger included, by their private addresses. The
+    // stranger is never contacted by the node though, so the NAT has no traffic with it. The node
+    // knows the stranger's ENR in advance, since the one the stranger would send in a handshake
+    // doesn't match the private address.
+    let seed = run_seed(&run_parameters.test_run);
+    let mut voters = vec![];
+    for peer in peers.iter().chain([&stranger]) {
+        let remote = peer.enr.udp4_socket().expect("The peer's address").into();
+        let private = relay.map(remote).await?;
+        let key = generate_deterministic_keypair(1, seed.wrapping_add(peer.seq)).remove(0);
+        let translated = Enr::builder()
+            .ip(private.ip())
+            .udp4(private.port())
+            .seq(TRANSLATED_ENR_SEQ)
+            .build(&key)
+            .expect("Construct an Enr");
+        assert_eq!(peer.enr.node_id(), translated.node_id());
+        if peer.seq == stranger.seq {
+            discv5.add_enr(translated)?;
+        } else {
+            voters.push(translated);
+        }
+    }
+
+    // //////////////////////////////////////////////////////////////
+    // Discover the public address with PING
+    // //////////////////////////////////////////////////////////////
+    // Contacting the voters inserts them into the routing table as outgoing entries, the only
+    // ones whose votes count. The PONGs to these PINGs are returned to us instead of voting, so
+    // the votes come from the PINGs discv5 sends every `ping_interval`.
+    let mut observed = vec![];
+    let mut errors = vec![];
+    for voter in voters.iter() {
+        match discv5.send_ping(voter.clone()).await {
+            Ok(pong) => observed.push(SocketAddr::new(pong.ip, pong.port)),
+            Err(e) => errors.push(format!("Failed to send PING to a peer: {e}")),
+        }
+    }
+
+    let waiting_since = Instant::now();
+    while socket_updates
+        .lock()
+        .expect("Lock the socket updates")
+        .is_empty()
+        && waiting_since.elapsed() < Duration::from_secs(ping_interval * 2 + 1)
+    {
+        tokio::time::sleep(Duration::from_millis(500)).await;
+    }
+    let socket_updates = socket_updates
+        .lock()
+        .expect("Lock the socket updates")
+        .clone();
+    let local_enr = discv5.local_enr();
+    info!(
+        "observed: {observed:?}, socket_updates: {socket_updates:?}, enr socket: {:?}",
+        local_enr.udp4_socket()
+    );
+
+    // //////////////////////////////////////////////////////////////
+    // Check the discovered address
+    // //////////////////////////////////////////////////////////////
+    let observed_distinct = observed.iter().collect::<HashSet<_>>().len();
+    if observed.iter().any(|socket_addr| socket_addr.ip() != ip) {
+        errors.push(format!(
+            "The peers have observed addresses other than the NAT's: {observed:?}"
+        ));
+    }
+    match nat_type {
+        // All the peers observe the same address, which the node adopts.
+        NatType::FullCone => {
+            let public: SocketAddr = (ip, NAT_PUBLIC_PORT).into();
+            if observed_distinct > 1 {
+                errors.push(format!(
+                    "The peers have observed different addresses: {observed:?}"
+                ));
+            }
+            if socket_updates != vec![public] {
+                errors.push(format!(
+                    "The socket has been updated to {socket_updates:?}, expected {public}."
+                ));
+            }
+            if local_enr.udp4_socket().map(SocketAddr::from) != Some(public) {
+                errors.push(format!(
+                    "The ENR advertises {:?}, expected {public}.",
+                    local_enr.udp4_socket()
+                ));
+            }
+        }
+        // Each peer observes a different address, none of which gets the majority.
+        NatType::Symmetric => {
+            if observed_distinct != observed.len() {
+                errors.push(format!(
+                    "The peers have observed the same address: {observed:?}"
+                ));
+            }
+            if !socket_updates.is_empty() {
+                errors.push(format!(
+                    "The socket has been updated to {socket_updates:?}, expected no update."
+                ));
+            }
+        }
+    }
+
+    // //////////////////////////////////////////////////////////////
+    // Let the stranger probe the node
+    // //////////////////////////////////////////////////////////////
+    // Behind a full-cone NAT, the stranger probes the node at the address it advertises. The node
+    // behind a symmetric NAT advertises none, so the stranger probes the public address a voter
+    // has observed instead, from a source the mapping hasn't been opened for.
+    let probed = match (nat_type, observed.first()) {
+        (NatType::Symmetric, Some(public)) => {
+            let enr_key = CombinedKey::secp256k1_from_bytes(&mut secret_key.clone())?;
+            Enr::builder()
+                .ip(public.ip())
+                .udp4(public.port())
+                .build(&enr_key)
+                .expect("Construct an Enr")
+        }
+        _ => local_enr.clone(),
+    };
+    publish_and_collect_on(
+        &client,
+        "nat_enrs",
+        InstanceInfo {
+            role: Role::Natted,
+            seq: client.global_seq(),
+            enr: probed,
+        },
+    )
+    .await?;
+
+    client
+        .signal_and_wait(STATE_DONE, run_parameters.test_instance_count)
+        .await?;
+
+    // The stranger has probed the node by now, which a symmetric NAT must have filtered out.
+    let dropped = relay.dropped();
+    if nat_type == NatType::Symmetric && dropped == 0 {
+        errors.push("The NAT has not filtered out the probe from the stranger.".to_string());
+    }
+
+    // //////////////////////////////////////////////////////////////
+    // Record metrics
+    // //////////////////////////////////////////////////////////////
+    let write_query = WriteQuery::new(
+        Local::now().into(),
+        format!(
+            "discv5-testground_{}_{}",
+            run_parameters.test_case, run_parameters.test_run
+        ),
+    )
+    .add_field("voters", voters.len() as u64)
+    .add_field("pongs", observed.len() as u64)
+    .add_field("observed_addresses", observed_distinct as u64)
+    .add_field("socket_updated", !socket_updates.is_empty())
+    .add_field("dropped_packets", dropped)
+    .add_tag("nat_type", nat_type.to_string());
+    client.record_metric(write_query).await?;
+
+    if errors.is_empty() {
+        client.record_success().await?;
+    } else {
+        for e in errors.iter() {
+            error!("{e}");
+        }
+        client.record_failure(errors.join(", ")).await?;
+    }
+    Ok(())
+}
+
+async fn play_peers(client: Client, ip: IpAddr) -> Result<(), Box<dyn std::error::Error>> {
+    let run_parameters = client.run_parameters();
+    let nat_type: NatType = get_param("nat_type", &run_parameters.test_instance_params)?;
+
+    // ////////////////////////
+    // Start discv5
+    // ////////////////////////
+    // The key is derived from the run, so that the node behind the NAT can sign the ENR of this
+    // peer pointing to the private side of the NAT.
+    let enr_key = generate_deterministic_keypair(
+        1,
+        run_seed(&run_parameters.test_run).wrapping_add(client.global_seq()),
+    )
+    .remove(0);
+    let enr = Enr::builder()
+        .ip(ip)
+        .udp4(9000)
+        .build(&enr_key)
+        .expect("Construct an Enr");
+    let listen_config = ListenConfig::Ipv4 {
+        ip: Ipv4Addr::UNSPECIFIED,
+        port: 9000,
+    };
+    let config = discv5::ConfigBuilder::new(listen_config).build();
+    let mut discv5: Discv5 = Discv5::new(enr.clone(), enr_key, config)?;
+    discv5.start().await.expect("Start Discovery v5 server");
+
+    // //////////////////////////////////////////////////////////////
+    // Collect information of all participants in the test case
+    // //////////////////////////////////////////////////////////////
+    let instance_info = InstanceInfo {
+        role: Role::Peers,
+        seq: client.global_seq(),
+        enr,
+    };
+    let participants = publish_and_collect(&client, instance_info.clone()).await?;
+    capture::register_enrs(participants.iter().map(|p| &p.enr));
+    let is_stranger = participants
+        .iter()
+        .filter(|p| p.role == Role::Peers)
+        .all(|p| p.seq <= instance_info.seq);
+
+    client
+        .signal_and_wait(
+            STATE_COMPLETED_TO_COLLECT_INSTANCE_INFORMATION,
+            run_parameters.test_instance_count,
+        )
+        .await?;
+
+    // Wait until the node behind the NAT has discovered its address, if any.
+    let participants = publish_and_collect_on(&client, "nat_enrs", instance_info).await?;
+    capture::register_enrs(participants.iter().map(|p| &p.enr));
+    let natted = participants
+        .into_iter()
+        .find(|p| p.role == Role::Natted)
+        .expect("The node behind the NAT")
+        .enr;
+
+    // //////////////////////////////////////////////////////////////
+    // Probe the node behind the NAT
+    // //////////////////////////////////////////////////////////////
+    // Only a full-cone NAT lets in the traffic from a peer the node has never contacted. A
+    // symmetric NAT drops it, even at the public address a voter has observed.
+    let mut result = Ok(());
+    if is_stranger {
+        let reachable = match natted.udp4_socket() {
+            Some(_) => match discv5.send_ping(natted.clone()).await {
+                Ok(_) => true,
+                Err(e) => {
+                    warn!("Failed to send PING to the node behind the NAT: {e}");
+                    false
+                }
+            },
+            None => false,
+        };
+        let expected = nat_type == NatType::FullCone;
+        info!("reachable: {reachable}, expected: {expected}");
+
+        let write_query = WriteQuery::new(
+            Local::now().into(),
+            format!(
+                "discv5-testground_{}_{}",
+                run_parameters.test_case, run_parameters.test_run
+            ),
+        )
+        .add_field("reachable", reachable)
+        .add_tag("nat_type", nat_type.to_string());
+        client.record_metric(write_query).await?;
+
+        if reachable != expected {
+            result = Err(format!(
+                "The node behind the {nat_type} NAT is {}reachable from a stranger.",
+                if reachable { "" } else { "not " }
+            ));
+        }
+    }
+
+    client
+        .signal_and_wait(STATE_DONE, run_parameters.test_instance_count)
+        .await?;
+
+    match result {
+        Ok(()) => client.record_success().await?,
+        Err(e) => {
+            error!("{e}");
+            client.record_failure(e).await?;
+        }
+    }
+    Ok(())
+}
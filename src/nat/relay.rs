@@ -0,0 +1,201 @@
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::net::UdpSocket;
+use tracing::warn;
+
+const MAX_PACKET_SIZE: usize = 1280;
+
+/// How the NAT maps the private address of the node onto public ones.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum NatType {
+    /// `full-cone`. All the outbound traffic goes out from a single public address, which accepts
+    /// inbound traffic from anyone.
+    FullCone,
+    /// `symmetric`. The outbound traffic to each remote address goes out from a public address of
+    /// its own, which accepts inbound traffic only from that remote address.
+    Symmetric,
+}
+
+impl FromStr for NatType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "full-cone" => Ok(NatType::FullCone),
+            "symmetric" => Ok(NatType::Symmetric),
+            _ => Err(format!("Unknown NAT type: {s}")),
+        }
+    }
+}
+
+impl Display for NatType {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            NatType::FullCone => write!(f, "full-cone"),
+            NatType::Symmetric => write!(f, "symmetric"),
+        }
+    }
+}
+
+/// A NAT stand-in, relaying UDP packets between a node bound to the loopback interface and the
+/// data network.
+///
+/// Unlike a real NAT, the relay can't intercept the packets the node sends to a remote address.
+/// Instead, each remote address is mapped to an address on the private side, see `Relay::map`,
+/// and the node sends to the private address to reach the remote one. Likewise, the packets from
+/// a remote address reach the node from the private address it is mapped to.
+pub(crate) struct Relay {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    nat_type: NatType,
+    public_ip: IpAddr,
+    // The address the node behind the NAT is bound to.
+    internal: SocketAddr,
+    // The public socket shared by all the mappings of a full-cone NAT.
+    public: Option<Arc<UdpSocket>>,
+    // The private sockets, by the remote address they stand for.
+    private: Mutex<HashMap<SocketAddr, Arc<UdpSocket>>>,
+    // The inbound packets the NAT has filtered out.
+    dropped: AtomicU64,
+}
+
+impl Relay {
+    pub(crate) async fn new(
+        nat_type: NatType,
+        public_ip: IpAddr,
+        public_port: u16,
+        internal: SocketAddr,
+    ) -> std::io::Result<Self> {
+        let public = match nat_type {
+            NatType::FullCone => Some(Arc::new(
+                UdpSocket::bind(SocketAddr::new(public_ip, public_port)).await?,
+            )),
+            NatType::Symmetric => None,
+        };
+        let inner = Arc::new(Inner {
+            nat_type,
+            public_ip,
+            internal,
+            public: public.clone(),
+            private: Mutex::new(HashMap::new()),
+            dropped: AtomicU64::new(0),
+        });
+
+        // A full-cone NAT forwards the inbound traffic from anyone, mapping the unknown remote
+        // addresses on the fly.
+        if let Some(public) = public {
+            let inner = inner.clone();
+            tokio::spawn(async move {
+                let mut buffer = [0; MAX_PACKET_SIZE];
+                loop {
+                    let (length, src) = match public.recv_from(&mut buffer).await {
+                        Ok(received) => received,
+                        Err(e) => {
+                            warn!("Failed to receive a packet on the public side: {e}");
+                            continue;
+                        }
+                    };
+                    let private = match Inner::map(&inner, src).await {
+                        Ok(private) => private,
+                        Err(e) => {
+                            warn!("Failed to map {src}: {e}");
+                            continue;
+                        }
+                    };
+                    if let Err(e) = private.send_to(&buffer[..length], inner.internal).await {
+                        warn!("Failed to forward a packet to the node: {e}");
+                    }
+                }
+            });
+        }
+
+        Ok(Relay { inner })
+    }
+
+    /// Maps `remote` to an address on the private side, which the node sends to in order to
+    /// reach `remote`.
+    pub(crate) async fn map(&self, remote: SocketAddr) -> std::io::Result<SocketAddr> {
+        Inner::map(&self.inner, remote).await?.local_addr()
+    }
+
+    /// The number of the inbound packets the NAT has filtered out.
+    pub(crate) fn dropped(&self) -> u64 {
+        self.inner.dropped.load(Ordering::Relaxed)
+    }
+}
+
+impl Inner {
+    async fn map(inner: &Arc<Inner>, remote: SocketAddr) -> std::io::Result<Arc<UdpSocket>> {
+        if let Some(private) = inner
+            .private
+            .lock()
+            .expect("Lock the mappings")
+            .get(&remote)
+        {
+            return Ok(private.clone());
+        }
+
+        let private = Arc::new(UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await?);
+        let public = match &inner.public {
+            Some(public) => public.clone(),
+            None => Arc::new(UdpSocket::bind((inner.public_ip, 0)).await?),
+        };
+        inner
+            .private
+            .lock()
+            .expect("Lock the mappings")
+            .insert(remote, private.clone());
+
+        // Outbound: from the node to the remote address, through the public socket.
+        let outbound_private = private.clone();
+        let outbound_public = public.clone();
+        let internal = inner.internal;
+        tokio::spawn(async move {
+            let mut buffer = [0; MAX_PACKET_SIZE];
+            loop {
+                match outbound_private.recv_from(&mut buffer).await {
+                    Ok((length, src)) if src == internal => {
+                        if let Err(e) = outbound_public.send_to(&buffer[..length], remote).await {
+                            warn!("Failed to forward a packet to {remote}: {e}");
+                        }
+                    }
+                    Ok(_) => {}
+                    Err(e) => warn!("Failed to receive a packet on the private side: {e}"),
+                }
+            }
+        });
+
+        // Inbound: a symmetric NAT accepts only the packets from the remote address the public
+        // socket has been opened for.
+        if inner.nat_type == NatType::Symmetric {
+            let inbound_private = private.clone();
+            let inner = inner.clone();
+            tokio::spawn(async move {
+                let mut buffer = [0; MAX_PACKET_SIZE];
+                loop {
+                    match public.recv_from(&mut buffer).await {
+                        Ok((length, src)) if src == remote => {
+                            if let Err(e) =
+                                inbound_private.send_to(&buffer[..length], internal).await
+                            {
+                                warn!("Failed to forward a packet to the node: {e}");
+                            }
+                        }
+                        Ok(_) => {
+                            inner.dropped.fetch_add(1, Ordering::Relaxed);
+                        }
+                        Err(e) => warn!("Failed to receive a packet on the public side: {e}"),
+                    }
+                }
+            });
+        }
+
+        Ok(private)
+    }
+}
@@ -0,0 +1,76 @@
+use crate::capture;
+use std::net::{IpAddr, Ipv4Addr};
+use testground::client::Client;
+use testground::network_conf::{
+    FilterAction, LinkShape, NetworkConfiguration, RoutingPolicyType, DEFAULT_DATA_NETWORK,
+};
+
+// The limits discv5 applies to the nodes from the same /24 subnet when `ip_limit` is enabled. See
+// `discv5::kbucket::filter`.
+pub(crate) const MAX_NODES_PER_SUBNET_BUCKET: usize = 2;
+pub(crate) const MAX_NODES_PER_SUBNET_TABLE: usize = 10;
+
+/// The IPv4 addresses on the data network which are not in `used`, in ascending order.
+pub(crate) fn unused_ips<'a>(
+    client: &Client,
+    used: &'a [Ipv4Addr],
+) -> impl Iterator<Item = Ipv4Addr> + 'a {
+    client
+        .run_parameters()
+        .test_subnet
+        .iter()
+        // Skip the network address and the first one. The first one is reserved by Testground.
+        .skip(2)
+        .filter_map(|ip| match ip {
+            IpAddr::V4(ipv4) => Some(ipv4),
+            IpAddr::V6(_) => None,
+        })
+        .filter(move |ip| !used.contains(ip))
+}
+
+/// Changes the IP address of this instance on the data network, and waits until
+/// `callback_target` instances have applied their network configuration with the same
+/// `callback_state`.
+pub(crate) async fn assign_ip(
+    client: &Client,
+    ip: IpAddr,
+    callback_state: &str,
+    callback_target: u64,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let subnet = client.run_parameters().test_subnet;
+
+    client
+        .configure_network(NetworkConfiguration {
+            network: DEFAULT_DATA_NETWORK.to_owned(),
+            ipv4: Some(format!("{}/{}", ip, subnet.prefix()).parse().unwrap()),
+            ipv6: None,
+            enable: true,
+            default: LinkShape {
+                latency: client
+                    .run_parameters()
+                    .test_instance_params
+                    .get("latency")
+                    .ok_or("latency is not specified")?
+                    .parse::<u64>()?
+                    * 1_000_000, // Translate from millisecond to nanosecond
+                jitter: 0,
+                bandwidth: 1048576, // 1Mib
+                filter: FilterAction::Accept,
+                loss: 0.0,
+                corrupt: 0.0,
+                corrupt_corr: 0.0,
+                reorder: 0.0,
+                reorder_corr: 0.0,
+                duplicate: 0.0,
+                duplicate_corr: 0.0,
+            },
+            rules: None,
+            callback_state: callback_state.to_owned(),
+            callback_target: Some(callback_target),
+            routing_policy: RoutingPolicyType::DenyAll,
+        })
+        .await?;
+    capture::set_local_ip(ip);
+
+    Ok(())
+}
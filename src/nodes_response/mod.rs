@@ -0,0 +1,427 @@
+use crate::capture;
+use crate::mock::{
+    Action, Behaviour, Behaviours, CustomResponseId, Expect, Mock, NodesResponse, NodesTotal,
+    PacketSize, Request, Response, Step,
+};
+use crate::utils::publish_and_collect;
+use discv5::enr::{CombinedKey, NodeId};
+use discv5::{Discv5, Enr, Event, Key, ListenConfig};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashSet, VecDeque};
+use std::net::Ipv4Addr;
+use std::time::Duration;
+use testground::client::Client;
+use tokio::sync::mpsc;
+use tracing::{error, info};
+
+const STATE_DISCV5_STARTED: &str = "state_discv5_started";
+const STATE_FINISHED: &str = "state_finished";
+
+// The maximum number of ENRs the discv5 node collects from the NODES responses to a request.
+const MAX_NODES_RESPONSE: usize = 16;
+
+// The number of ENRs in each response. They take more than a packet.
+const NUM_NODES: usize = 16;
+
+// The number of ENRs in each of the responses with a wrong id, and in the valid one.
+const NUM_NODES_PER_ID: usize = 4;
+
+/// The NODES responses the mock sends, in order, to the FINDNODE requests of the lookups run by
+/// the discv5 node.
+#[derive(Clone, Copy, Debug)]
+enum NodesCase {
+    /// The ENRs are split into the packets they fit in.
+    Split,
+    /// The ENRs are split into several packets, but `total` tells there is only one.
+    TotalTooLow,
+    /// Responses with a random id, the id following that of the request, and an id longer than
+    /// 8 bytes, followed by a valid one.
+    WrongIds,
+    /// More ENRs than the discv5 node collects for a request.
+    TooManyNodes,
+    /// The ENRs are put into a single packet which exceeds the maximum packet size.
+    Oversized,
+}
+
+const NODES_CASES: [NodesCase; 5] = [
+    NodesCase::Split,
+    NodesCase::TotalTooLow,
+    NodesCase::WrongIds,
+    NodesCase::TooManyNodes,
+    NodesCase::Oversized,
+];
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct InstanceInfo {
+    // The sequence number of this test instance within the test.
+    seq: u64,
+    enr: Enr,
+    // The target of the lookups and the ENRs the mock responds with. Published by the mock.
+    responses: Option<Responses>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct Responses {
+    target: Enr,
+    // The ENRs in each case, in the order of `NODES_CASES`.
+    cases: Vec<CaseEnrs>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct CaseEnrs {
+    // The ENRs in the responses with a wrong id.
+    invalid: Vec<Enr>,
+    // The ENRs in the response to the request.
+    valid: Vec<Enr>,
+}
+
+pub(crate) async fn run(client: Client) -> Result<(), Box<dyn std::error::Error>> {
+    let run_parameters = client.run_parameters();
+    let ip = run_parameters
+        .data_network_ip()?
+        .expect("IP address for the data network");
+
+    // ////////////////////////
+    // Construct local Enr
+    // ////////////////////////
+    let enr_key = CombinedKey::generate_secp256k1();
+    let enr = Enr::builder()
+        .ip(ip)
+        .udp4(9000)
+        .build(&enr_key)
+        .expect("enr");
+
+    // //////////////////////////////////////////////////////////////
+    // Collect information of all participants in the test case
+    // //////////////////////////////////////////////////////////////
+    let instance_info = InstanceInfo {
+        seq: client.global_seq(),
+        enr: enr.clone(),
+        responses: if client.global_seq() == 2 {
+            Some(Responses::generate(&enr.node_id()))
+        } else {
+            None
+        },
+    };
+    client.record_message(format!(
+        "seq: {}, node_id: {}, ip: {}",
+        instance_info.seq,
+        instance_info.enr.node_id(),
+        ip
+    ));
+
+    let participants = publish_and_collect(&client, instance_info).await?;
+    capture::register_enrs(participants.iter().map(|p| &p.enr));
+    let another_instance_info = participants
+        .iter()
+        .find(|p| p.seq != client.global_seq())
+        .expect("Another instance")
+        .clone();
+    let responses = participants
+        .into_iter()
+        .find_map(|p| p.responses)
+        .expect("Responses of the mock");
+
+    // ////////////////////////
+    // Discv5 config
+    // ////////////////////////
+    let listen_config = ListenConfig::Ipv4 {
+        ip: Ipv4Addr::UNSPECIFIED,
+        port: 9000,
+    };
+    let config = discv5::ConfigBuilder::new(listen_config)
+        .request_timeout(Duration::from_secs(3))
+        .max_nodes_response(MAX_NODES_RESPONSE)
+        .build();
+
+    match client.global_seq() {
+        1 => {
+            run_discv5(
+                client,
+                enr,
+                enr_key,
+                config,
+                another_instance_info,
+                responses,
+            )
+            .await?
+        }
+        2 => run_mock(client, enr, enr_key, config, responses).await?,
+        _ => unreachable!(),
+    }
+
+    Ok(())
+}
+
+async fn run_discv5(
+    client: Client,
+    enr: Enr,
+    enr_key: CombinedKey,
+    config: discv5::Config,
+    mock: InstanceInfo,
+    responses: Responses,
+) -> Result<(), Box<dyn std::error::Error>> {
+    // ////////////////////////
+    // Start discv5
+    // ////////////////////////
+    let mut discv5: Discv5 = Discv5::new(enr, enr_key, config)?;
+    // The mock is the only node in the routing table, so each lookup starts with asking it.
+    discv5.add_enr(mock.enr.clone())?;
+    discv5.start().await.expect("Start Discovery v5 server");
+
+    // Record the nodes discovered from the NODES responses.
+    let (discovered_send, mut discovered_recv) = mpsc::unbounded_channel();
+    let mut event_stream = discv5.event_stream().await.expect("Discv5Event");
+    tokio::spawn(async move {
+        while let Some(event) = event_stream.recv().await {
+            if let Event::Discovered(enr) = event {
+                let _ = discovered_send.send(enr.node_id());
+            }
+        }
+    });
+
+    client
+        .signal_and_wait(
+            STATE_DISCV5_STARTED,
+            client.run_parameters().test_instance_count,
+        )
+        .await?;
+
+    // //////////////////////////////////////////////////////////////
+    // Run a lookup for each case
+    // //////////////////////////////////////////////////////////////
+    // The discovered nodes have no socket address, so the lookups end as soon as the mock has
+    // responded.
+    let mut errors = vec![];
+    for (case, enrs) in NODES_CASES.iter().zip(responses.cases.iter()) {
+        match discv5.find_node(responses.target.node_id()).await {
+            Ok(found) => info!("{case:?}: The lookup has found {} nodes.", found.len()),
+            Err(e) => info!("{case:?}: The lookup has failed: {e:?}"),
+        }
+
+        // The events may arrive after the lookup has returned, so wait until they stop coming.
+        let mut discovered = HashSet::new();
+        while let Ok(Some(node_id)) =
+            tokio::time::timeout(Duration::from_millis(500), discovered_recv.recv()).await
+        {
+            discovered.insert(node_id);
+        }
+        client.record_message(format!(
+            "{case:?}: discovered: {}, valid: {}, invalid: {}",
+            discovered.len(),
+            enrs.valid.len(),
+            enrs.invalid.len()
+        ));
+        if let Err(e) = case.check(enrs, &discovered) {
+            errors.push(format!("{case:?}: {e}"));
+        }
+    }
+
+    client
+        .signal_and_wait(STATE_FINISHED, client.run_parameters().test_instance_count)
+        .await?;
+
+    if errors.is_empty() {
+        client.record_success().await?;
+    } else {
+        for e in errors.iter() {
+            error!("{e}");
+        }
+        client.record_failure(errors.join(", ")).await?;
+    }
+    Ok(())
+}
+
+async fn run_mock(
+    client: Client,
+    enr: Enr,
+    enr_key: CombinedKey,
+    config: discv5::Config,
+    responses: Responses,
+) -> Result<(), Box<dyn std::error::Error>> {
+    // ////////////////////////
+    // Start mock
+    // ////////////////////////
+    // The first FINDNODE request is sent in the handshake, and the rest over the session.
+    let mut behaviours = VecDeque::new();
+    behaviours.push_back(Step::Ordered(Behaviour {
+        expect: Expect::MessageWithoutSession,
+        actions: vec![Action::SendWhoAreYou],
+    }));
+    for (i, (case, enrs)) in NODES_CASES.iter().zip(responses.cases).enumerate() {
+        let mut actions = case.actions(enrs);
+        behaviours.push_back(Step::Ordered(if i == 0 {
+            actions.insert(0, Action::EstablishSession);
+            Behaviour {
+                expect: Expect::Handshake(Request::FindNode),
+                actions,
+            }
+        } else {
+            Behaviour {
+                expect: Expect::Message(Request::FindNode),
+                actions,
+            }
+        }));
+    }
+    // The discv5 node can't decrypt the truncated packet, so it drops the session and asks the
+    // mock to start a new handshake.
+    behaviours.push_back(Step::Ordered(Behaviour {
+        expect: Expect::WhoAreYou,
+        actions: vec![Action::Ignore(
+            "The oversized response has been dropped.".to_string(),
+        )],
+    }));
+    let _mock = Mock::start(enr, enr_key, config, Behaviours::Sequential(behaviours)).await;
+
+    client
+        .signal_and_wait(
+            STATE_DISCV5_STARTED,
+            client.run_parameters().test_instance_count,
+        )
+        .await?;
+
+    client
+        .signal_and_wait(STATE_FINISHED, client.run_parameters().test_instance_count)
+        .await?;
+
+    client.record_success().await?;
+    Ok(())
+}
+
+impl Responses {
+    /// Generates the target of the lookups and the ENRs of the responses. All of them are at the
+    /// log2 distance 256 from the mock, which is one of the distances requested by the lookups.
+    fn generate(mock: &NodeId) -> Self {
+        let mock_key: Key<NodeId> = (*mock).into();
+        let generate_enrs = |n: usize| {
+            let mut enrs = vec![];
+            while enrs.len() < n {
+                // The ENRs have no socket address, so the discv5 node doesn't contact them.
+                let enr = Enr::builder()
+                    .build(&CombinedKey::generate_secp256k1())
+                    .expect("enr");
+                if mock_key.log2_distance(&enr.node_id().into()) == Some(256) {
+                    enrs.push(enr);
+                }
+            }
+            enrs
+        };
+
+        let target = generate_enrs(1).remove(0);
+        let cases = NODES_CASES
+            .iter()
+            .map(|case| match case {
+                NodesCase::WrongIds => CaseEnrs {
+                    invalid: generate_enrs(NUM_NODES_PER_ID * 3),
+                    valid: generate_enrs(NUM_NODES_PER_ID),
+                },
+                NodesCase::TooManyNodes => CaseEnrs {
+                    invalid: vec![],
+                    valid: generate_enrs(MAX_NODES_RESPONSE * 3),
+                },
+                _ => CaseEnrs {
+                    invalid: vec![],
+                    valid: generate_enrs(NUM_NODES),
+                },
+            })
+            .collect();
+
+        Responses { target, cases }
+    }
+}
+
+impl NodesCase {
+    /// The actions of the mock in reply to the FINDNODE request.
+    fn actions(&self, enrs: CaseEnrs) -> Vec<Action> {
+        let nodes = |id: CustomResponseId, nodes: Vec<Enr>, total, packet_size| {
+            Action::SendResponse(Response::Nodes(NodesResponse {
+                id,
+                nodes,
+                total,
+                packet_size,
+            }))
+        };
+
+        match self {
+            NodesCase::Split | NodesCase::TooManyNodes => vec![nodes(
+                CustomResponseId::InboundRequestId,
+                enrs.valid,
+                NodesTotal::Actual,
+                PacketSize::Limited,
+            )],
+            NodesCase::TotalTooLow => vec![nodes(
+                CustomResponseId::InboundRequestId,
+                enrs.valid,
+                NodesTotal::Fixed(1),
+                PacketSize::Limited,
+            )],
+            NodesCase::WrongIds => {
+                let mut invalid = enrs.invalid.chunks(NUM_NODES_PER_ID);
+                let mut wrong_id = |id| {
+                    nodes(
+                        id,
+                        invalid.next().expect("ENRs").to_vec(),
+                        NodesTotal::Actual,
+                        PacketSize::Limited,
+                    )
+                };
+                vec![
+                    Action::CaptureRequest,
+                    wrong_id(CustomResponseId::Random),
+                    wrong_id(CustomResponseId::NextRequestId(0)),
+                    wrong_id(CustomResponseId::Raw(vec![0; 9])),
+                    nodes(
+                        CustomResponseId::CapturedRequestId(0),
+                        enrs.valid,
+                        NodesTotal::Actual,
+                        PacketSize::Limited,
+                    ),
+                ]
+            }
+            NodesCase::Oversized => vec![nodes(
+                CustomResponseId::InboundRequestId,
+                enrs.valid,
+                NodesTotal::Actual,
+                PacketSize::Unlimited,
+            )],
+        }
+    }
+
+    /// Checks the nodes the discv5 node has discovered from the responses.
+    fn check(&self, enrs: &CaseEnrs, discovered: &HashSet<NodeId>) -> Result<(), String> {
+        let valid = enrs.valid.iter().map(Enr::node_id).collect::<HashSet<_>>();
+        if !discovered.is_subset(&valid) {
+            return Err(format!(
+                "{} nodes have been discovered which are not in the response to the request.",
+                discovered.difference(&valid).count()
+            ));
+        }
+
+        let (ok, expected) = match self {
+            NodesCase::Split | NodesCase::WrongIds => (
+                discovered.len() == valid.len(),
+                "all the nodes in the valid response".to_string(),
+            ),
+            // Only the first packet is taken.
+            NodesCase::TotalTooLow => (
+                !discovered.is_empty() && discovered.len() < valid.len(),
+                format!("at least one, fewer than {}", valid.len()),
+            ),
+            // The packets are collected until the limit has been reached.
+            NodesCase::TooManyNodes => (
+                discovered.len() >= MAX_NODES_RESPONSE && discovered.len() < valid.len(),
+                format!("at least {MAX_NODES_RESPONSE}, fewer than {}", valid.len()),
+            ),
+            NodesCase::Oversized => (discovered.is_empty(), "none".to_string()),
+        };
+
+        if ok {
+            Ok(())
+        } else {
+            Err(format!(
+                "Unexpected number of nodes discovered. expected:{expected}, actual:{}",
+                discovered.len()
+            ))
+        }
+    }
+}
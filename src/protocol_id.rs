@@ -0,0 +1,33 @@
+use discv5::ProtocolIdentity;
+use std::str::FromStr;
+
+/// A protocol id other than the default one, to run an overlay network which is isolated from the
+/// default one on the same data network.
+pub(crate) struct AltProtocolId;
+
+impl ProtocolIdentity for AltProtocolId {
+    const PROTOCOL_ID_BYTES: [u8; 6] = *b"discvx";
+    const PROTOCOL_VERSION_BYTES: [u8; 2] = 0x0001_u16.to_be_bytes();
+}
+
+/// The protocol id an instance runs with, selected by the `protocol_id` test parameter. The
+/// instances are generic over `ProtocolIdentity`, so each value maps to a type.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum ProtocolId {
+    /// `discv5`, i.e. `DefaultProtocolId`.
+    Default,
+    /// `discvx`, i.e. `AltProtocolId`.
+    Alt,
+}
+
+impl FromStr for ProtocolId {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "discv5" => Ok(ProtocolId::Default),
+            "discvx" => Ok(ProtocolId::Alt),
+            _ => Err(format!("Unknown protocol id: {s}")),
+        }
+    }
+}
@@ -0,0 +1,292 @@
+use crate::capture;
+use crate::mock::{Action, Behaviours, DeclarativeBehaviour, Mock};
+use crate::protocol_id::{AltProtocolId, ProtocolId};
+use crate::utils::{cpu_time, get_param, publish_and_collect};
+use chrono::Local;
+use discv5::enr::{CombinedKey, NodeId};
+use discv5::{ConnectionState, DefaultProtocolId, Discv5, Enr, ListenConfig, ProtocolIdentity};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashSet};
+use std::net::{IpAddr, Ipv4Addr};
+use std::time::{Duration, Instant};
+use testground::client::Client;
+use testground::WriteQuery;
+use tracing::{error, info, warn};
+
+const STATE_TABLE_SEEDED: &str = "state_table_seeded";
+const STATE_LOOKUP_DONE: &str = "state_lookup_done";
+const STATE_BASELINE_MEASURED: &str = "state_baseline_measured";
+const STATE_PACKETS_SENT: &str = "state_packets_sent";
+const STATE_DONE: &str = "state_done";
+
+// The duration the idle instances measure their CPU usage for, as the baseline.
+const BASELINE_DURATION: Duration = Duration::from_secs(3);
+
+// The ports of the discv5 node and the mock in each instance.
+const DISCV5_PORT: u16 = 9000;
+const MOCK_PORT: u16 = 9001;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct InstanceInfo {
+    // The group the instance belongs to, which is the overlay network.
+    network: String,
+    enr: Enr,
+    mock_enr: Enr,
+}
+
+pub(crate) async fn run(client: Client) -> Result<(), Box<dyn std::error::Error>> {
+    let run_parameters = client.run_parameters();
+    let ip = run_parameters
+        .data_network_ip()?
+        .expect("IP address for the data network");
+    let protocol_id: ProtocolId = get_param("protocol_id", &run_parameters.test_instance_params)?;
+    client.record_message(format!(
+        "network: {}, protocol_id: {:?}",
+        run_parameters.test_group_id, protocol_id
+    ));
+
+    match protocol_id {
+        ProtocolId::Default => run_node::<DefaultProtocolId>(client, ip).await,
+        ProtocolId::Alt => run_node::<AltProtocolId>(client, ip).await,
+    }
+}
+
+async fn run_node<P: ProtocolIdentity + Send + 'static>(
+    client: Client,
+    ip: IpAddr,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let run_parameters = client.run_parameters();
+    let network = run_parameters.test_group_id.clone();
+    let num_packets: usize = get_param("num_packets", &run_parameters.test_instance_params)?;
+
+    // ////////////////////////
+    // Start discv5 and the mock
+    // ////////////////////////
+    // Both speak the protocol id of the network. The mock sends the packets to the other network.
+    let enr_key = CombinedKey::generate_secp256k1();
+    let enr = Enr::builder()
+        .ip(ip)
+        .udp4(DISCV5_PORT)
+        .build(&enr_key)
+        .expect("Construct an Enr");
+    let config = discv5::ConfigBuilder::new(ListenConfig::Ipv4 {
+        ip: Ipv4Addr::UNSPECIFIED,
+        port: DISCV5_PORT,
+    })
+    .build();
+    let mut discv5: Discv5<P> = Discv5::new(enr.clone(), enr_key, config)?;
+    discv5.start().await.expect("Start Discovery v5 server");
+
+    let mock_key = CombinedKey::generate_secp256k1();
+    let mock_enr = Enr::builder()
+        .ip(ip)
+        .udp4(MOCK_PORT)
+        .build(&mock_key)
+        .expect("Construct an Enr");
+    let mock_config = discv5::ConfigBuilder::new(ListenConfig::Ipv4 {
+        ip: Ipv4Addr::UNSPECIFIED,
+        port: MOCK_PORT,
+    })
+    .build();
+    let behaviours = Behaviours::Declarative(DeclarativeBehaviour {
+        whoareyou: vec![Action::Ignore("Counted in the stats".to_string())],
+        handshake: vec![Action::Ignore("Unexpected handshake".to_string())],
+        message: vec![Action::Ignore("Unexpected message".to_string())],
+        message_without_session: vec![Action::Ignore("Unexpected message".to_string())],
+    });
+    let mut mock =
+        Mock::start_with_protocol_id::<P>(mock_enr.clone(), mock_key, mock_config, behaviours)
+            .await;
+
+    // //////////////////////////////////////////////////////////////
+    // Collect information of all participants in the test case
+    // //////////////////////////////////////////////////////////////
+    let participants = publish_and_collect(
+        &client,
+        InstanceInfo {
+            network: network.clone(),
+            enr: enr.clone(),
+            mock_enr,
+        },
+    )
+    .await?;
+    capture::register_enrs(participants.iter().flat_map(|p| [&p.enr, &p.mock_enr]));
+    let other_network = participants
+        .iter()
+        .filter(|p| p.network != network)
+        .map(|p| p.enr.clone())
+        .collect::<Vec<_>>();
+    let other_network_ids = other_network
+        .iter()
+        .map(|enr| enr.node_id())
+        .collect::<HashSet<_>>();
+
+    // Seed the routing table with the nodes of both networks, as if they were found through a
+    // bootstrap node shared by the networks.
+    for p in participants.iter().filter(|p| p.enr != enr) {
+        if let Err(e) = discv5.add_enr(p.enr.clone()) {
+            error!("Failed to add an ENR: {e}");
+        }
+    }
+
+    client
+        .signal_and_wait(STATE_TABLE_SEEDED, run_parameters.test_instance_count)
+        .await?;
+
+    // //////////////////////////////////////////////////////////////
+    // Run a lookup
+    // //////////////////////////////////////////////////////////////
+    let mut errors = vec![];
+    let started_at = Instant::now();
+    let found = match discv5.find_node(NodeId::random()).await {
+        Ok(found) => found,
+        Err(e) => {
+            errors.push(format!("Failed to run a lookup: {e}"));
+            vec![]
+        }
+    };
+    let lookup_duration = started_at.elapsed();
+    let crossed = found
+        .iter()
+        .filter(|enr| other_network_ids.contains(&enr.node_id()))
+        .count();
+    info!(
+        "Lookup finished. found: {}, crossed: {crossed}, duration: {lookup_duration:?}",
+        found.len()
+    );
+    if crossed > 0 {
+        errors.push(format!(
+            "The lookup has returned {crossed} nodes of the other network."
+        ));
+    }
+
+    client
+        .signal_and_wait(STATE_LOOKUP_DONE, run_parameters.test_instance_count)
+        .await?;
+
+    // //////////////////////////////////////////////////////////////
+    // Measure the CPU usage while idle
+    // //////////////////////////////////////////////////////////////
+    let cpu_time_start = cpu_time();
+    let started_at = Instant::now();
+    tokio::time::sleep(BASELINE_DURATION).await;
+    let baseline_cpu_usage = cpu_usage(cpu_time_start, started_at);
+
+    client
+        .signal_and_wait(STATE_BASELINE_MEASURED, run_parameters.test_instance_count)
+        .await?;
+
+    // //////////////////////////////////////////////////////////////
+    // Send packets from the wrong network
+    // //////////////////////////////////////////////////////////////
+    // A random packet to the node in this instance shows that the mock is heard in its own
+    // network. The nodes of the other network should drop the packets when decoding the header,
+    // without answering them.
+    mock.send_random_packet(enr.clone())?;
+    tokio::time::sleep(Duration::from_secs(2)).await;
+    let own_whoareyou = mock.stats().await?.whoareyou_received;
+    if own_whoareyou == 0 {
+        errors.push("The own network hasn't answered the random packet.".to_string());
+    }
+
+    // The networks send the packets in turn, so that the CPU usage of the receivers doesn't
+    // include the cost of sending.
+    let networks = participants
+        .iter()
+        .map(|p| p.network.clone())
+        .collect::<BTreeSet<_>>();
+    let mut cross_whoareyou = 0;
+    let mut cross_cpu_usage = None;
+    for sender in networks {
+        let cpu_time_start = cpu_time();
+        let started_at = Instant::now();
+        if sender == network {
+            for peer in other_network.iter() {
+                mock.send_random_packets(peer.clone(), mock.alias_node_ids(num_packets))?;
+            }
+            // Wait for the WHOAREYOUs in flight.
+            tokio::time::sleep(Duration::from_secs(3)).await;
+            cross_whoareyou = mock.stats().await?.whoareyou_received - own_whoareyou;
+            if cross_whoareyou > 0 {
+                errors.push(format!(
+                    "The other network has answered {cross_whoareyou} packets."
+                ));
+            }
+        }
+
+        client
+            .signal_and_wait(
+                format!("{STATE_PACKETS_SENT}_{sender}"),
+                run_parameters.test_instance_count,
+            )
+            .await?;
+        if sender != network {
+            cross_cpu_usage = cpu_usage(cpu_time_start, started_at);
+        }
+    }
+    info!(
+        "CPU usage. baseline: {baseline_cpu_usage:?}, cross-network packets: {cross_cpu_usage:?}"
+    );
+
+    // None of the nodes of the other network should have been connected.
+    let cross_connected = discv5
+        .table_entries()
+        .into_iter()
+        .filter(|(node_id, _, status)| {
+            other_network_ids.contains(node_id) && status.state == ConnectionState::Connected
+        })
+        .count();
+    if cross_connected > 0 {
+        errors.push(format!(
+            "{cross_connected} nodes of the other network are connected in the routing table."
+        ));
+    }
+
+    // //////////////////////////////////////////////////////////////
+    // Record metrics
+    // //////////////////////////////////////////////////////////////
+    let mut write_query = WriteQuery::new(
+        Local::now().into(),
+        format!(
+            "discv5-testground_{}_{}",
+            run_parameters.test_case, run_parameters.test_run
+        ),
+    )
+    .add_field("found", found.len() as u64)
+    .add_field("crossed", crossed as u64)
+    .add_field("lookup_duration_ms", lookup_duration.as_millis() as u64)
+    .add_field("cross_packets", (num_packets * other_network.len()) as u64)
+    .add_field("cross_whoareyou", cross_whoareyou)
+    .add_field("cross_connected", cross_connected as u64)
+    .add_tag("network", network);
+    match (baseline_cpu_usage, cross_cpu_usage) {
+        (Some(baseline), Some(cross)) => {
+            write_query = write_query
+                .add_field("baseline_cpu_usage", baseline)
+                .add_field("cross_cpu_usage", cross)
+                .add_field("cross_cpu_usage_over_baseline", cross - baseline);
+        }
+        _ => warn!("Failed to read the CPU time of the process."),
+    }
+    client.record_metric(write_query).await?;
+
+    client
+        .signal_and_wait(STATE_DONE, run_parameters.test_instance_count)
+        .await?;
+
+    if errors.is_empty() {
+        client.record_success().await?;
+    } else {
+        for e in errors.iter() {
+            error!("{e}");
+        }
+        client.record_failure(errors.join(", ")).await?;
+    }
+    Ok(())
+}
+
+/// Returns the share of a single core the process has used since the given time.
+fn cpu_usage(cpu_time_start: Option<Duration>, started_at: Instant) -> Option<f64> {
+    let cpu_time = cpu_time()?.checked_sub(cpu_time_start?)?;
+    Some(cpu_time.as_secs_f64() / started_at.elapsed().as_secs_f64())
+}
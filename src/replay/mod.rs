@@ -0,0 +1,283 @@
+use crate::capture;
+use crate::mock::{Action, Behaviours, DeclarativeBehaviour, Mock, PacketType, Replay};
+use crate::utils::{get_param, publish_and_collect};
+use discv5::enr::CombinedKey;
+use discv5::rpc::RequestBody;
+use discv5::{Discv5, Enr, Event, ListenConfig};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::Ipv4Addr;
+use std::time::Duration;
+use testground::client::Client;
+use tokio::sync::mpsc;
+use tracing::{error, info};
+
+const STATE_DISCV5_STARTED: &str = "state_discv5_started";
+const STATE_REPLAYED: &str = "state_replayed";
+const STATE_FINISHED: &str = "state_finished";
+
+const TALK_PROTOCOL: &[u8] = b"PROTOCOL";
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct InstanceInfo {
+    // The sequence number of this test instance within the test.
+    seq: u64,
+    enr: Enr,
+}
+
+pub(crate) async fn run(client: Client) -> Result<(), Box<dyn std::error::Error>> {
+    let run_parameters = client.run_parameters();
+    let ip = run_parameters
+        .data_network_ip()?
+        .expect("IP address for the data network");
+    let session_timeout =
+        get_param::<u64>("session_timeout", &run_parameters.test_instance_params)?;
+
+    // ////////////////////////
+    // Construct local Enr
+    // ////////////////////////
+    let enr_key = CombinedKey::generate_secp256k1();
+    let enr = Enr::builder()
+        .ip(ip)
+        .udp4(9000)
+        .build(&enr_key)
+        .expect("enr");
+
+    // //////////////////////////////////////////////////////////////
+    // Collect information of all participants in the test case
+    // //////////////////////////////////////////////////////////////
+    let instance_info = InstanceInfo {
+        seq: client.global_seq(),
+        enr: enr.clone(),
+    };
+    client.record_message(format!(
+        "seq: {}, node_id: {}, ip: {}",
+        instance_info.seq,
+        instance_info.enr.node_id(),
+        ip
+    ));
+
+    let participants = publish_and_collect(&client, instance_info).await?;
+    capture::register_enrs(participants.iter().map(|p| &p.enr));
+    let another_instance_info = participants
+        .into_iter()
+        .find(|p| p.seq != client.global_seq())
+        .expect("Another instance");
+
+    // ////////////////////////
+    // Discv5 config
+    // ////////////////////////
+    let listen_config = ListenConfig::Ipv4 {
+        ip: Ipv4Addr::UNSPECIFIED,
+        port: 9000,
+    };
+    let config = discv5::ConfigBuilder::new(listen_config)
+        .session_timeout(Duration::from_secs(session_timeout))
+        .build();
+
+    match client.global_seq() {
+        1 => run_discv5(client, enr, enr_key, config).await?,
+        2 => {
+            run_mock(
+                client,
+                enr,
+                enr_key,
+                config,
+                another_instance_info,
+                session_timeout,
+            )
+            .await?
+        }
+        _ => unreachable!(),
+    }
+
+    Ok(())
+}
+
+async fn run_discv5(
+    client: Client,
+    enr: Enr,
+    enr_key: CombinedKey,
+    config: discv5::Config,
+) -> Result<(), Box<dyn std::error::Error>> {
+    // ////////////////////////
+    // Start discv5
+    // ////////////////////////
+    let mut discv5: Discv5 = Discv5::new(enr, enr_key, config)?;
+    discv5.start().await.expect("Start Discovery v5 server");
+
+    // Observe the sessions established and the TALKREQs delivered.
+    let (events_send, mut events_recv) = mpsc::unbounded_channel();
+    let mut event_stream = discv5.event_stream().await.expect("Discv5Event");
+    tokio::spawn(async move {
+        while let Some(event) = event_stream.recv().await {
+            match event {
+                Event::SessionEstablished(enr, socket_addr) => {
+                    info!("SessionEstablished: {} {}", enr.node_id(), socket_addr);
+                    let _ = events_send.send(None);
+                }
+                Event::TalkRequest(talk_request) => {
+                    info!("TalkRequest: {:?}", talk_request);
+                    let _ = events_send.send(Some(talk_request.body().to_vec()));
+                    let response = talk_request.body().to_vec();
+                    if let Err(e) = talk_request.respond(response) {
+                        error!("Failed to send response: {e:?}");
+                    }
+                }
+                _ => {}
+            }
+        }
+    });
+
+    client
+        .signal_and_wait(
+            STATE_DISCV5_STARTED,
+            client.run_parameters().test_instance_count,
+        )
+        .await?;
+
+    // Wait until the mock has replayed the packets.
+    client
+        .signal_and_wait(STATE_REPLAYED, client.run_parameters().test_instance_count)
+        .await?;
+
+    // //////////////////////////////////////////////////////////////
+    // Check the events
+    // //////////////////////////////////////////////////////////////
+    let mut sessions_established = 0;
+    let mut talk_requests: HashMap<Vec<u8>, usize> = HashMap::new();
+    while let Ok(event) = events_recv.try_recv() {
+        match event {
+            None => sessions_established += 1,
+            Some(body) => *talk_requests.entry(body).or_default() += 1,
+        }
+    }
+
+    let mut errors = vec![];
+    if sessions_established != 1 {
+        errors.push(format!(
+            "Expected exactly one session to be established, but {sessions_established} sessions have been established."
+        ));
+    }
+    for (body, count) in talk_requests.iter() {
+        if *count > 1 {
+            errors.push(format!(
+                "The TALKREQ {body:?} has been delivered {count} times."
+            ));
+        }
+    }
+    if talk_requests.len() != 2 {
+        errors.push(format!(
+            "Expected two distinct TALKREQs to be delivered, but got {}.",
+            talk_requests.len()
+        ));
+    }
+
+    client
+        .signal_and_wait(STATE_FINISHED, client.run_parameters().test_instance_count)
+        .await?;
+
+    if errors.is_empty() {
+        client.record_success().await?;
+    } else {
+        for e in errors.iter() {
+            error!("{e}");
+        }
+        client.record_failure(errors.join(", ")).await?;
+    }
+    Ok(())
+}
+
+async fn run_mock(
+    client: Client,
+    enr: Enr,
+    enr_key: CombinedKey,
+    config: discv5::Config,
+    discv5_node: InstanceInfo,
+    session_timeout: u64,
+) -> Result<(), Box<dyn std::error::Error>> {
+    // ////////////////////////
+    // Start mock
+    // ////////////////////////
+    // The mock initiates the session by itself, and ignores the packets in reply to the replays.
+    let behaviours = Behaviours::Declarative(DeclarativeBehaviour {
+        whoareyou: vec![Action::Ignore(
+            "WHOAREYOU in reply to a replayed packet".to_string(),
+        )],
+        handshake: vec![Action::Ignore("Unexpected handshake".to_string())],
+        message: vec![Action::Ignore("Unexpected message".to_string())],
+        message_without_session: vec![Action::Ignore(
+            "Unexpected message without session".to_string(),
+        )],
+    });
+    let mut mock = Mock::start(enr, enr_key, config, behaviours).await;
+
+    client
+        .signal_and_wait(
+            STATE_DISCV5_STARTED,
+            client.run_parameters().test_instance_count,
+        )
+        .await?;
+
+    // //////////////////////////////////////////////////////////////
+    // Send TALKREQs to capture a handshake packet and a message packet
+    // //////////////////////////////////////////////////////////////
+    let mut errors = vec![];
+    for i in 0..2u8 {
+        // The first request is sent with the handshake, and the second one is sent over the
+        // established session.
+        let body = RequestBody::Talk {
+            protocol: TALK_PROTOCOL.to_vec(),
+            request: vec![i],
+        };
+        if let Err(e) = mock.send_request(discv5_node.enr.clone(), body) {
+            errors.push(e);
+            continue;
+        }
+        match tokio::time::timeout(Duration::from_secs(5), mock.recv_response()).await {
+            Ok(Some((_, response))) => info!("Response: {:?}", response),
+            _ => errors.push(format!("No response to the TALKREQ {i}.")),
+        }
+    }
+
+    // //////////////////////////////////////////////////////////////
+    // Replay the packets
+    // //////////////////////////////////////////////////////////////
+    // An old handshake packet. This must not create another session.
+    replay(&mut mock, PacketType::Handshake, &mut errors).await;
+    // The message with the same nonce. This must not deliver the TALKREQ again.
+    replay(&mut mock, PacketType::Message, &mut errors).await;
+    // A message from the expired session. The discv5 node should reply with WHOAREYOU.
+    tokio::time::sleep(Duration::from_secs(session_timeout + 1)).await;
+    let whoareyou_received = mock.stats().await?.whoareyou_received;
+    replay(&mut mock, PacketType::Message, &mut errors).await;
+    if mock.stats().await?.whoareyou_received == whoareyou_received {
+        errors.push(
+            "The replayed message from the expired session has not been answered with WHOAREYOU."
+                .to_string(),
+        );
+    }
+
+    client
+        .signal_and_wait(STATE_REPLAYED, client.run_parameters().test_instance_count)
+        .await?;
+
+    client
+        .signal_and_wait(STATE_FINISHED, client.run_parameters().test_instance_count)
+        .await?;
+
+    if errors.is_empty() {
+        client.record_success().await?;
+    } else {
+        client.record_failure(errors.join(", ")).await?;
+    }
+    Ok(())
+}
+
+async fn replay(mock: &mut Mock, packet_type: PacketType, errors: &mut Vec<String>) {
+    if let Err(e) = mock.replay_packet(Replay::LastSent(packet_type)) {
+        errors.push(e);
+    }
+    // Give the discv5 node time to process the replayed packet.
+    tokio::time::sleep(Duration::from_secs(2)).await;
+}
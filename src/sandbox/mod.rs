@@ -1,13 +1,12 @@
+use crate::capture;
 use crate::mock::{
     Action, Behaviour, Behaviours, CustomResponse, CustomResponseId, DeclarativeBehaviour, Expect,
-    Mock, Request, Response,
+    Mock, Request, Response, Step, UnorderedBehaviour,
 };
-use crate::utils::publish_and_collect;
+use crate::utils::{generate_deterministic_keypair, publish_and_collect};
 use discv5::enr::{CombinedKey, NodeId};
 use discv5::rpc::ResponseBody;
 use discv5::{Discv5, Enr, ListenConfig};
-use enr::k256;
-use rand::{RngCore, SeedableRng};
 use serde::{Deserialize, Serialize};
 use std::collections::VecDeque;
 use std::net::{IpAddr, Ipv4Addr};
@@ -67,6 +66,7 @@ pub(crate) async fn run(client: Client) -> Result<(), Box<dyn std::error::Error>
     ));
 
     let participants = publish_and_collect(&client, instance_info).await?;
+    capture::register_enrs(participants.iter().map(|p| &p.enr));
 
     // let another_instance_info = {
     //     let participants = publish_and_collect(&client, instance_info).await?;
@@ -186,74 +186,31 @@ async fn run_mock(
     // ////////////////////////
     // Start mock
     // ////////////////////////
-    // Sequential: FINDNODE
-    // let mut behaviours = VecDeque::new();
-    // behaviours.push_back(Behaviour {
-    //     expect: Expect::Handshake(Request::FINDNODE),
-    //     action: Action::EstablishSession(Box::new(Action::CaptureRequest)),
-    // });
-    // behaviours.push_back(Behaviour {
-    //     expect: Expect::Message(Request::FINDNODE),
-    //     action: Action::SendResponse(Response::Custom(vec![
-    //         CustomResponse {
-    //             id: CustomResponseId::CapturedRequestId(0),
-    //             body: discv5::rpc::ResponseBody::Nodes {
-    //                 total: 2,
-    //                 nodes: vec![target_enr.clone()],
-    //             },
-    //         },
-    //         CustomResponse {
-    //             id: CustomResponseId::CapturedRequestId(1),
-    //             body: discv5::rpc::ResponseBody::Nodes {
-    //                 total: 1,
-    //                 nodes: vec![target_enr.clone()],
-    //             },
-    //         },
-    //         CustomResponse {
-    //             id: CustomResponseId::CapturedRequestId(0),
-    //             body: discv5::rpc::ResponseBody::Nodes {
-    //                 total: 2,
-    //                 nodes: vec![target_enr],
-    //             },
-    //         },
-    //     ])),
-    // });
-
     // Sequential: PING
     let mut behaviours = VecDeque::new();
-    behaviours.push_back(Behaviour {
+    behaviours.push_back(Step::Ordered(Behaviour {
         expect: Expect::MessageWithoutSession,
         actions: vec![Action::SendWhoAreYou],
-    });
-    behaviours.push_back(Behaviour {
-        expect: Expect::Handshake(Request::Ping),
-        actions: vec![
-            Action::EstablishSession,
-            Action::CaptureRequest,
-            Action::SendResponse(Response::Custom(vec![CustomResponse {
-                id: CustomResponseId::CapturedRequestId(0),
-                body: ResponseBody::Pong {
-                    enr_seq: discv5_node.enr.seq(),
-                    ip: IpAddr::V4(discv5_node.enr.ip4().unwrap()),
-                    port: NonZeroU16::new(9000).unwrap(),
-                },
-            }])),
-        ],
-    });
-    behaviours.push_back(Behaviour {
-        expect: Expect::Message(Request::Ping),
-        actions: vec![
-            Action::CaptureRequest,
-            Action::SendResponse(Response::Custom(vec![CustomResponse {
-                id: CustomResponseId::CapturedRequestId(1),
-                body: ResponseBody::Pong {
-                    enr_seq: discv5_node.enr.seq(),
-                    ip: IpAddr::V4(discv5_node.enr.ip4().unwrap()),
-                    port: NonZeroU16::new(9000).unwrap(),
-                },
-            }])),
-        ],
-    });
+    }));
+    // The PINGs are sent concurrently, so reply to them in any order.
+    let pong = Action::SendResponse(Response::Custom(vec![CustomResponse {
+        id: CustomResponseId::InboundRequestId,
+        body: ResponseBody::Pong {
+            enr_seq: discv5_node.enr.seq(),
+            ip: IpAddr::V4(discv5_node.enr.ip4().unwrap()),
+            port: NonZeroU16::new(9000).unwrap(),
+        },
+    }]));
+    behaviours.push_back(Step::Unordered(vec![
+        UnorderedBehaviour::once(Behaviour {
+            expect: Expect::Handshake(Request::Ping),
+            actions: vec![Action::EstablishSession, pong.clone()],
+        }),
+        UnorderedBehaviour::once(Behaviour {
+            expect: Expect::Message(Request::Ping),
+            actions: vec![pong],
+        }),
+    ]));
 
     // Declarative
     let _behaviours = Behaviours::Declarative(DeclarativeBehaviour {
@@ -279,24 +236,3 @@ async fn run_mock(
     client.record_success().await?;
     Ok(())
 }
-
-/// Generate `n` deterministic keypairs from a given seed.
-fn generate_deterministic_keypair(n: usize, seed: u64) -> Vec<CombinedKey> {
-    let mut keypairs = Vec::new();
-    for i in 0..n {
-        let sk = {
-            let rng = &mut rand_xorshift::XorShiftRng::seed_from_u64(seed + i as u64);
-            let mut b = [0; 32];
-            loop {
-                // until a value is given within the curve order
-                rng.fill_bytes(&mut b);
-                if let Ok(k) = k256::ecdsa::SigningKey::from_slice(&b) {
-                    break k;
-                }
-            }
-        };
-        let kp = CombinedKey::from(sk);
-        keypairs.push(kp);
-    }
-    keypairs
-}
@@ -0,0 +1,305 @@
+use crate::capture;
+use crate::fleet::Fleet;
+use crate::utils::{get_param, publish_and_collect};
+use chrono::Local;
+use discv5::enr::{CombinedKey, NodeId};
+use discv5::{ConnectionState, Discv5, Enr, Event, ListenConfig};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use testground::client::Client;
+use testground::WriteQuery;
+use tokio::task::JoinSet;
+use tracing::{error, info, warn};
+
+const STATE_COMPLETED_TO_COLLECT_INSTANCE_INFORMATION: &str =
+    "STATE_COMPLETED_TO_COLLECT_INSTANCE_INFORMATION";
+const STATE_DONE: &str = "STATE_DONE";
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+enum Role {
+    Node,
+    Peers,
+}
+
+impl From<&str> for Role {
+    fn from(test_group_id: &str) -> Self {
+        match test_group_id {
+            "node" => Role::Node,
+            "peers" => Role::Peers,
+            _ => unreachable!(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct InstanceInfo {
+    role: Role,
+    // The ENRs of the identities hosted in the instance.
+    enrs: Vec<Enr>,
+}
+
+pub(crate) async fn run(client: Client) -> Result<(), Box<dyn std::error::Error>> {
+    let run_parameters = client.run_parameters();
+    let role: Role = run_parameters.test_group_id.as_str().into();
+    let ip = run_parameters
+        .data_network_ip()?
+        .expect("IP address for the data network");
+    client.record_message(format!(
+        "role: {:?}, group_seq: {}",
+        role,
+        client.group_seq()
+    ));
+
+    match role {
+        Role::Node => play_node(client, ip).await?,
+        Role::Peers => play_peers(client, ip).await?,
+    }
+
+    Ok(())
+}
+
+async fn play_node(client: Client, ip: IpAddr) -> Result<(), Box<dyn std::error::Error>> {
+    let run_parameters = client.run_parameters();
+    let session_cache_capacity: usize = get_param(
+        "session_cache_capacity",
+        &run_parameters.test_instance_params,
+    )?;
+    let rounds: u64 = get_param("rounds", &run_parameters.test_instance_params)?;
+    let ping_interval: u64 = get_param("ping_interval", &run_parameters.test_instance_params)?;
+
+    // ////////////////////////
+    // Start discv5
+    // ////////////////////////
+    let enr_key = CombinedKey::generate_secp256k1();
+    let enr = Enr::builder()
+        .ip(ip)
+        .udp4(9000)
+        .build(&enr_key)
+        .expect("Construct an Enr");
+    let listen_config = ListenConfig::Ipv4 {
+        ip: Ipv4Addr::UNSPECIFIED,
+        port: 9000,
+    };
+    let config = discv5::ConfigBuilder::new(listen_config)
+        .session_cache_capacity(session_cache_capacity)
+        .ping_interval(Duration::from_secs(ping_interval))
+        .build();
+    let mut discv5: Discv5 = Discv5::new(enr.clone(), enr_key, config)?;
+    discv5.start().await.expect("Start Discovery v5 server");
+
+    // Observe Discv5 events, counting the handshakes per peer.
+    let handshakes = Arc::new(Mutex::new(HashMap::<NodeId, u64>::new()));
+    let mut event_stream = discv5.event_stream().await.expect("Discv5Event");
+    let counter = handshakes.clone();
+    tokio::spawn(async move {
+        while let Some(event) = event_stream.recv().await {
+            if let Event::SessionEstablished(enr, _) = event {
+                *counter
+                    .lock()
+                    .expect("Lock the handshake counter")
+                    .entry(enr.node_id())
+                    .or_default() += 1;
+            }
+        }
+    });
+
+    // //////////////////////////////////////////////////////////////
+    // Collect information of all participants in the test case
+    // //////////////////////////////////////////////////////////////
+    let participants = publish_and_collect(
+        &client,
+        InstanceInfo {
+            role: Role::Node,
+            enrs: vec![enr],
+        },
+    )
+    .await?;
+    capture::register_enrs(participants.iter().flat_map(|p| p.enrs.iter()));
+    let peers = participants
+        .into_iter()
+        .filter(|p| p.role == Role::Peers)
+        .flat_map(|p| p.enrs)
+        .collect::<Vec<_>>();
+    if peers.len() <= session_cache_capacity {
+        warn!(
+            "The node talks to {} peers, which fit into the session cache of {session_cache_capacity}.",
+            peers.len()
+        );
+    }
+
+    client
+        .signal_and_wait(
+            STATE_COMPLETED_TO_COLLECT_INSTANCE_INFORMATION,
+            run_parameters.test_instance_count,
+        )
+        .await?;
+
+    for peer in peers.iter() {
+        if let Err(e) = discv5.add_enr(peer.clone()) {
+            warn!("Failed to add an ENR: {e}");
+        }
+    }
+
+    // //////////////////////////////////////////////////////////////
+    // Talk to all the peers at once, for each round
+    // //////////////////////////////////////////////////////////////
+    // Since the peers outnumber the sessions the node can hold, establishing a session evicts
+    // another, possibly one a request is still waiting on.
+    let mut requests = 0;
+    let mut failures = 0;
+    for round in 0..rounds {
+        let mut tasks = JoinSet::new();
+        for peer in peers.iter() {
+            tasks.spawn(discv5.send_ping(peer.clone()));
+        }
+        let mut round_failures = BTreeMap::<String, u64>::new();
+        while let Some(result) = tasks.join_next().await {
+            match result {
+                Ok(Ok(_)) => {}
+                Ok(Err(e)) => *round_failures.entry(format!("{e:?}")).or_default() += 1,
+                Err(e) => *round_failures.entry(format!("{e}")).or_default() += 1,
+            }
+        }
+        let round_failed = round_failures.values().sum::<u64>();
+        requests += peers.len() as u64;
+        failures += round_failed;
+        info!(
+            "round {round}: failures: {round_failed}/{}, {round_failures:?}",
+            peers.len()
+        );
+
+        let write_query = WriteQuery::new(
+            Local::now().into(),
+            format!(
+                "discv5-testground_{}_{}",
+                run_parameters.test_case, run_parameters.test_run
+            ),
+        )
+        .add_field("round", round)
+        .add_field("requests", peers.len() as u64)
+        .add_field("failures", round_failed)
+        .add_field("handshakes", total_handshakes(&handshakes))
+        .add_tag("session_cache_capacity", session_cache_capacity as u64)
+        .add_tag("scope", "round");
+        client.record_metric(write_query).await?;
+    }
+
+    // //////////////////////////////////////////////////////////////
+    // Check the liveness of the routing table
+    // //////////////////////////////////////////////////////////////
+    // Let the liveness checks, which need sessions as well, run for a couple of intervals. All
+    // the peers are alive, so none of them should be marked as disconnected.
+    tokio::time::sleep(Duration::from_secs(ping_interval * 2 + 1)).await;
+
+    let peer_ids = peers.iter().map(Enr::node_id).collect::<HashSet<_>>();
+    let table_entries = discv5
+        .table_entries()
+        .into_iter()
+        .filter(|(node_id, _, _)| peer_ids.contains(node_id))
+        .collect::<Vec<_>>();
+    let disconnected = table_entries
+        .iter()
+        .filter(|(_, _, status)| status.state != ConnectionState::Connected)
+        .count();
+
+    // A handshake is redone if the session with the peer has been established before.
+    let handshakes_total = total_handshakes(&handshakes);
+    let peers_handshaked = handshakes.lock().expect("Lock the handshake counter").len() as u64;
+    let handshakes_redone = handshakes_total - peers_handshaked;
+    info!(
+        "requests: {requests}, failures: {failures}, handshakes: {handshakes_total}, handshakes_redone: {handshakes_redone}, table_entries: {}, disconnected: {disconnected}",
+        table_entries.len()
+    );
+
+    // //////////////////////////////////////////////////////////////
+    // Record metrics
+    // //////////////////////////////////////////////////////////////
+    let write_query = WriteQuery::new(
+        Local::now().into(),
+        format!(
+            "discv5-testground_{}_{}",
+            run_parameters.test_case, run_parameters.test_run
+        ),
+    )
+    .add_field("peers", peers.len() as u64)
+    .add_field("requests", requests)
+    .add_field("failures", failures)
+    .add_field("handshakes", handshakes_total)
+    .add_field("handshakes_redone", handshakes_redone)
+    .add_field("table_entries", table_entries.len() as u64)
+    .add_field("disconnected", disconnected as u64)
+    .add_tag("session_cache_capacity", session_cache_capacity as u64)
+    .add_tag("scope", "run");
+    client.record_metric(write_query).await?;
+
+    client
+        .signal_and_wait(STATE_DONE, run_parameters.test_instance_count)
+        .await?;
+
+    if disconnected == 0 {
+        client.record_success().await?;
+    } else {
+        let e = format!(
+            "{disconnected} of {} live peers in the routing table have been marked as disconnected.",
+            table_entries.len()
+        );
+        error!("{e}");
+        client.record_failure(e).await?;
+    }
+    Ok(())
+}
+
+fn total_handshakes(handshakes: &Mutex<HashMap<NodeId, u64>>) -> u64 {
+    handshakes
+        .lock()
+        .expect("Lock the handshake counter")
+        .values()
+        .sum()
+}
+
+async fn play_peers(client: Client, ip: IpAddr) -> Result<(), Box<dyn std::error::Error>> {
+    let run_parameters = client.run_parameters();
+    let num_peers: usize = get_param("num_peers", &run_parameters.test_instance_params)?;
+
+    // ////////////////////////
+    // Start the fleet
+    // ////////////////////////
+    let keys = (0..num_peers)
+        .map(|_| CombinedKey::generate_secp256k1())
+        .collect();
+    let fleet = Fleet::start_discv5(ip, 9000, keys, |listen_config| {
+        discv5::ConfigBuilder::new(listen_config).build()
+    })
+    .await?;
+
+    // //////////////////////////////////////////////////////////////
+    // Collect information of all participants in the test case
+    // //////////////////////////////////////////////////////////////
+    let participants = publish_and_collect(
+        &client,
+        InstanceInfo {
+            role: Role::Peers,
+            enrs: fleet.enrs(),
+        },
+    )
+    .await?;
+    capture::register_enrs(participants.iter().flat_map(|p| p.enrs.iter()));
+
+    client
+        .signal_and_wait(
+            STATE_COMPLETED_TO_COLLECT_INSTANCE_INFORMATION,
+            run_parameters.test_instance_count,
+        )
+        .await?;
+
+    // Nothing to do, just answer the node until the simulation has been done.
+    client
+        .signal_and_wait(STATE_DONE, run_parameters.test_instance_count)
+        .await?;
+
+    client.record_success().await?;
+    Ok(())
+}
@@ -0,0 +1,277 @@
+use crate::capture;
+use crate::fleet::Fleet;
+use crate::network::{MAX_NODES_PER_SUBNET_BUCKET, MAX_NODES_PER_SUBNET_TABLE};
+use crate::utils::{get_param, publish_and_collect};
+use chrono::Local;
+use discv5::enr::{CombinedKey, NodeId};
+use discv5::{Discv5, Enr, Key, ListenConfig};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::net::{IpAddr, Ipv4Addr};
+use testground::client::Client;
+use testground::WriteQuery;
+use tokio::task::JoinSet;
+use tracing::{error, info, warn};
+
+const STATE_COMPLETED_TO_COLLECT_INSTANCE_INFORMATION: &str =
+    "STATE_COMPLETED_TO_COLLECT_INSTANCE_INFORMATION";
+const STATE_SYBILS_CONNECTED: &str = "STATE_SYBILS_CONNECTED";
+const STATE_DONE: &str = "STATE_DONE";
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+enum Role {
+    Victim,
+    Sybils,
+}
+
+impl From<&str> for Role {
+    fn from(test_group_id: &str) -> Self {
+        match test_group_id {
+            "victim" => Role::Victim,
+            "sybils" => Role::Sybils,
+            _ => unreachable!(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct InstanceInfo {
+    role: Role,
+    // The ENRs of the identities hosted in the instance.
+    enrs: Vec<Enr>,
+}
+
+pub(crate) async fn run(client: Client) -> Result<(), Box<dyn std::error::Error>> {
+    let run_parameters = client.run_parameters();
+    let role: Role = run_parameters.test_group_id.as_str().into();
+    let ip = run_parameters
+        .data_network_ip()?
+        .expect("IP address for the data network");
+    client.record_message(format!(
+        "role: {:?}, group_seq: {}",
+        role,
+        client.group_seq()
+    ));
+
+    match role {
+        Role::Victim => play_victim(client, ip).await?,
+        Role::Sybils => play_sybils(client, ip).await?,
+    }
+
+    Ok(())
+}
+
+async fn play_victim(client: Client, ip: IpAddr) -> Result<(), Box<dyn std::error::Error>> {
+    let run_parameters = client.run_parameters();
+    let incoming_bucket_limit: usize = get_param(
+        "incoming_bucket_limit",
+        &run_parameters.test_instance_params,
+    )?;
+    let ip_limit: bool = get_param("ip_limit", &run_parameters.test_instance_params)?;
+
+    // ////////////////////////
+    // Start discv5
+    // ////////////////////////
+    let enr_key = CombinedKey::generate_secp256k1();
+    let enr = Enr::builder()
+        .ip(ip)
+        .udp4(9000)
+        .build(&enr_key)
+        .expect("Construct an Enr");
+    let listen_config = ListenConfig::Ipv4 {
+        ip: Ipv4Addr::UNSPECIFIED,
+        port: 9000,
+    };
+    let mut config_builder = discv5::ConfigBuilder::new(listen_config);
+    config_builder.incoming_bucket_limit(incoming_bucket_limit);
+    if ip_limit {
+        config_builder.ip_limit();
+    }
+    let mut discv5: Discv5 = Discv5::new(enr.clone(), enr_key, config_builder.build())?;
+    discv5.start().await.expect("Start Discovery v5 server");
+
+    // //////////////////////////////////////////////////////////////
+    // Collect information of all participants in the test case
+    // //////////////////////////////////////////////////////////////
+    let participants = publish_and_collect(
+        &client,
+        InstanceInfo {
+            role: Role::Victim,
+            enrs: vec![enr],
+        },
+    )
+    .await?;
+    capture::register_enrs(participants.iter().flat_map(|p| p.enrs.iter()));
+    let sybil_ids = participants
+        .into_iter()
+        .filter(|p| p.role == Role::Sybils)
+        .flat_map(|p| p.enrs)
+        .map(|enr| enr.node_id())
+        .collect::<HashSet<_>>();
+
+    client
+        .signal_and_wait(
+            STATE_COMPLETED_TO_COLLECT_INSTANCE_INFORMATION,
+            run_parameters.test_instance_count,
+        )
+        .await?;
+
+    // Wait until the sybils have connected to the victim.
+    client
+        .signal_and_wait(STATE_SYBILS_CONNECTED, run_parameters.test_instance_count)
+        .await?;
+
+    // //////////////////////////////////////////////////////////////
+    // Check the routing table
+    // //////////////////////////////////////////////////////////////
+    // All the sybils are incoming nodes which share the same IP address.
+    let local_key: Key<NodeId> = discv5.local_enr().node_id().into();
+    let table_entries = discv5.table_entries_id();
+    let mut sybils_per_bucket: BTreeMap<u64, usize> = BTreeMap::new();
+    for node_id in table_entries.iter().filter(|id| sybil_ids.contains(id)) {
+        let distance = local_key
+            .log2_distance(&(*node_id).into())
+            .expect("Not the local node");
+        *sybils_per_bucket.entry(distance).or_default() += 1;
+    }
+    let sybil_entries: usize = sybils_per_bucket.values().sum();
+    info!(
+        "sybils: {}, table_entries: {}, sybil_entries: {}, sybils_per_bucket: {:?}",
+        sybil_ids.len(),
+        table_entries.len(),
+        sybil_entries,
+        sybils_per_bucket
+    );
+
+    let mut errors = vec![];
+    let bucket_limit = if ip_limit {
+        incoming_bucket_limit.min(MAX_NODES_PER_SUBNET_BUCKET)
+    } else {
+        incoming_bucket_limit
+    };
+    for (distance, count) in sybils_per_bucket.iter() {
+        if *count > bucket_limit {
+            errors.push(format!(
+                "The bucket at distance {distance} has {count} sybils, exceeding the limit {bucket_limit}."
+            ));
+        }
+    }
+    if ip_limit && sybil_entries > MAX_NODES_PER_SUBNET_TABLE {
+        errors.push(format!(
+            "The table has {sybil_entries} sybils from the same subnet, exceeding the limit {MAX_NODES_PER_SUBNET_TABLE}."
+        ));
+    }
+
+    // //////////////////////////////////////////////////////////////
+    // Record metrics
+    // //////////////////////////////////////////////////////////////
+    let write_query = WriteQuery::new(
+        Local::now().into(),
+        format!(
+            "discv5-testground_{}_{}",
+            run_parameters.test_case, run_parameters.test_run
+        ),
+    )
+    .add_field("sybils", sybil_ids.len() as u64)
+    .add_field("table_entries", table_entries.len() as u64)
+    .add_field("sybil_entries", sybil_entries as u64)
+    .add_field(
+        "sybil_share",
+        sybil_entries as f64 / table_entries.len().max(1) as f64,
+    );
+    client.record_metric(write_query).await?;
+
+    client
+        .signal_and_wait(STATE_DONE, run_parameters.test_instance_count)
+        .await?;
+
+    if errors.is_empty() {
+        client.record_success().await?;
+    } else {
+        for e in errors.iter() {
+            error!("{e}");
+        }
+        client.record_failure(errors.join(", ")).await?;
+    }
+    Ok(())
+}
+
+async fn play_sybils(client: Client, ip: IpAddr) -> Result<(), Box<dyn std::error::Error>> {
+    let run_parameters = client.run_parameters();
+    let num_sybils: usize = get_param("num_sybils", &run_parameters.test_instance_params)?;
+
+    // ////////////////////////
+    // Start the fleet
+    // ////////////////////////
+    let keys = (0..num_sybils)
+        .map(|_| CombinedKey::generate_secp256k1())
+        .collect();
+    let fleet = Fleet::start_discv5(ip, 9000, keys, |listen_config| {
+        discv5::ConfigBuilder::new(listen_config).build()
+    })
+    .await?;
+
+    // //////////////////////////////////////////////////////////////
+    // Collect information of all participants in the test case
+    // //////////////////////////////////////////////////////////////
+    let participants = publish_and_collect(
+        &client,
+        InstanceInfo {
+            role: Role::Sybils,
+            enrs: fleet.enrs(),
+        },
+    )
+    .await?;
+    capture::register_enrs(participants.iter().flat_map(|p| p.enrs.iter()));
+    let victim = participants
+        .into_iter()
+        .find(|p| p.role == Role::Victim)
+        .expect("victim")
+        .enrs
+        .remove(0);
+
+    client
+        .signal_and_wait(
+            STATE_COMPLETED_TO_COLLECT_INSTANCE_INFORMATION,
+            run_parameters.test_instance_count,
+        )
+        .await?;
+
+    // //////////////////////////////////////////////////////////////
+    // Connect to the victim
+    // //////////////////////////////////////////////////////////////
+    // Each sybil establishes a session with the victim, which makes the victim insert the sybil
+    // into its routing table as an incoming node.
+    let mut tasks = JoinSet::new();
+    for member in fleet.members.iter() {
+        let discv5 = member.node.clone();
+        let victim = victim.clone();
+        tasks.spawn(async move { discv5.send_ping(victim).await });
+    }
+    let mut failures = 0;
+    while let Some(result) = tasks.join_next().await {
+        match result {
+            Ok(Ok(_)) => {}
+            Ok(Err(e)) => {
+                warn!("Failed to send PING: {e}");
+                failures += 1;
+            }
+            Err(e) => {
+                warn!("Failed to join the task: {e}");
+                failures += 1;
+            }
+        }
+    }
+    info!("Sybils connected. failures: {failures}/{num_sybils}");
+
+    client
+        .signal_and_wait(STATE_SYBILS_CONNECTED, run_parameters.test_instance_count)
+        .await?;
+
+    client
+        .signal_and_wait(STATE_DONE, run_parameters.test_instance_count)
+        .await?;
+
+    client.record_success().await?;
+    Ok(())
+}
@@ -1,3 +1,4 @@
+use crate::capture;
 use crate::utils::publish_and_collect;
 use discv5::enr::CombinedKey;
 use discv5::{Discv5, Enr, Event, ListenConfig};
@@ -54,8 +55,9 @@ pub(super) async fn run(client: Client) -> Result<(), Box<dyn std::error::Error>
     };
     debug!("instance_info: {:?}", instance_info);
 
-    let another_node = publish_and_collect(&client, instance_info.clone())
-        .await?
+    let participants = publish_and_collect(&client, instance_info.clone()).await?;
+    capture::register_enrs(participants.iter().map(|p| &p.enr));
+    let another_node = participants
         .into_iter()
         .find(|info| info.seq != client.global_seq())
         .unwrap();
@@ -0,0 +1,40 @@
+use serde::{Deserialize, Serialize};
+
+/// An entry in the trace of a test instance, written as a line of JSON.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct TraceEntry {
+    pub(crate) run: String,
+    /// The global sequence number of the instance.
+    pub(crate) seq: u64,
+    pub(crate) role: String,
+    /// The node id of the instance, in hex.
+    pub(crate) node_id: String,
+    /// Milliseconds since the UNIX epoch.
+    pub(crate) at_ms: u64,
+    pub(crate) kind: TraceKind,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub(crate) enum TraceKind {
+    /// The instance has started. The socket is `None` if the ENR has no UDP socket.
+    Started { socket: Option<String> },
+    /// A packet has been sent to the peer.
+    Sent {
+        peer_node_id: String,
+        peer_socket: String,
+        summary: String,
+    },
+    /// A packet has been received from the peer. The node id is `None` for WHOAREYOU, which
+    /// doesn't carry it.
+    Received {
+        peer_node_id: Option<String>,
+        peer_socket: String,
+        summary: String,
+    },
+    /// An event emitted by discv5, along with the node it is about, if any.
+    Event {
+        name: String,
+        peer_node_id: Option<String>,
+    },
+}
@@ -0,0 +1,122 @@
+mod entry;
+
+pub(crate) use crate::trace::entry::{TraceEntry, TraceKind};
+use discv5::enr::NodeId;
+use discv5::{Enr, Event};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc;
+
+/// The name of the trace file written into the outputs of each instance.
+const TRACE_FILE: &str = "trace.jsonl";
+
+/// Records the packets and events observed by a test instance, which are written into the outputs
+/// of the instance once the test has finished. The `sequence-diagram` binary turns the traces of
+/// a run into a mermaid sequence diagram.
+///
+/// The tracer can be cloned to record events from a spawned task.
+#[derive(Clone)]
+pub(crate) struct Tracer {
+    run: String,
+    seq: u64,
+    role: String,
+    node_id: String,
+    entries: Arc<Mutex<Vec<TraceEntry>>>,
+}
+
+impl Tracer {
+    pub(crate) fn new(run: &str, seq: u64, role: &str, enr: &Enr) -> Self {
+        let tracer = Tracer {
+            run: run.to_string(),
+            seq,
+            role: role.to_string(),
+            node_id: node_id_hex(&enr.node_id()),
+            entries: Arc::new(Mutex::new(vec![])),
+        };
+        tracer.record(
+            now_ms(),
+            TraceKind::Started {
+                socket: enr.udp4_socket().map(|socket| socket.to_string()),
+            },
+        );
+        tracer
+    }
+
+    pub(crate) fn record(&self, at_ms: u64, kind: TraceKind) {
+        self.entries
+            .lock()
+            .expect("Lock the trace entries")
+            .push(TraceEntry {
+                run: self.run.clone(),
+                seq: self.seq,
+                role: self.role.clone(),
+                node_id: self.node_id.clone(),
+                at_ms,
+                kind,
+            });
+    }
+
+    /// Records the packets the mock has sent and received. See `Transcript::packets()`.
+    pub(crate) fn record_packets(&self, packets: Vec<(u64, TraceKind)>) {
+        for (at_ms, kind) in packets {
+            self.record(at_ms, kind);
+        }
+    }
+
+    pub(crate) fn record_event(&self, event: &Event) {
+        let (name, peer) = match event {
+            Event::Discovered(enr) => ("Discovered", Some(enr.node_id())),
+            Event::EnrAdded { enr, .. } => ("EnrAdded", Some(enr.node_id())),
+            Event::NodeInserted { node_id, .. } => ("NodeInserted", Some(*node_id)),
+            Event::SessionEstablished(enr, _) => ("SessionEstablished", Some(enr.node_id())),
+            Event::SocketUpdated(_) => ("SocketUpdated", None),
+            Event::TalkRequest(talk_request) => ("TalkRequest", Some(*talk_request.node_id())),
+        };
+        self.record(
+            now_ms(),
+            TraceKind::Event {
+                name: name.to_string(),
+                peer_node_id: peer.as_ref().map(node_id_hex),
+            },
+        );
+    }
+
+    /// Writes the trace into the outputs directory of the instance.
+    pub(crate) fn write(&self, outputs_path: impl AsRef<Path>) -> std::io::Result<()> {
+        let mut writer = BufWriter::new(File::create(outputs_path.as_ref().join(TRACE_FILE))?);
+        for entry in self.entries.lock().expect("Lock the trace entries").iter() {
+            serde_json::to_writer(&mut writer, entry)?;
+            writer.write_all(b"\n")?;
+        }
+        writer.flush()
+    }
+}
+
+/// Records all the events emitted by discv5, for the instances that don't handle the events
+/// otherwise.
+pub(crate) fn spawn_event_recorder(mut event_stream: mpsc::Receiver<Event>, tracer: Tracer) {
+    tokio::spawn(async move {
+        while let Some(event) = event_stream.recv().await {
+            tracer.record_event(&event);
+        }
+    });
+}
+
+pub(crate) fn node_id_hex(node_id: &NodeId) -> String {
+    node_id
+        .raw()
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+/// Milliseconds since the UNIX epoch.
+pub(crate) fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("System time after the UNIX epoch")
+        .as_millis() as u64
+}
@@ -1,22 +1,35 @@
+use discv5::enr::k256::elliptic_curve::rand_core::{RngCore, SeedableRng};
+use discv5::enr::{k256, CombinedKey};
+use discv5::Discv5;
 use serde::de::DeserializeOwned;
 use serde::Serialize;
 use std::borrow::Cow;
 use std::collections::HashMap;
 use std::str::FromStr;
+use std::time::Duration;
 use testground::client::Client;
 use tokio_stream::StreamExt;
+use tracing::warn;
 
 pub(crate) async fn publish_and_collect<T: Serialize + DeserializeOwned>(
     client: &Client,
     info: T,
 ) -> Result<Vec<T>, Box<dyn std::error::Error>> {
-    const TOPIC: &str = "publish_and_collect";
+    publish_and_collect_on(client, "publish_and_collect", info).await
+}
 
+/// Same as `publish_and_collect`, on another topic. A topic can be collected only once in a run,
+/// since the subscribers receive everything published to it from the beginning.
+pub(crate) async fn publish_and_collect_on<T: Serialize + DeserializeOwned>(
+    client: &Client,
+    topic: &'static str,
+    info: T,
+) -> Result<Vec<T>, Box<dyn std::error::Error>> {
     client
-        .publish(TOPIC, Cow::Owned(serde_json::to_value(&info)?))
+        .publish(topic, Cow::Owned(serde_json::to_value(&info)?))
         .await?;
 
-    let mut stream = client.subscribe(TOPIC, u16::MAX.into()).await;
+    let mut stream = client.subscribe(topic, u16::MAX.into()).await;
 
     let mut vec: Vec<T> = vec![];
 
@@ -44,3 +57,66 @@ pub(crate) fn get_param<T: FromStr>(
         .parse::<T>()
         .map_err(|_| format!("Failed to parse instance_param. key: {}", k))
 }
+
+/// Derives a seed from the run id, so that all the instances in a run generate the same random
+/// values, such as a topology or a schedule.
+pub(crate) fn run_seed(test_run: &str) -> u64 {
+    // FNV-1a, since the algorithm of `DefaultHasher` is unspecified.
+    test_run.bytes().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// Starts discv5, retrying while the socket of the previous run may not have been released yet.
+pub(crate) async fn start_discv5(discv5: &mut Discv5) -> Result<(), Box<dyn std::error::Error>> {
+    let mut attempts = 0;
+    loop {
+        match discv5.start().await {
+            Ok(()) => return Ok(()),
+            Err(e) if attempts < 5 => {
+                warn!("Failed to start discv5, retrying: {e:?}");
+                attempts += 1;
+                tokio::time::sleep(Duration::from_millis(500)).await;
+            }
+            Err(e) => return Err(format!("Failed to start discv5: {e:?}").into()),
+        }
+    }
+}
+
+/// Returns the CPU time the process has spent in all of its threads, read from `/proc/self/stat`.
+pub(crate) fn cpu_time() -> Option<Duration> {
+    // The number of clock ticks per second, which is 100 on virtually all Linux systems.
+    const CLOCK_TICKS_PER_SECOND: u64 = 100;
+
+    let stat = std::fs::read_to_string("/proc/self/stat").ok()?;
+    // The command name can contain spaces, so start after it. `utime` and `stime` are the 14th and
+    // 15th fields, and the fields after the command name start from the 3rd.
+    let mut fields = stat[stat.rfind(')')? + 1..].split_whitespace().skip(11);
+    let utime = fields.next()?.parse::<u64>().ok()?;
+    let stime = fields.next()?.parse::<u64>().ok()?;
+    Some(Duration::from_millis(
+        (utime + stime) * 1000 / CLOCK_TICKS_PER_SECOND,
+    ))
+}
+
+// This function is copied from https://github.com/sigp/discv5/blob/master/src/discv5/test.rs
+// Generate `n` deterministic keypairs from a given seed.
+pub(crate) fn generate_deterministic_keypair(n: usize, seed: u64) -> Vec<CombinedKey> {
+    let mut keypairs = Vec::new();
+    for i in 0..n {
+        let sk = {
+            let rng = &mut rand_xorshift::XorShiftRng::seed_from_u64(seed + i as u64);
+            let mut b = [0; 32];
+            loop {
+                // until a value is given within the curve order
+                rng.fill_bytes(&mut b);
+                if let Ok(k) = k256::ecdsa::SigningKey::from_slice(&b) {
+                    break k;
+                }
+            }
+        };
+        let kp = CombinedKey::from(sk);
+        keypairs.push(kp);
+    }
+    keypairs
+}